@@ -0,0 +1,218 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+/// How many suggestions to show under the recipient box
+pub const MAX_SUGGESTIONS: usize = 8;
+
+/// Someone who could be suggested as a recipient
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    pub did: String,
+    pub handle: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+/// Autocompletes handles in the recipient box from the accounts we follow, people we've had Conversations
+/// with and Bluesky's typeahead search
+#[derive(Default)]
+pub struct Autocomplete {
+    /// Everyone we know about locally, keyed by DID
+    known: HashMap<String, Candidate>,
+    /// Results of the last typeahead search, and what was searched for
+    searched: Option<(String, Vec<Candidate>)>,
+    /// The typeahead search that's running now
+    searching: Option<String>,
+    /// Whether each DID has published a bmail_pub_key, once we've checked
+    bmail_capable: HashMap<String, bool>,
+    /// DIDs we're checking for a bmail_pub_key right now
+    checking: HashSet<String>,
+    /// What's being typed, and what we're suggesting for it
+    query: String,
+    suggestions: Vec<Candidate>,
+    selected: Option<usize>,
+}
+
+impl Autocomplete {
+    /// Remember some people that can be suggested without searching. Display names are kept if we already have one
+    pub fn add_known(&mut self, candidates: impl IntoIterator<Item = Candidate>) {
+        for candidate in candidates {
+            match self.known.get_mut(&candidate.did) {
+                Some(known) => {
+                    known.handle = candidate.handle;
+                    if candidate.display_name.is_some() {
+                        known.display_name = candidate.display_name;
+                    }
+                }
+                None => {
+                    self.known.insert(candidate.did.clone(), candidate);
+                }
+            }
+        }
+    }
+
+    /// Take the results of a typeahead search. Results for something that isn't being typed anymore are dropped
+    pub fn set_search_results(&mut self, query: String, results: Vec<Candidate>) {
+        if self.searching.as_ref() == Some(&query) {
+            self.searching = None;
+        }
+        if query == self.query {
+            self.searched = Some((query, results));
+            self.refresh();
+        }
+    }
+
+    /// Record whether people have published a bmail_pub_key
+    pub fn set_bmail_capable(&mut self, capable: HashMap<String, bool>) {
+        for did in capable.keys() {
+            self.checking.remove(did);
+        }
+        self.bmail_capable.extend(capable);
+    }
+
+    /// Forget that we were checking some DIDs, so they can be checked again
+    pub fn check_failed(&mut self, dids: &[String]) {
+        for did in dids {
+            self.checking.remove(did);
+        }
+    }
+
+    /// Whether someone has published a bmail_pub_key, or None if we don't know yet
+    pub fn bmail_capable(&self, did: &str) -> Option<bool> {
+        self.bmail_capable.get(did).copied()
+    }
+
+    /// Update the suggestions for what's in the recipient box. Only the handle after the last comma is completed
+    pub fn update(&mut self, recipient_input: &str) {
+        let query = current_token(recipient_input).to_lowercase();
+        if query != self.query {
+            self.query = query;
+            self.selected = None;
+        }
+        self.refresh();
+    }
+
+    fn refresh(&mut self) {
+        self.suggestions.clear();
+        if self.query.is_empty() {
+            self.selected = None;
+            return;
+        }
+        // People we know about come first, then anyone else the search found
+        let mut matches: Vec<&Candidate> = self
+            .known
+            .values()
+            .filter(|c| matches_query(c, &self.query))
+            .collect();
+        matches.sort_by_key(|c| (!c.handle.starts_with(&self.query), c.handle.clone()));
+        let searched = self
+            .searched
+            .iter()
+            .filter(|(q, _)| self.query.starts_with(q.as_str()))
+            .flat_map(|(_, results)| results.iter())
+            .filter(|c| matches_query(c, &self.query));
+
+        let mut seen = HashSet::new();
+        for candidate in matches.into_iter().chain(searched) {
+            if self.suggestions.len() == MAX_SUGGESTIONS {
+                break;
+            }
+            if seen.insert(candidate.did.clone()) {
+                self.suggestions.push(candidate.clone());
+            }
+        }
+        self.selected = match self.suggestions.len() {
+            0 => None,
+            n => self.selected.map(|i| i.min(n - 1)),
+        };
+    }
+
+    /// Get the suggestions to show
+    pub fn suggestions(&self) -> &[Candidate] {
+        &self.suggestions
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    /// Get the query a typeahead search should be run for, if there should be one, and mark it as running. Very
+    /// short queries match too many people to be useful
+    pub fn start_search(&mut self) -> Option<String> {
+        let already_searched = matches!(&self.searched, Some((q, _)) if *q == self.query);
+        if self.query.chars().count() < 2
+            || already_searched
+            || self.searching == Some(self.query.clone())
+        {
+            return None;
+        }
+        self.searching = Some(self.query.clone());
+        self.searching.clone()
+    }
+
+    /// Forget about a search that failed, so it can be tried again
+    pub fn search_failed(&mut self, query: &str) {
+        if self.searching.as_deref() == Some(query) {
+            self.searching = None;
+        }
+    }
+
+    /// Get the suggested DIDs we don't know the Bmail capability of yet, and mark them as being checked
+    pub fn start_capability_check(&mut self) -> Vec<String> {
+        let unchecked: Vec<String> = self
+            .suggestions
+            .iter()
+            .map(|c| c.did.clone())
+            .filter(|did| !self.bmail_capable.contains_key(did) && !self.checking.contains(did))
+            .collect();
+        self.checking.extend(unchecked.iter().cloned());
+        unchecked
+    }
+
+    pub fn select_next(&mut self) {
+        let n = self.suggestions.len();
+        if n > 0 {
+            self.selected = Some(self.selected.map_or(0, |i| (i + 1) % n));
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        let n = self.suggestions.len();
+        if n > 0 {
+            self.selected = Some(self.selected.map_or(n - 1, |i| (i + n - 1) % n));
+        }
+    }
+
+    /// Put the selected suggestion, or the first one, in place of the handle being typed. Returns the new
+    /// recipient input, or None if there's nothing to accept
+    pub fn accept(&mut self, recipient_input: &str) -> Option<String> {
+        let candidate = self.suggestions.get(self.selected.unwrap_or(0))?;
+        let prefix_len = recipient_input.len() - current_token(recipient_input).len();
+        let completed = format!("{}{}", &recipient_input[..prefix_len], candidate.handle);
+        self.update(&completed);
+        Some(completed)
+    }
+}
+
+/// Get the handle being typed, after the last comma
+fn current_token(recipient_input: &str) -> &str {
+    recipient_input
+        .rsplit(',')
+        .next()
+        .unwrap_or_default()
+        .trim_start()
+}
+
+fn matches_query(candidate: &Candidate, query: &str) -> bool {
+    // Don't suggest what's already been typed out in full
+    if candidate.handle == query {
+        return false;
+    }
+    candidate.handle.contains(query)
+        || candidate
+            .display_name
+            .as_ref()
+            .map(|name| name.to_lowercase().contains(query))
+            .unwrap_or(false)
+}
@@ -0,0 +1,249 @@
+use age::secrecy::{ExposeSecret, Secret};
+use age::x25519::Identity;
+use base64::engine::general_purpose;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use qrcode::{render::unicode, QrCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+use crate::errors::BmailError;
+use crate::store::StoreData;
+
+/// How many base64 characters go on each line of a paper key
+const PAPER_LINE_LENGTH: usize = 32;
+
+/// Everything needed to pick up where we left off on a new computer
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    pub created_at: DateTime<Utc>,
+    /// The secret key, as it's written in the identity file
+    pub identity: Zeroizing<String>,
+    /// The local store, with our pinned keys. Left out of identity only backups
+    pub store: Option<StoreData>,
+    /// The key log, as it's written on disk
+    pub key_log: Option<String>,
+}
+
+impl Backup {
+    pub fn identity(&self) -> Result<Identity, BmailError> {
+        Identity::from_str(&self.identity)
+            .map_err(|e| BmailError::BackupError(format!("The backup's key is invalid: {}", e)))
+    }
+}
+
+/// How to write a backup out
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum BackupFormat {
+    /// base64, to be saved in a file
+    #[default]
+    Text,
+    /// Numbered lines with a checksum on each, to be printed or written down and typed back in
+    Paper,
+    /// A QR code drawn in the terminal. Only fits identity only backups
+    Qr,
+}
+
+/// Encrypt a backup with a passphrase, using age's scrypt recipient
+pub fn encrypt_backup(backup: &Backup, passphrase: Secret<String>) -> Result<Vec<u8>, BmailError> {
+    let mut cbor_buffer: Vec<u8> = Vec::new();
+    ciborium::ser::into_writer(backup, &mut cbor_buffer)?;
+
+    let encryptor = age::Encryptor::with_user_passphrase(passphrase);
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(&mut encrypted)?;
+    writer.write_all(&cbor_buffer)?;
+    writer.finish()?;
+    Ok(encrypted)
+}
+
+/// Decrypt a backup made by [encrypt_backup]
+pub fn decrypt_backup(encrypted: &[u8], passphrase: &Secret<String>) -> Result<Backup, BmailError> {
+    let decryptor = match age::Decryptor::new(encrypted)? {
+        age::Decryptor::Passphrase(d) => d,
+        _ => {
+            return Err(BmailError::BackupError(
+                "This isn't a passphrase encrypted backup".to_string(),
+            ))
+        }
+    };
+    let mut decrypted = vec![];
+    decryptor
+        .decrypt(passphrase, None)?
+        .read_to_end(&mut decrypted)?;
+    Ok(ciborium::de::from_reader(decrypted.as_slice())?)
+}
+
+/// Write an encrypted backup out in a format
+pub fn render_backup(encrypted: &[u8], format: BackupFormat) -> Result<String, BmailError> {
+    let encoded = general_purpose::STANDARD_NO_PAD.encode(encrypted);
+    Ok(match format {
+        BackupFormat::Text => format!("{}\n", encoded),
+        BackupFormat::Paper => render_paper_key(&encoded),
+        BackupFormat::Qr => {
+            let code = QrCode::new(encoded.as_bytes()).map_err(|_| {
+                BmailError::BackupError(
+                    "The backup is too big for a QR code. Try --identity-only, or the paper format".to_string(),
+                )
+            })?;
+            code.render::<unicode::Dense1x2>()
+                .quiet_zone(true)
+                .build()
+        }
+    })
+}
+
+/// Split the backup into numbered lines of 4 character groups, each ending with a checksum so a typo can be
+/// found without retyping the whole thing
+fn render_paper_key(encoded: &str) -> String {
+    let chars: Vec<char> = encoded.chars().collect();
+    let mut paper = String::new();
+    for (i, line) in chars.chunks(PAPER_LINE_LENGTH).enumerate() {
+        let groups: Vec<String> = line.chunks(4).map(|group| group.iter().collect()).collect();
+        paper.push_str(&format!(
+            "{:03}: {} | {}\n",
+            i + 1,
+            groups.join(" "),
+            line_checksum(&line.iter().collect::<String>())
+        ));
+    }
+    paper
+}
+
+fn line_checksum(line: &str) -> String {
+    let hash = Sha256::digest(line.as_bytes());
+    format!("{:02x}{:02x}", hash[0], hash[1])
+}
+
+/// Read a backup written in the text or paper format back into the encrypted bytes. Paper keys have their
+/// line numbers and checksums stripped, and a line that doesn't match its checksum is reported
+pub fn parse_backup(text: &str) -> Result<Vec<u8>, BmailError> {
+    let mut encoded = String::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (number, rest) = match line.split_once(':') {
+            Some((number, rest)) => (Some(number.trim()), rest),
+            None => (None, line),
+        };
+        let (data, checksum) = match rest.split_once('|') {
+            Some((data, checksum)) => (data, Some(checksum.trim())),
+            None => (rest, None),
+        };
+        let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+        if let Some(checksum) = checksum {
+            if line_checksum(&data) != checksum.to_lowercase() {
+                return Err(BmailError::BackupError(format!(
+                    "Line {} doesn't match its checksum, check it for typos",
+                    number.unwrap_or("?")
+                )));
+            }
+        }
+        encoded.push_str(&data);
+    }
+    Ok(general_purpose::STANDARD_NO_PAD.decode(encoded.trim_end_matches('='))?)
+}
+
+/// Ask for a passphrase without showing it. When setting a new one it's asked for twice, so a typo doesn't
+/// make a backup nobody can open
+pub fn prompt_passphrase(prompt: &str, confirm: bool) -> Result<Secret<String>, BmailError> {
+    let passphrase = rpassword::prompt_password(format!("{}: ", prompt))?;
+    if passphrase.is_empty() {
+        return Err(BmailError::InvalidPassphrase("It can't be empty".to_string()));
+    }
+    if confirm && rpassword::prompt_password("Type it again: ")? != passphrase {
+        return Err(BmailError::InvalidPassphrase("The passphrases don't match".to_string()));
+    }
+    Ok(Secret::new(passphrase))
+}
+
+/// Get the secret key from an identity, as it's written in the identity file
+pub fn identity_secret(identity: &Identity) -> Zeroizing<String> {
+    Zeroizing::new(identity.to_string().expose_secret().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup() -> Backup {
+        Backup {
+            created_at: Utc::now(),
+            identity: identity_secret(&Identity::generate()),
+            store: None,
+            key_log: Some("a key log\n".to_string()),
+        }
+    }
+
+    #[test]
+    fn text_and_paper_read_back() {
+        let encrypted: Vec<u8> = (0..=255).collect();
+        for format in [BackupFormat::Text, BackupFormat::Paper] {
+            let rendered = render_backup(&encrypted, format).unwrap();
+            assert_eq!(parse_backup(&rendered).unwrap(), encrypted);
+        }
+        // Extra blank lines and spaces from typing it back in don't matter
+        let paper = render_backup(&encrypted, BackupFormat::Paper).unwrap();
+        let retyped = paper.replace('\n', "\n\n  ").replace(" | ", "  |  ");
+        assert_eq!(parse_backup(&retyped).unwrap(), encrypted);
+    }
+
+    #[test]
+    fn paper_key_lines_are_numbered_and_checked() {
+        let encrypted: Vec<u8> = (0..=255).collect();
+        let paper = render_backup(&encrypted, BackupFormat::Paper).unwrap();
+        let lines: Vec<&str> = paper.lines().collect();
+        assert!(lines.len() > 2);
+        assert!(lines[0].starts_with("001: "));
+        assert!(lines[1].starts_with("002: "));
+        // An uppercase checksum is fine
+        let upper_checksums: String = lines
+            .iter()
+            .map(|line| {
+                let (data, checksum) = line.split_once('|').unwrap();
+                format!("{}|{}\n", data, checksum.to_uppercase())
+            })
+            .collect();
+        assert_eq!(parse_backup(&upper_checksums).unwrap(), encrypted);
+    }
+
+    #[test]
+    fn paper_key_typos_are_found() {
+        let encrypted: Vec<u8> = (0..=255).collect();
+        let paper = render_backup(&encrypted, BackupFormat::Paper).unwrap();
+        let mut lines: Vec<String> = paper.lines().map(|line| line.to_string()).collect();
+        // Swap one character in the second line's first group
+        let typo = lines[1].chars().nth(5).unwrap();
+        let replacement = if typo == 'A' { "B" } else { "A" };
+        lines[1].replace_range(5..6, replacement);
+
+        match parse_backup(&lines.join("\n")) {
+            Err(BmailError::BackupError(message)) => assert!(message.contains("Line 002")),
+            other => panic!("Expected a checksum error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn backups_round_trip_with_their_passphrase() {
+        let backup = backup();
+        let encrypted = encrypt_backup(&backup, Secret::new("correct horse".to_string())).unwrap();
+        let paper = render_backup(&encrypted, BackupFormat::Paper).unwrap();
+
+        let decrypted = decrypt_backup(
+            &parse_backup(&paper).unwrap(),
+            &Secret::new("correct horse".to_string()),
+        )
+        .unwrap();
+        assert_eq!(*decrypted.identity, *backup.identity);
+        assert_eq!(decrypted.key_log, backup.key_log);
+        assert!(decrypted.store.is_none());
+        assert!(decrypted.identity().is_ok());
+
+        assert!(decrypt_backup(&encrypted, &Secret::new("wrong horse".to_string())).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    backup::{
+        decrypt_backup, encrypt_backup, identity_secret, parse_backup, prompt_passphrase,
+        render_backup, Backup, BackupFormat,
+    },
+    conf::Settings,
+    errors::BmailError,
+    key::{fingerprint, get_identity, BmailIdentity},
+    keylog::KeyLog,
+    lock::set_lock_passphrase,
+    ratchet::RatchetStore,
+    session::{AppView, Session},
+    store::LocalStore,
+    time::TimeFormatter,
+};
+
+/// Encrypted DMs for Bluesky. Run without a command to open the app
+#[derive(Parser)]
+#[command(name = "bmail", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Look through the Bmail keys we've seen, and back up your own
+    #[command(subcommand, alias = "key")]
+    Keys(KeysCommand),
+    /// Manage the devices you use Bmail on
+    #[command(subcommand)]
+    Devices(DevicesCommand),
+    /// Set up locking the app while you're away
+    #[command(subcommand)]
+    Lock(LockCommand),
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommand {
+    /// Show every key we've seen for someone, and where we saw it
+    History {
+        /// Their handle or DID
+        handle: String,
+    },
+    /// Check the key log for keys that changed, or that were shown differently to different people
+    Audit,
+    /// Write a passphrase encrypted backup of your key, pinned keys and key log
+    Export {
+        /// File to write the backup to. It's printed if this is left out
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(short, long, value_enum, default_value_t)]
+        format: BackupFormat,
+        /// Only back up the key, which keeps the backup small enough for a QR code
+        #[arg(long)]
+        identity_only: bool,
+    },
+    /// Restore a backup made with export, in the text or paper format
+    Import {
+        file: PathBuf,
+        /// Replace the key on this computer. The old key file is kept with .old on the end, and so are
+        /// the store and forward secrecy state if they were encrypted to it
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DevicesCommand {
+    /// Show every device on your profile
+    List,
+    /// Stop Bmails being encrypted to a lost or old device
+    Revoke {
+        /// The device's label, or the start of its fingerprint
+        device: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum LockCommand {
+    /// Set the passphrase that unlocks the app after it's been locked
+    SetPassphrase,
+}
+
+/// Run a `bmail keys` command, printing the results
+pub async fn run_keys_command(command: KeysCommand, conf: &Settings) -> Result<(), BmailError> {
+    let log = KeyLog::open(conf.store.key_log_path.clone())?;
+    let appview = AppView::new(&conf.network.appview_url);
+    match command {
+        KeysCommand::History { handle } => {
+            let did = appview.resolve_did(&handle).await?;
+            let history = log.history(&did);
+            if history.is_empty() {
+                println!("No keys seen for {} ({})", handle, did);
+                return Ok(());
+            }
+            let time = TimeFormatter::new(conf.time.clone())?;
+            println!("Keys seen for {} ({})", handle, did);
+            for entry in history {
+                println!(
+                    "{} {}  {}  {}",
+                    time.day_label(&entry.observed_at),
+                    time.message_time(&entry.observed_at),
+                    fingerprint(&entry.key),
+                    entry.source
+                );
+            }
+        }
+        KeysCommand::Audit => {
+            // Our own key is checked too, if we can find out who we are
+            let own_key = match conf.key.file_path.exists() {
+                true => Some(get_identity(&conf.key)?.public_key()),
+                false => None,
+            };
+            let own_did = match appview.resolve_did(&conf.user.handle).await {
+                Ok(did) => Some(did),
+                Err(e) => {
+                    println!("Couldn't look up your DID, so your own key won't be checked: {}", e);
+                    None
+                }
+            };
+            let own = match (&own_did, &own_key) {
+                (Some(did), Some(key)) => Some((did.as_str(), key.as_str())),
+                _ => None,
+            };
+            let findings = log.audit(own);
+            if findings.is_empty() {
+                println!("No problems found in the key log");
+            }
+            for finding in findings {
+                println!("{}", finding);
+            }
+        }
+        KeysCommand::Export {
+            output,
+            format,
+            identity_only,
+        } => {
+            if !conf.key.file_path.exists() {
+                return Err(BmailError::MissingIdentity);
+            }
+            let identity = get_identity(&conf.key)?;
+            let secret = identity.x25519().map(identity_secret).ok_or_else(|| {
+                BmailError::BackupError(
+                    "Only age keys can be backed up. Back up SSH and plugin keys with the tools that made them"
+                        .to_string(),
+                )
+            })?;
+            let (store, key_log) = match identity_only {
+                true => (None, None),
+                false => {
+                    let store = LocalStore::open(conf.store.file_path.clone(), &identity).await?;
+                    let key_log = tokio::fs::read_to_string(&conf.store.key_log_path).await.ok();
+                    (Some(store.data().clone()), key_log)
+                }
+            };
+            let backup = Backup {
+                created_at: Utc::now(),
+                identity: secret,
+                store,
+                key_log,
+            };
+            let encrypted = encrypt_backup(&backup, prompt_passphrase("Backup passphrase", true)?)?;
+            let rendered = render_backup(&encrypted, format)?;
+            match output {
+                Some(path) => {
+                    tokio::fs::write(&path, rendered).await?;
+                    println!("Backup written to {}", path.display());
+                }
+                None => print!("{}", rendered),
+            }
+        }
+        KeysCommand::Import { file, force } => {
+            let encrypted = parse_backup(&tokio::fs::read_to_string(&file).await?)?;
+            let passphrase = prompt_passphrase("Backup passphrase", false)?;
+            let backup = decrypt_backup(&encrypted, &passphrase)?;
+            let identity = BmailIdentity::X25519(backup.identity()?);
+
+            let key_path = &conf.key.file_path;
+            let existing = tokio::fs::read_to_string(key_path).await.unwrap_or_default();
+            if !existing.trim().is_empty() && existing.trim() != backup.identity.as_str() {
+                if !force {
+                    return Err(BmailError::BackupError(format!(
+                        "There's already a different key in {}. Use --force to replace it",
+                        key_path.display()
+                    )));
+                }
+                let old_path = PathBuf::from(format!("{}.old", key_path.display()));
+                tokio::fs::rename(key_path, old_path).await?;
+            }
+            // On a new computer the keys folder won't be there yet
+            if let Some(dir) = key_path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            write_key_file(key_path, backup.identity.as_bytes()).await?;
+
+            // Anything encrypted to another key can't be read with this one, so it's kept out of the way
+            let store_path = &conf.store.file_path;
+            if let Err(BmailError::UnreadableStore(..)) =
+                LocalStore::open(store_path.clone(), &identity).await
+            {
+                set_aside(store_path).await?;
+            }
+            let ratchet_path = &conf.forward_secrecy.state_path;
+            if let Err(BmailError::UnreadableStore(..)) =
+                RatchetStore::open(ratchet_path.clone(), &identity).await
+            {
+                set_aside(ratchet_path).await?;
+            }
+            if let Some(data) = backup.store {
+                let mut store = LocalStore::open(conf.store.file_path.clone(), &identity).await?;
+                store.replace(data);
+                store.save(&identity).await?;
+            }
+            // The log is only added to, so one that's already here is left alone
+            if let Some(key_log) = backup.key_log {
+                if conf.store.key_log_path.exists() {
+                    println!(
+                        "Kept the key log already in {}",
+                        conf.store.key_log_path.display()
+                    );
+                } else {
+                    tokio::fs::write(&conf.store.key_log_path, key_log).await?;
+                }
+            }
+            println!(
+                "Restored key {} from a backup made {}",
+                fingerprint(&identity.public_key()),
+                backup.created_at
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Write a key file that only we can read
+async fn write_key_file(path: &Path, contents: &[u8]) -> Result<(), BmailError> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(contents).await?;
+    // An existing file keeps its permissions when it's opened, so they're set again
+    #[cfg(unix)]
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .await?;
+    Ok(())
+}
+
+/// Move a file encrypted to another key aside, keeping it with .old on the end
+async fn set_aside(path: &Path) -> Result<(), BmailError> {
+    let old_path = PathBuf::from(format!("{}.old", path.display()));
+    tokio::fs::rename(path, &old_path).await?;
+    println!(
+        "{} is encrypted to a different key, so it was moved to {}",
+        path.display(),
+        old_path.display()
+    );
+    Ok(())
+}
+
+/// Run a `bmail lock` command
+pub fn run_lock_command(command: LockCommand, conf: &Settings) -> Result<(), BmailError> {
+    match command {
+        LockCommand::SetPassphrase => {
+            let passphrase = prompt_passphrase("Lock passphrase", true)?;
+            set_lock_passphrase(&conf.lock.passphrase_path, passphrase)?;
+            println!("Lock passphrase saved. Press ctrl+l in the app (unless you changed the key) to lock it");
+        }
+    }
+    Ok(())
+}
+
+/// Run a `bmail devices` command, printing the results
+pub async fn run_devices_command(command: DevicesCommand, session: &Session) -> Result<(), BmailError> {
+    let time = TimeFormatter::new(session.conf.time.clone())?;
+    let our_key = session.identity.public_key();
+    match command {
+        DevicesCommand::List => {
+            let devices = session.list_devices().await?;
+            if devices.is_empty() {
+                println!("No devices yet. Run bmail to set this one up");
+            }
+            for device in devices {
+                let mut line = format!(
+                    "{}  {}  added {}",
+                    fingerprint(&device.key),
+                    device.label,
+                    time.day_label(&device.added_at)
+                );
+                if let Some(revoked_at) = &device.revoked_at {
+                    line.push_str(&format!(", revoked {}", time.day_label(revoked_at)));
+                }
+                if device.key == our_key {
+                    line.push_str(" (this device)");
+                }
+                println!("{}", line);
+            }
+        }
+        DevicesCommand::Revoke { device } => {
+            let revoked = session.revoke_device(&device).await?;
+            println!(
+                "Revoked {} ({}). New Bmails won't be encrypted to it, but it can still read old ones",
+                revoked.label,
+                fingerprint(&revoked.key)
+            );
+        }
+    }
+    Ok(())
+}
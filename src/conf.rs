@@ -1,12 +1,33 @@
 use crate::errors::BmailError;
+use crate::keybindings::{Action, KeybindingPreset};
+use crate::theme::{ThemeElement, ThemePreset};
 use config::Config;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct Settings {
     pub user: UserSettings,
     pub key: KeySettings,
+    #[serde(default)]
+    pub notifications: NotificationSettings,
+    #[serde(default)]
+    pub sync: SyncSettings,
+    #[serde(default)]
+    pub store: StoreSettings,
+    #[serde(default)]
+    pub forward_secrecy: ForwardSecrecySettings,
+    #[serde(default)]
+    pub lock: LockSettings,
+    #[serde(default)]
+    pub network: NetworkSettings,
+    #[serde(default)]
+    pub time: TimeSettings,
+    #[serde(default)]
+    pub keybindings: KeybindingSettings,
+    #[serde(default)]
+    pub theme: ThemeSettings,
 }
 
 #[derive(Clone, Serialize, Deserialize, Default)]
@@ -18,6 +39,192 @@ pub struct UserSettings {
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct KeySettings {
     pub file_path: PathBuf,
+    /// Name for this device in your list of devices. Defaults to the hostname
+    #[serde(default)]
+    pub device_label: String,
+    /// The public key to publish when file_path is an SSH key or an age plugin identity. SSH keys default to
+    /// the .pub file next to them, and plugin identities to the recipient written in them
+    #[serde(default)]
+    pub public_key: String,
+    /// age plugins that can be run to encrypt to someone's plugin key, like "yubikey". Keys come from other
+    /// people's profiles, so plugins that aren't listed are never run
+    #[serde(default)]
+    pub allowed_plugins: Vec<String>,
+}
+
+impl KeySettings {
+    /// Get the label for this device, falling back to the hostname if one isn't set
+    pub fn device_label(&self) -> String {
+        if !self.device_label.trim().is_empty() {
+            return self.device_label.trim().to_string();
+        }
+        ["HOSTNAME", "COMPUTERNAME"]
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+            .unwrap_or_else(|| "Unnamed device".to_string())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    /// Show a desktop notification through D-Bus when a Bmail arrives
+    pub desktop: bool,
+    /// Ring the terminal bell if desktop notifications are off or fail
+    pub bell: bool,
+    /// Put the message text in the notification. Turn off if others can see your screen
+    pub show_contents: bool,
+    /// Minimum number of seconds between notifications for the same Conversation
+    pub rate_limit_seconds: u64,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            desktop: false,
+            bell: true,
+            show_contents: false,
+            rate_limit_seconds: 30,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncSettings {
+    /// Fetch recent messages for every Conversation in the background when the app starts
+    pub on_startup: bool,
+    /// How many of the most recent messages to fetch for each Conversation
+    pub recent_messages: usize,
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        Self {
+            on_startup: false,
+            recent_messages: 20,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreSettings {
+    /// Where to keep local data like unsent drafts. It's encrypted with your key
+    pub file_path: PathBuf,
+    /// Where to keep the log of every key we've seen. Only ever added to
+    pub key_log_path: PathBuf,
+}
+
+impl Default for StoreSettings {
+    fn default() -> Self {
+        Self {
+            file_path: PathBuf::from("keys/bmail_store.age"),
+            key_log_path: PathBuf::from("keys/bmail_key_log.jsonl"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardSecrecySettings {
+    /// Publish signed prekeys and send forward secret Bmails to people who've published theirs
+    pub enabled: bool,
+    /// Where to keep ratchet sessions and the text of forward secret Bmails. It's encrypted with your key
+    pub state_path: PathBuf,
+}
+
+impl Default for ForwardSecrecySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            state_path: PathBuf::from("keys/bmail_ratchet.age"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockSettings {
+    /// Where to keep the passphrase for unlocking the app, set with bmail lock set-passphrase
+    pub passphrase_path: PathBuf,
+}
+
+impl Default for LockSettings {
+    fn default() -> Self {
+        Self {
+            passphrase_path: PathBuf::from("keys/bmail_lock.age"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkSettings {
+    /// The Bluesky AppView to look up handles, follows and posts with. These don't need to be logged in
+    pub appview_url: String,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            appview_url: "https://public.api.bsky.app".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeSettings {
+    /// Timezone to show times in, like "America/Los_Angeles". Leave empty to use the system's timezone
+    pub timezone: String,
+    /// strftime format for the time next to each message
+    pub message_format: String,
+    /// strftime format for the day separators in the message list, other than Today and Yesterday
+    pub day_format: String,
+    /// strftime format for the last message time in the sidebar, if it wasn't today
+    pub sidebar_format: String,
+}
+
+impl Default for TimeSettings {
+    fn default() -> Self {
+        Self {
+            timezone: String::new(),
+            message_format: "%H:%M".to_string(),
+            day_format: "%A, %B %-d, %Y".to_string(),
+            sidebar_format: "%m/%d".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct KeybindingSettings {
+    /// Set of keybindings to start from: default, vim or emacs
+    pub preset: KeybindingPreset,
+    /// Keys for individual actions, like `send=["ctrl+s"]`. These replace the preset's keys for that action
+    #[serde(flatten)]
+    pub overrides: HashMap<Action, Vec<String>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ThemeSettings {
+    /// Colour scheme to start from: dark, light or high-contrast
+    pub preset: ThemePreset,
+    /// Styles for individual parts of the UI, like `unread={fg="red"}`. These replace the preset's style
+    #[serde(flatten)]
+    pub overrides: HashMap<ThemeElement, StyleSettings>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct StyleSettings {
+    /// Colour name, `#rrggbb` or 256 colour index
+    pub fg: Option<String>,
+    pub bg: Option<String>,
+    /// Like bold, italic, underlined or reversed
+    pub modifiers: Vec<String>,
 }
 
 /// Get configuration either from bmail.toml or from the env vars
@@ -0,0 +1,364 @@
+use unicode_width::UnicodeWidthChar;
+use zeroize::{Zeroize, Zeroizing};
+
+/// A multi-line text editor for composing messages, with a cursor that can be moved around
+/// and a history of sent messages that can be recalled
+#[derive(Default)]
+pub struct Editor {
+    text: String,
+    /// Byte index of the cursor in the text. Always on a char boundary
+    cursor: usize,
+    /// Previously sent messages, oldest first
+    history: Vec<String>,
+    /// Which history entry is being shown, or None if we're on the current draft
+    history_index: Option<usize>,
+    /// The draft we were writing before going back through the history
+    stashed_draft: String,
+}
+
+impl Editor {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Replace the text, putting the cursor at the end
+    pub fn set_text(&mut self, text: String) {
+        self.text = text;
+        self.cursor = self.text.len();
+        self.history_index = None;
+    }
+
+    /// Wipe the text and history from memory, like when the app is locked
+    pub fn wipe(&mut self) {
+        self.text.zeroize();
+        self.stashed_draft.zeroize();
+        self.history.zeroize();
+        self.cursor = 0;
+        self.history_index = None;
+    }
+
+    /// Take the text out to send it, leaving the editor empty. The text is added to the history
+    pub fn take(&mut self) -> Zeroizing<String> {
+        let text = Zeroizing::new(std::mem::take(&mut self.text));
+        self.cursor = 0;
+        self.history_index = None;
+        self.stashed_draft.clear();
+        if !text.is_empty() && self.history.last() != Some(&*text) {
+            self.history.push(text.to_string());
+        }
+        text
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// Insert a chunk of text, like a paste. Windows line endings are turned into plain newlines
+    pub fn insert_str(&mut self, s: &str) {
+        let s = s.replace("\r\n", "\n").replace('\r', "\n");
+        self.text.insert_str(self.cursor, &s);
+        self.cursor += s.len();
+    }
+
+    pub fn newline(&mut self) {
+        self.insert_char('\n');
+    }
+
+    /// Delete the character before the cursor
+    pub fn backspace(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Delete the character under the cursor
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Delete from the start of the word before the cursor to the cursor
+    pub fn delete_word_left(&mut self) {
+        let end = self.cursor;
+        self.move_word_left();
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    pub fn move_left(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if let Some(c) = self.text[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    /// Move to the start of the word before the cursor
+    pub fn move_word_left(&mut self) {
+        let before = &self.text[..self.cursor];
+        let trimmed = before.trim_end();
+        self.cursor = trimmed
+            .rfind(char::is_whitespace)
+            .map(|i| i + trimmed[i..].chars().next().map_or(0, char::len_utf8))
+            .unwrap_or(0);
+    }
+
+    /// Move to the end of the word after the cursor
+    pub fn move_word_right(&mut self) {
+        let after = &self.text[self.cursor..];
+        let skipped = after.len() - after.trim_start().len();
+        let word = &after[skipped..];
+        let word_len = word.find(char::is_whitespace).unwrap_or(word.len());
+        self.cursor += skipped + word_len;
+    }
+
+    /// Move to the start of the current line
+    pub fn move_home(&mut self) {
+        self.cursor = self.line_start();
+    }
+
+    /// Move to the end of the current line
+    pub fn move_end(&mut self) {
+        self.cursor = self.line_end(self.cursor);
+    }
+
+    /// Move up a line, or back through the history if we're on the first line
+    pub fn move_up(&mut self) {
+        let line_start = self.line_start();
+        if line_start == 0 {
+            self.history_prev();
+            return;
+        }
+        let column = self.text[line_start..self.cursor].chars().count();
+        let prev_start = self.text[..line_start - 1]
+            .rfind('\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        self.cursor = self.offset_in_line(prev_start, column);
+    }
+
+    /// Move down a line, or forward through the history if we're on the last line
+    pub fn move_down(&mut self) {
+        let line_end = self.line_end(self.cursor);
+        if line_end == self.text.len() {
+            self.history_next();
+            return;
+        }
+        let column = self.text[self.line_start()..self.cursor].chars().count();
+        self.cursor = self.offset_in_line(line_end + 1, column);
+    }
+
+    /// Show the previous message from the history, stashing the draft if we're just starting
+    pub fn history_prev(&mut self) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(i) => i - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.stashed_draft = self.text.clone();
+                self.history.len() - 1
+            }
+        };
+        self.show_history(Some(index));
+    }
+
+    /// Show the next message from the history, or the stashed draft if we run out
+    pub fn history_next(&mut self) {
+        match self.history_index {
+            Some(i) if i + 1 < self.history.len() => self.show_history(Some(i + 1)),
+            Some(_) => self.show_history(None),
+            None => (),
+        }
+    }
+
+    fn show_history(&mut self, index: Option<usize>) {
+        self.text = match index {
+            Some(i) => self.history[i].clone(),
+            None => std::mem::take(&mut self.stashed_draft),
+        };
+        self.cursor = self.text.len();
+        self.history_index = index;
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor]
+            .rfind('\n')
+            .map(|i| i + 1)
+            .unwrap_or(0)
+    }
+
+    fn line_end(&self, from: usize) -> usize {
+        self.text[from..]
+            .find('\n')
+            .map(|i| from + i)
+            .unwrap_or(self.text.len())
+    }
+
+    /// Get the byte offset of a column in the line starting at line_start, clamped to the end of that line
+    fn offset_in_line(&self, line_start: usize, column: usize) -> usize {
+        let line_end = self.line_end(line_start);
+        self.text[line_start..line_end]
+            .char_indices()
+            .nth(column)
+            .map(|(i, _)| line_start + i)
+            .unwrap_or(line_end)
+    }
+
+    /// Lay the text out in lines that fit in a width, measured in terminal columns. Returns the lines,
+    /// and the row and column the cursor is at
+    pub fn layout(&self, width: usize) -> (Vec<String>, (usize, usize)) {
+        let width = width.max(1);
+        let mut lines = vec![String::new()];
+        let mut line_width = 0;
+        let mut cursor = (0, 0);
+
+        for (i, c) in self.text.char_indices() {
+            if i == self.cursor {
+                cursor = (lines.len() - 1, line_width);
+            }
+            if c == '\n' {
+                lines.push(String::new());
+                line_width = 0;
+                continue;
+            }
+            let c_width = c.width().unwrap_or(0);
+            if line_width + c_width > width {
+                lines.push(String::new());
+                line_width = 0;
+                if i == self.cursor {
+                    cursor = (lines.len() - 1, 0);
+                }
+            }
+            lines.last_mut().unwrap().push(c);
+            line_width += c_width;
+        }
+        if self.cursor == self.text.len() {
+            // The cursor sits after the last character, which might be past the edge
+            if line_width >= width {
+                lines.push(String::new());
+                line_width = 0;
+            }
+            cursor = (lines.len() - 1, line_width);
+        }
+        (lines, cursor)
+    }
+}
+
+impl Drop for Editor {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> Editor {
+        let mut editor = Editor::default();
+        editor.set_text(text.to_string());
+        editor
+    }
+
+    #[test]
+    fn edits_around_multibyte_characters() {
+        let mut e = Editor::default();
+        for c in "héy".chars() {
+            e.insert_char(c);
+        }
+        e.backspace();
+        assert_eq!(e.text(), "hé");
+        e.move_left();
+        e.delete();
+        assert_eq!(e.text(), "h");
+        assert_eq!(e.cursor, 1);
+    }
+
+    #[test]
+    fn moves_and_deletes_by_word() {
+        let mut e = editor("hello big world");
+        e.move_word_left();
+        assert_eq!(e.cursor, 10);
+        e.move_word_left();
+        assert_eq!(e.cursor, 6);
+        e.move_word_right();
+        assert_eq!(e.cursor, 9);
+        e.delete_word_left();
+        assert_eq!(e.text(), "hello  world");
+        assert_eq!(e.cursor, 6);
+    }
+
+    #[test]
+    fn moves_between_lines() {
+        let mut e = editor("abcd\nef\nghij");
+        // Lines too short for the column put the cursor at their end
+        e.move_up();
+        assert_eq!(e.cursor, 7);
+        e.move_up();
+        assert_eq!(e.cursor, 2);
+        e.move_down();
+        assert_eq!(e.cursor, 7);
+        e.move_home();
+        assert_eq!(e.cursor, 5);
+        e.move_end();
+        assert_eq!(e.cursor, 7);
+    }
+
+    #[test]
+    fn recalls_sent_messages() {
+        let mut e = Editor::default();
+        for text in ["one", "two", "two"] {
+            e.set_text(text.to_string());
+            assert_eq!(*e.take(), text);
+        }
+        assert_eq!(e.history, vec!["one", "two"]);
+
+        e.insert_str("draft");
+        e.move_up();
+        assert_eq!(e.text(), "two");
+        e.move_up();
+        e.move_up();
+        assert_eq!(e.text(), "one");
+        e.move_down();
+        assert_eq!(e.text(), "two");
+        e.move_down();
+        assert_eq!(e.text(), "draft");
+    }
+
+    #[test]
+    fn pastes_with_plain_newlines() {
+        let mut e = Editor::default();
+        e.insert_str("a\r\nb\rc");
+        assert_eq!(e.text(), "a\nb\nc");
+        assert_eq!(e.cursor, 5);
+    }
+
+    #[test]
+    fn lays_out_with_the_cursor() {
+        assert_eq!(
+            editor("abcdef").layout(4),
+            (vec!["abcd".to_string(), "ef".to_string()], (1, 2))
+        );
+        // A cursor past the edge goes on the next line
+        assert_eq!(
+            editor("abcd").layout(4),
+            (vec!["abcd".to_string(), String::new()], (1, 0))
+        );
+        let mut e = editor("ab\ncd");
+        e.move_home();
+        assert_eq!(
+            e.layout(10),
+            (vec!["ab".to_string(), "cd".to_string()], (1, 0))
+        );
+    }
+}
@@ -23,6 +23,28 @@ pub enum BmailError {
     MissingRecipient(String),
     #[error("Missing Recipient Keys")]
     MultipleRecipientKeys,
+    #[error("Bmail Key Changed for {0}")]
+    KeyChanged(String),
+    #[error("This device ({0}) has been revoked. Move your key file to set it up again as a new device")]
+    DeviceRevoked(String),
+    #[error("No Single Device Matches {0}")]
+    DeviceNotFound(String),
+    #[error("Backup Failed: {0}")]
+    BackupError(String),
+    #[error("Forward Secrecy Error: {0}")]
+    RatchetError(String),
+    #[error("Invalid Passphrase: {0}")]
+    InvalidPassphrase(String),
+    #[error("Wrong Passphrase")]
+    WrongPassphrase,
+    #[error("Lock Failed: {0}")]
+    LockError(String),
+    #[error("Group Error: {0}")]
+    GroupError(String),
+    #[error("Settings Sync Failed: {0}")]
+    SettingsError(String),
+    #[error("Couldn't Read {0}: {1}. Move it aside to start again without it")]
+    UnreadableStore(String, String),
     #[error(transparent)]
     ConfigError(#[from] config::ConfigError),
     #[error(transparent)]
@@ -49,8 +71,26 @@ pub enum BmailError {
     StreamError,
     #[error(transparent)]
     BiskyError(#[from] bisky::errors::BiskyError),
+    #[error("Bluesky Request Failed: {0}")]
+    XrpcError(String),
+    #[error(transparent)]
+    HttpError(#[from] reqwest::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error("Unsupported Key: {0}")]
+    UnsupportedKey(String),
+    #[error("Unsupported Encryption: it was encrypted with a passphrase, not to a key")]
+    UnsupportedEncryption,
+    #[error("The age-plugin-{0} plugin is needed to encrypt to this key, but isn't installed")]
+    MissingPlugin(String),
     #[error("Failed to Parse Recipient Key String")]
     ParseRecipientError,
+    #[error("Invalid Time Settings: {0}")]
+    InvalidTimeSettings(String),
+    #[error("Invalid Keybinding {0}")]
+    InvalidKeybinding(String),
+    #[error("Invalid Theme: {0}")]
+    InvalidTheme(String),
 }
 
 impl From<tokio::sync::mpsc::error::SendError<FirehoseMessages>> for BmailError {
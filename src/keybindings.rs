@@ -0,0 +1,632 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::{conf::KeybindingSettings, errors::BmailError, ui::InputMode};
+
+/// Something a key can be bound to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Cancel background tasks. Works in every mode but Locked
+    CancelTasks,
+    /// Wipe decrypted Bmails from memory until the passphrase is typed in. Works in every mode but Locked
+    Lock,
+    Quit,
+    StartEditing,
+    ScrollMessages,
+    SelectConversation,
+    VerifyKeys,
+    DisappearingTimer,
+    ManageGroup,
+    EditRecipient,
+    StopEditing,
+    Send,
+    Newline,
+    CursorLeft,
+    CursorRight,
+    CursorUp,
+    CursorDown,
+    WordLeft,
+    WordRight,
+    LineStart,
+    LineEnd,
+    DeleteBack,
+    DeleteForward,
+    DeleteWordBack,
+    LoadConversation,
+    NextSuggestion,
+    PreviousSuggestion,
+    AcceptSuggestion,
+    Back,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    First,
+    Last,
+    Open,
+    RenameConversation,
+    ToggleMuted,
+    ToggleArchived,
+    ShowArchived,
+    SaveTitle,
+    ToggleVerified,
+    AcceptKey,
+    Unlock,
+    RunGroupCommand,
+}
+
+impl Action {
+    /// Every Action, in the order they're checked in. The first one bound to a key wins
+    pub const ALL: [Action; 45] = [
+        Action::CancelTasks,
+        Action::Lock,
+        Action::Quit,
+        Action::StartEditing,
+        Action::ScrollMessages,
+        Action::SelectConversation,
+        Action::VerifyKeys,
+        Action::DisappearingTimer,
+        Action::ManageGroup,
+        Action::EditRecipient,
+        Action::StopEditing,
+        Action::Send,
+        Action::Newline,
+        Action::CursorLeft,
+        Action::CursorRight,
+        Action::CursorUp,
+        Action::CursorDown,
+        Action::WordLeft,
+        Action::WordRight,
+        Action::LineStart,
+        Action::LineEnd,
+        Action::DeleteBack,
+        Action::DeleteForward,
+        Action::DeleteWordBack,
+        Action::LoadConversation,
+        Action::NextSuggestion,
+        Action::PreviousSuggestion,
+        Action::AcceptSuggestion,
+        Action::Back,
+        Action::Up,
+        Action::Down,
+        Action::PageUp,
+        Action::PageDown,
+        Action::First,
+        Action::Last,
+        Action::Open,
+        Action::RenameConversation,
+        Action::ToggleMuted,
+        Action::ToggleArchived,
+        Action::ShowArchived,
+        Action::SaveTitle,
+        Action::ToggleVerified,
+        Action::AcceptKey,
+        Action::Unlock,
+        Action::RunGroupCommand,
+    ];
+
+    /// Whether this Action does anything in a mode
+    pub fn available_in(&self, mode: &InputMode) -> bool {
+        use Action::*;
+        match mode {
+            InputMode::Normal => matches!(
+                self,
+                CancelTasks
+                    | Lock
+                    | Quit
+                    | StartEditing
+                    | ScrollMessages
+                    | SelectConversation
+                    | VerifyKeys
+                    | DisappearingTimer
+                    | ManageGroup
+                    | EditRecipient
+            ),
+            InputMode::Editing => matches!(
+                self,
+                CancelTasks
+                    | Lock
+                    | EditRecipient
+                    | StopEditing
+                    | Send
+                    | Newline
+                    | CursorLeft
+                    | CursorRight
+                    | CursorUp
+                    | CursorDown
+                    | WordLeft
+                    | WordRight
+                    | LineStart
+                    | LineEnd
+                    | DeleteBack
+                    | DeleteForward
+                    | DeleteWordBack
+            ),
+            InputMode::EditingRecipient => matches!(
+                self,
+                CancelTasks
+                    | Lock
+                    | StopEditing
+                    | DeleteBack
+                    | LoadConversation
+                    | NextSuggestion
+                    | PreviousSuggestion
+                    | AcceptSuggestion
+            ),
+            InputMode::ScrollingMessages => matches!(
+                self,
+                CancelTasks | Lock | Back | Up | Down | PageUp | PageDown | First | Last
+            ),
+            InputMode::SelectingConversation => matches!(
+                self,
+                CancelTasks
+                    | Lock
+                    | Back
+                    | Up
+                    | Down
+                    | Open
+                    | RenameConversation
+                    | ToggleMuted
+                    | ToggleArchived
+                    | ShowArchived
+            ),
+            InputMode::EditingTitle => matches!(
+                self,
+                CancelTasks | Lock | StopEditing | DeleteBack | SaveTitle
+            ),
+            InputMode::VerifyingKeys => matches!(
+                self,
+                CancelTasks | Lock | Back | Up | Down | ToggleVerified | AcceptKey
+            ),
+            InputMode::EditingGroup => matches!(
+                self,
+                CancelTasks | Lock | StopEditing | DeleteBack | RunGroupCommand
+            ),
+            InputMode::Locked => matches!(self, Unlock | DeleteBack),
+        }
+    }
+
+    /// Keys for an Action when no preset or override changes them
+    fn default_keys(&self) -> &'static [&'static str] {
+        use Action::*;
+        match self {
+            CancelTasks => &["ctrl+c"],
+            Lock => &["ctrl+l"],
+            Quit => &["q"],
+            StartEditing => &["e"],
+            ScrollMessages => &["m"],
+            SelectConversation => &["c"],
+            VerifyKeys => &["v"],
+            DisappearingTimer => &["t"],
+            ManageGroup => &["g"],
+            EditRecipient => &["tab"],
+            StopEditing => &["esc"],
+            Send => &["enter"],
+            // Shift+Enter doesn't come through on a lot of terminals, so Alt+Enter works too
+            Newline => &["alt+enter", "shift+enter"],
+            CursorLeft => &["left"],
+            CursorRight => &["right"],
+            CursorUp => &["up"],
+            CursorDown => &["down"],
+            WordLeft => &["ctrl+left"],
+            WordRight => &["ctrl+right"],
+            LineStart => &["home"],
+            LineEnd => &["end"],
+            DeleteBack => &["backspace"],
+            DeleteForward => &["delete"],
+            DeleteWordBack => &["ctrl+w"],
+            LoadConversation => &["enter"],
+            NextSuggestion => &["down"],
+            PreviousSuggestion => &["up"],
+            AcceptSuggestion => &["tab"],
+            Back => &["esc"],
+            Up => &["up"],
+            Down => &["down"],
+            PageUp => &["pgup"],
+            PageDown => &["pgdn"],
+            First => &["home"],
+            Last => &["end"],
+            Open => &["enter"],
+            RenameConversation => &["r"],
+            ToggleMuted => &["m"],
+            ToggleArchived => &["a"],
+            ShowArchived => &["A"],
+            SaveTitle => &["enter"],
+            ToggleVerified => &["v"],
+            AcceptKey => &["a"],
+            Unlock => &["enter"],
+            RunGroupCommand => &["enter"],
+        }
+    }
+}
+
+/// Sets of keybindings to start from
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeybindingPreset {
+    #[default]
+    Default,
+    Vim,
+    Emacs,
+}
+
+impl KeybindingPreset {
+    /// Keys a preset adds on top of the defaults. They come first, so they're the ones shown in the help line
+    fn extra_keys(&self, action: Action) -> &'static [&'static str] {
+        use Action::*;
+        match (self, action) {
+            (KeybindingPreset::Default, _) => &[],
+            (KeybindingPreset::Vim, StartEditing) => &["i"],
+            (KeybindingPreset::Vim, Up) => &["k"],
+            (KeybindingPreset::Vim, Down) => &["j"],
+            (KeybindingPreset::Vim, PageUp) => &["ctrl+u"],
+            (KeybindingPreset::Vim, PageDown) => &["ctrl+d"],
+            (KeybindingPreset::Vim, First) => &["g"],
+            (KeybindingPreset::Vim, Last) => &["G"],
+            (KeybindingPreset::Vim, Open) => &["l"],
+            (KeybindingPreset::Vim, Back) => &["h"],
+            (KeybindingPreset::Vim, _) => &[],
+            (KeybindingPreset::Emacs, StopEditing | Back) => &["ctrl+g"],
+            (KeybindingPreset::Emacs, CursorLeft) => &["ctrl+b"],
+            (KeybindingPreset::Emacs, CursorRight) => &["ctrl+f"],
+            (KeybindingPreset::Emacs, CursorUp | Up | PreviousSuggestion) => &["ctrl+p"],
+            (KeybindingPreset::Emacs, CursorDown | Down | NextSuggestion) => &["ctrl+n"],
+            (KeybindingPreset::Emacs, WordLeft) => &["alt+b"],
+            (KeybindingPreset::Emacs, WordRight) => &["alt+f"],
+            (KeybindingPreset::Emacs, LineStart) => &["ctrl+a"],
+            (KeybindingPreset::Emacs, LineEnd) => &["ctrl+e"],
+            (KeybindingPreset::Emacs, DeleteForward) => &["ctrl+d"],
+            (KeybindingPreset::Emacs, DeleteWordBack) => &["alt+backspace"],
+            (KeybindingPreset::Emacs, PageUp) => &["alt+v"],
+            (KeybindingPreset::Emacs, PageDown) => &["ctrl+v"],
+            (KeybindingPreset::Emacs, First) => &["alt+<"],
+            (KeybindingPreset::Emacs, Last) => &["alt+>"],
+            (KeybindingPreset::Emacs, _) => &[],
+        }
+    }
+}
+
+/// A key with its modifiers, like Ctrl+W
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyChord {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyChord {
+    /// Shift is part of the character for character keys, so `G` and `shift+g` are the same chord
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        match code {
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::SHIFT) => Self {
+                code: KeyCode::Char(c.to_ascii_uppercase()),
+                modifiers: modifiers - KeyModifiers::SHIFT,
+            },
+            code => Self { code, modifiers },
+        }
+    }
+}
+
+impl From<&KeyEvent> for KeyChord {
+    fn from(key: &KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+}
+
+impl FromStr for KeyChord {
+    type Err = BmailError;
+
+    /// Parse chords like `q`, `enter`, `ctrl+w` or `alt+shift+enter`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || BmailError::InvalidKeybinding(s.to_string());
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = s.trim();
+        // Modifiers come first. `ctrl++` is Ctrl and the plus key
+        while let Some((modifier, key)) = rest.split_once('+') {
+            if key.is_empty() {
+                break;
+            }
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" | "meta" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(invalid()),
+            };
+            rest = key;
+        }
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            // Single characters keep their case
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match rest.to_lowercase().as_str() {
+                "enter" | "return" => KeyCode::Enter,
+                "esc" | "escape" => KeyCode::Esc,
+                "tab" => KeyCode::Tab,
+                "backtab" => KeyCode::BackTab,
+                "backspace" => KeyCode::Backspace,
+                "delete" | "del" => KeyCode::Delete,
+                "insert" | "ins" => KeyCode::Insert,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" | "pgup" => KeyCode::PageUp,
+                "pagedown" | "pgdn" | "pgdown" => KeyCode::PageDown,
+                "space" => KeyCode::Char(' '),
+                f => match f.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                    Some(n) => KeyCode::F(n),
+                    None => return Err(invalid()),
+                },
+            },
+        };
+        Ok(Self::new(code, modifiers))
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "Shift+")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::PageUp => write!(f, "PgUp"),
+            KeyCode::PageDown => write!(f, "PgDn"),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// The keys bound to each Action, built from the preset and overrides in the settings
+pub struct Keymap {
+    bindings: HashMap<Action, Vec<KeyChord>>,
+}
+
+impl Keymap {
+    pub fn new(settings: &KeybindingSettings) -> Result<Self, BmailError> {
+        let mut bindings = HashMap::new();
+        for action in Action::ALL {
+            let keys: Vec<String> = match settings.overrides.get(&action) {
+                Some(keys) => keys.clone(),
+                None => settings
+                    .preset
+                    .extra_keys(action)
+                    .iter()
+                    .chain(action.default_keys())
+                    .map(|k| k.to_string())
+                    .collect(),
+            };
+            let chords = keys
+                .iter()
+                .map(|k| k.parse())
+                .collect::<Result<Vec<KeyChord>, _>>()?;
+            bindings.insert(action, chords);
+        }
+        Ok(Self { bindings })
+    }
+
+    /// Find the Action a key is bound to in a mode
+    pub fn action(&self, mode: &InputMode, key: &KeyEvent) -> Option<Action> {
+        let chord = KeyChord::from(key);
+        Action::ALL
+            .into_iter()
+            .filter(|action| action.available_in(mode))
+            .find(|action| {
+                self.bindings
+                    .get(action)
+                    .into_iter()
+                    .flatten()
+                    .any(|k| k == &chord)
+            })
+    }
+
+    /// Get the first key bound to an Action, for showing in help text
+    pub fn key_label(&self, action: Action) -> Option<String> {
+        self.bindings
+            .get(&action)
+            .and_then(|keys| keys.first())
+            .map(|k| k.to_string())
+    }
+
+    /// Get the help line for a mode, as the keys and what they do. Actions that aren't bound are left out
+    pub fn help(&self, mode: &InputMode) -> Vec<(String, &'static str)> {
+        use Action::*;
+        let entries: &[(&[Action], &'static str)] = match mode {
+            InputMode::Normal => &[
+                (&[Quit], "to exit"),
+                (&[StartEditing], "to start typing a message"),
+                (&[ScrollMessages], "to enter conversation scroll mode"),
+                (&[SelectConversation], "to pick a conversation"),
+                (&[VerifyKeys], "to verify keys"),
+                (&[DisappearingTimer], "to set disappearing Bmails"),
+                (&[ManageGroup], "to manage groups"),
+                (&[Lock], "to lock"),
+            ],
+            InputMode::Editing => &[
+                (&[StopEditing], "to stop Editing"),
+                (&[EditRecipient], "to change conversation"),
+                (&[Send], "to send the message"),
+                (&[Newline], "for a new line"),
+            ],
+            InputMode::EditingRecipient => &[
+                (&[StopEditing], "to stop Editing"),
+                (&[AcceptSuggestion], "to complete the handle"),
+                (&[LoadConversation], "to load the conversation"),
+            ],
+            InputMode::ScrollingMessages => &[
+                (&[Back], "to stop Scrolling"),
+                (&[Up, Down], "to scroll messages"),
+                (&[PageUp, PageDown], "to page"),
+                (&[First, Last], "for the oldest/newest"),
+            ],
+            InputMode::SelectingConversation => &[
+                (&[Back], "to stop Selecting"),
+                (&[Up, Down], "to choose a conversation"),
+                (&[Open], "to load it"),
+                (&[RenameConversation], "to rename it"),
+                (&[ToggleMuted], "to mute it"),
+                (&[ToggleArchived], "to archive it"),
+                (&[ShowArchived], "to show archived"),
+            ],
+            InputMode::EditingTitle => &[
+                (&[StopEditing], "to cancel"),
+                (&[SaveTitle], "to save the title, or clear it if it's empty"),
+            ],
+            InputMode::VerifyingKeys => &[
+                (&[Back], "to stop Verifying"),
+                (&[Up, Down], "to choose a contact"),
+                (&[ToggleVerified], "to mark them verified"),
+                (&[AcceptKey], "to trust a changed key"),
+            ],
+            InputMode::EditingGroup => &[
+                (&[StopEditing], "to cancel"),
+                (&[RunGroupCommand], "to run it"),
+            ],
+            InputMode::Locked => &[(&[Unlock], "to unlock")],
+        };
+        entries
+            .iter()
+            .filter_map(|(actions, description)| {
+                let keys: Vec<String> = actions
+                    .iter()
+                    .filter_map(|action| self.key_label(*action))
+                    .collect();
+                (!keys.is_empty()).then(|| (keys.join("/"), *description))
+            })
+            .collect()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::new(&KeybindingSettings::default()).expect("the default keybindings are valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(s: &str) -> KeyChord {
+        s.parse().unwrap()
+    }
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn parses_chords() {
+        assert_eq!(
+            chord("q"),
+            KeyChord::new(KeyCode::Char('q'), KeyModifiers::NONE)
+        );
+        assert_eq!(
+            chord("ctrl+w"),
+            KeyChord::new(KeyCode::Char('w'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(
+            chord("ctrl++"),
+            KeyChord::new(KeyCode::Char('+'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(chord("G"), chord("shift+g"));
+        assert_eq!(chord("f5").code, KeyCode::F(5));
+        assert_eq!(chord("space").code, KeyCode::Char(' '));
+        let enter = chord("Alt+Shift+Enter");
+        assert_eq!(enter.code, KeyCode::Enter);
+        assert_eq!(enter.modifiers, KeyModifiers::ALT | KeyModifiers::SHIFT);
+    }
+
+    #[test]
+    fn rejects_bad_chords() {
+        for bad in ["", "banana", "hyper+x", "ctrl+"] {
+            assert!(bad.parse::<KeyChord>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn shows_chords() {
+        assert_eq!(chord("ctrl+w").to_string(), "Ctrl+w");
+        assert_eq!(chord("pgup").to_string(), "PgUp");
+        assert_eq!(chord("space").to_string(), "Space");
+    }
+
+    #[test]
+    fn finds_actions_by_mode() {
+        let keymap = Keymap::default();
+        let enter = key(KeyCode::Enter, KeyModifiers::NONE);
+        let q = key(KeyCode::Char('q'), KeyModifiers::NONE);
+        assert_eq!(keymap.action(&InputMode::Normal, &q), Some(Action::Quit));
+        assert_eq!(
+            keymap.action(&InputMode::Editing, &enter),
+            Some(Action::Send)
+        );
+        assert_eq!(
+            keymap.action(&InputMode::EditingRecipient, &enter),
+            Some(Action::LoadConversation)
+        );
+        let j = key(KeyCode::Char('j'), KeyModifiers::NONE);
+        assert_eq!(keymap.action(&InputMode::ScrollingMessages, &j), None);
+    }
+
+    #[test]
+    fn presets_add_keys() {
+        let settings = KeybindingSettings {
+            preset: KeybindingPreset::Vim,
+            ..Default::default()
+        };
+        let keymap = Keymap::new(&settings).unwrap();
+        let j = key(KeyCode::Char('j'), KeyModifiers::NONE);
+        let down = key(KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!(
+            keymap.action(&InputMode::ScrollingMessages, &j),
+            Some(Action::Down)
+        );
+        assert_eq!(
+            keymap.action(&InputMode::ScrollingMessages, &down),
+            Some(Action::Down)
+        );
+        assert_eq!(keymap.key_label(Action::Down).unwrap(), "j");
+    }
+
+    #[test]
+    fn overrides_replace_keys() {
+        let mut settings = KeybindingSettings::default();
+        settings
+            .overrides
+            .insert(Action::Send, vec!["ctrl+s".to_string()]);
+        settings.overrides.insert(Action::Lock, Vec::new());
+        let keymap = Keymap::new(&settings).unwrap();
+        let ctrl_s = key(KeyCode::Char('s'), KeyModifiers::CONTROL);
+        let enter = key(KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(
+            keymap.action(&InputMode::Editing, &ctrl_s),
+            Some(Action::Send)
+        );
+        assert_ne!(
+            keymap.action(&InputMode::Editing, &enter),
+            Some(Action::Send)
+        );
+        // Unbound actions are left out of the help
+        assert!(!keymap
+            .help(&InputMode::Normal)
+            .iter()
+            .any(|(_, description)| *description == "to lock"));
+
+        settings
+            .overrides
+            .insert(Action::Quit, vec!["banana".to_string()]);
+        assert!(Keymap::new(&settings).is_err());
+    }
+}
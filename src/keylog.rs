@@ -0,0 +1,312 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::errors::BmailError;
+use crate::key::fingerprint;
+
+/// Where we saw someone's key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum KeySource {
+    /// A profile update went by on the Firehose
+    Firehose,
+    /// We read it off their profile ourselves
+    ProfileFetch,
+    /// Someone else sent a Bmail encrypted to this key, so that's what their PDS showed them
+    Gossip { from: String },
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Firehose => write!(f, "firehose"),
+            KeySource::ProfileFetch => write!(f, "profile fetch"),
+            KeySource::Gossip { from } => write!(f, "gossip from {}", from),
+        }
+    }
+}
+
+/// One line of the key log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyObservation {
+    pub did: String,
+    pub key: String,
+    pub observed_at: DateTime<Utc>,
+    pub source: KeySource,
+    /// Hash of the line before this one, so a line that's edited or removed on its own gets noticed. It isn't
+    /// keyed, so anyone who can write the file can rewrite every line after the one they change too
+    pub prev: String,
+}
+
+/// Something the audit thinks is worth a look
+#[derive(Clone, Debug)]
+pub enum Finding {
+    /// A line doesn't follow on from the one before it, so the log has been edited or damaged
+    Tampered { line: usize },
+    /// Our own profile showed keys that don't include this device's
+    NotOurKey { key: String, observed: KeyObservation },
+    /// Someone's profile showed a different key than it did before. This is what making a new key looks like,
+    /// but also what swapping someone's key looks like
+    KeyChanged { from: String, observed: KeyObservation },
+    /// Someone's profile went back to a key it showed before. Making a new key doesn't do this, but a PDS
+    /// showing different keys at different times does
+    KeyReverted { observed: KeyObservation },
+    /// Someone else encrypted to a key we were never shown for this person
+    UnknownGossip { observed: KeyObservation },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Tampered { line } => {
+                write!(f, "Line {} of the key log doesn't match the line before it, the log has been edited or damaged", line)
+            }
+            Finding::NotOurKey { key, observed } => write!(
+                f,
+                "{} Your profile showed key {} ({}), which doesn't include this device",
+                observed.observed_at,
+                fingerprint(key),
+                observed.source
+            ),
+            Finding::KeyChanged { from, observed } => write!(
+                f,
+                "{} {}'s key changed from {} to {} ({})",
+                observed.observed_at,
+                observed.did,
+                fingerprint(from),
+                fingerprint(&observed.key),
+                observed.source
+            ),
+            Finding::KeyReverted { observed } => write!(
+                f,
+                "{} {}'s key went back to {} ({}), their PDS may be showing different keys at different times",
+                observed.observed_at,
+                observed.did,
+                fingerprint(&observed.key),
+                observed.source
+            ),
+            Finding::UnknownGossip { observed } => write!(
+                f,
+                "{} {} was sent a Bmail encrypted to {} ({}), which we were never shown",
+                observed.observed_at,
+                observed.did,
+                fingerprint(&observed.key),
+                observed.source
+            ),
+        }
+    }
+}
+
+/// An append only log of every Bmail key we've seen for each DID, including our own. Kept as one JSON object
+/// per line, and not encrypted, since everything in it is public on people's profiles anyway. The hash chain
+/// catches accidental and partial edits, not someone set on rewriting the log
+#[derive(Default)]
+pub struct KeyLog {
+    /// Where the log is kept. If this is None it only lives in memory
+    path: Option<PathBuf>,
+    entries: Vec<KeyObservation>,
+    /// Hash of the last line, for the next one to point back to
+    last_hash: String,
+    /// Lines that didn't follow on from the one before, counting from 1
+    broken_lines: Vec<usize>,
+}
+
+impl KeyLog {
+    /// Read the log, or start an empty one if the file doesn't exist yet
+    pub fn open(path: PathBuf) -> Result<Self, BmailError> {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut log = Self {
+            path: Some(path),
+            ..Default::default()
+        };
+        for (i, line) in contents.lines().enumerate() {
+            match serde_json::from_str::<KeyObservation>(line) {
+                Ok(entry) => {
+                    if entry.prev != log.last_hash {
+                        log.broken_lines.push(i + 1);
+                    }
+                    log.entries.push(entry);
+                }
+                Err(_) => log.broken_lines.push(i + 1),
+            }
+            log.last_hash = hash_line(line);
+        }
+        Ok(log)
+    }
+
+    /// Add a key we've seen to the log. It's only written if it's different from the last key this source
+    /// showed us for them, so the log doesn't fill up with the same key. Returns whether it was written
+    pub fn record(&mut self, did: &str, key: &str, source: KeySource) -> Result<bool, BmailError> {
+        let last = self
+            .entries
+            .iter()
+            .rev()
+            .find(|e| e.did == did && e.source == source);
+        if matches!(last, Some(last) if last.key == key) {
+            return Ok(false);
+        }
+        let entry = KeyObservation {
+            did: did.to_string(),
+            key: key.to_string(),
+            observed_at: Utc::now(),
+            source,
+            prev: self.last_hash.clone(),
+        };
+        let line = serde_json::to_string(&entry)?;
+        if let Some(path) = &self.path {
+            let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(f, "{}", line)?;
+        }
+        self.last_hash = hash_line(&line);
+        self.entries.push(entry);
+        Ok(true)
+    }
+
+    /// Get everything we've seen for someone, oldest first
+    pub fn history(&self, did: &str) -> Vec<&KeyObservation> {
+        self.entries.iter().filter(|e| e.did == did).collect()
+    }
+
+    /// Look through the log for signs of someone's key being swapped. Pass our own DID and this device's key to
+    /// check that nobody has swapped ours too
+    pub fn audit(&self, own: Option<(&str, &str)>) -> Vec<Finding> {
+        let mut findings: Vec<Finding> = self
+            .broken_lines
+            .iter()
+            .map(|line| Finding::Tampered { line: *line })
+            .collect();
+
+        // Every key each DID's profile has shown us, to check what others were shown against
+        let mut shown: HashMap<&str, HashSet<&str>> = HashMap::new();
+        for e in self.entries.iter() {
+            if !matches!(e.source, KeySource::Gossip { .. }) {
+                shown.entry(e.did.as_str()).or_default().insert(e.key.as_str());
+            }
+        }
+
+        let mut current: HashMap<&str, &str> = HashMap::new();
+        let mut seen: HashMap<&str, HashSet<&str>> = HashMap::new();
+        for e in self.entries.iter() {
+            if let Some((did, key)) = own {
+                // Keys are logged as the set of all of someone's devices
+                if e.did == did && !e.key.split(',').any(|k| k == key) {
+                    findings.push(Finding::NotOurKey {
+                        key: e.key.clone(),
+                        observed: e.clone(),
+                    });
+                    continue;
+                }
+            }
+            if matches!(e.source, KeySource::Gossip { .. }) {
+                let known = shown.get(e.did.as_str());
+                if matches!(known, Some(keys) if !keys.contains(e.key.as_str())) {
+                    findings.push(Finding::UnknownGossip { observed: e.clone() });
+                }
+                continue;
+            }
+            let did_seen = seen.entry(e.did.as_str()).or_default();
+            match current.insert(e.did.as_str(), e.key.as_str()) {
+                Some(previous) if previous == e.key => (),
+                Some(_) if did_seen.contains(e.key.as_str()) => {
+                    findings.push(Finding::KeyReverted { observed: e.clone() })
+                }
+                Some(previous) => findings.push(Finding::KeyChanged {
+                    from: previous.to_string(),
+                    observed: e.clone(),
+                }),
+                None => (),
+            }
+            did_seen.insert(e.key.as_str());
+        }
+        findings
+    }
+}
+
+fn hash_line(line: &str) -> String {
+    Sha256::digest(line.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gossip() -> KeySource {
+        KeySource::Gossip {
+            from: "carol".to_string(),
+        }
+    }
+
+    fn see(log: &mut KeyLog, did: &str, key: &str, source: KeySource) -> bool {
+        log.record(did, key, source).unwrap()
+    }
+
+    #[test]
+    fn repeats_are_not_logged() {
+        let mut log = KeyLog::default();
+        assert!(see(&mut log, "bob", "key1", KeySource::Firehose));
+        assert!(!see(&mut log, "bob", "key1", KeySource::Firehose));
+        // Each source is tracked on its own
+        assert!(see(&mut log, "bob", "key1", KeySource::ProfileFetch));
+        assert_eq!(log.history("bob").len(), 2);
+    }
+
+    #[test]
+    fn audit_finds_changes_reverts_and_unknown_gossip() {
+        let mut log = KeyLog::default();
+        see(&mut log, "bob", "key1", KeySource::Firehose);
+        see(&mut log, "bob", "key2", KeySource::Firehose);
+        see(&mut log, "bob", "key1", KeySource::ProfileFetch);
+        see(&mut log, "bob", "key3", gossip());
+        see(&mut log, "bob", "key2", gossip());
+        let findings = log.audit(None);
+        assert_eq!(findings.len(), 3);
+        let changed = matches!(&findings[0], Finding::KeyChanged { from, observed }
+            if from == "key1" && observed.key == "key2");
+        assert!(changed);
+        let reverted = matches!(&findings[1], Finding::KeyReverted { observed }
+            if observed.key == "key1");
+        assert!(reverted);
+        let unknown = matches!(&findings[2], Finding::UnknownGossip { observed }
+            if observed.key == "key3");
+        assert!(unknown);
+    }
+
+    #[test]
+    fn audit_checks_our_own_key() {
+        let mut log = KeyLog::default();
+        see(&mut log, "us", "ours,other", KeySource::Firehose);
+        see(&mut log, "us", "other", KeySource::Firehose);
+        let findings = log.audit(Some(("us", "ours")));
+        assert_eq!(findings.len(), 1);
+        assert!(matches!(&findings[0], Finding::NotOurKey { key, .. } if key == "other"));
+    }
+
+    #[test]
+    fn edited_lines_are_found() {
+        let path = std::env::temp_dir().join(format!("bmail_key_log_{}", uuid::Uuid::new_v4()));
+        let mut log = KeyLog::open(path.clone()).unwrap();
+        for key in ["key1", "key2", "key3"] {
+            see(&mut log, "bob", key, KeySource::Firehose);
+        }
+        assert_eq!(KeyLog::open(path.clone()).unwrap().audit(None).len(), 2);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replacen("key2", "keyX", 1)).unwrap();
+        let findings = KeyLog::open(path.clone()).unwrap().audit(None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(findings[0], Finding::Tampered { line: 3 }));
+    }
+}
@@ -4,11 +4,27 @@ use std::sync::Arc;
 use bisky::{atproto::Client, bluesky::Bluesky, lexicon::app::bsky::actor::ProfileViewDetailed};
 use tokio::sync::{oneshot, RwLock};
 
+pub mod autocomplete;
+pub mod backup;
+pub mod cli;
 pub mod conf;
+pub mod editor;
 pub mod errors;
 pub mod key;
+pub mod keybindings;
+pub mod keylog;
+pub mod lock;
 pub mod message;
+pub mod notify;
+pub mod ratchet;
+pub mod session;
+pub mod store;
+pub mod tasks;
+pub mod theme;
+pub mod time;
 pub mod ui;
+pub mod wrap;
+pub mod xeddsa;
 
 #[derive(Default)]
 pub struct SharableBluesky(pub Arc<RwLock<Bluesky>>);
@@ -0,0 +1,76 @@
+use age::secrecy::Secret;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::conf::Settings;
+use crate::errors::BmailError;
+use crate::key::BmailIdentity;
+use crate::ratchet::{RatchetStore, SharedRatchet};
+use crate::store::LocalStore;
+
+/// What's encrypted in the passphrase file. Being able to decrypt it is all that's checked
+const LOCK_CHECK: &[u8] = b"bmail lock";
+
+/// Save a new passphrase for unlocking the app, as a known value encrypted with it
+pub fn set_lock_passphrase(path: &Path, passphrase: Secret<String>) -> Result<(), BmailError> {
+    let encryptor = age::Encryptor::with_user_passphrase(passphrase);
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(&mut encrypted)?;
+    writer.write_all(LOCK_CHECK)?;
+    writer.finish()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, encrypted)?;
+    Ok(())
+}
+
+/// Check a passphrase against the one saved with [set_lock_passphrase]. This takes about a second on
+/// purpose, so it's best run off the UI thread
+pub fn check_lock_passphrase(path: &Path, passphrase: &Secret<String>) -> Result<(), BmailError> {
+    let encrypted = match std::fs::read(path) {
+        Ok(encrypted) => encrypted,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(BmailError::LockError(
+                "No lock passphrase is set. Run bmail lock set-passphrase first".to_string(),
+            ))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let decryptor = match age::Decryptor::new(encrypted.as_slice())? {
+        age::Decryptor::Passphrase(d) => d,
+        _ => {
+            return Err(BmailError::LockError(
+                "The lock passphrase file isn't passphrase encrypted".to_string(),
+            ))
+        }
+    };
+    let mut decrypted = vec![];
+    match decryptor.decrypt(passphrase, None) {
+        Ok(mut reader) => reader.read_to_end(&mut decrypted)?,
+        Err(age::DecryptError::DecryptionFailed) => return Err(BmailError::WrongPassphrase),
+        Err(e) => return Err(e.into()),
+    };
+    match decrypted == LOCK_CHECK {
+        true => Ok(()),
+        false => Err(BmailError::WrongPassphrase),
+    }
+}
+
+/// Check the passphrase, then open the store and ratchet state again after locking dropped them. The ratchet
+/// is opened straight into the shared one, and the store is returned for the App to take back
+pub async fn unlock(
+    conf: &Settings,
+    identity: &BmailIdentity,
+    ratchet: &SharedRatchet,
+    passphrase: Secret<String>,
+) -> Result<LocalStore, BmailError> {
+    let path = conf.lock.passphrase_path.clone();
+    tokio::task::spawn_blocking(move || check_lock_passphrase(&path, &passphrase))
+        .await
+        .map_err(|e| BmailError::LockError(e.to_string()))??;
+    let store = LocalStore::open(conf.store.file_path.clone(), identity).await?;
+    *ratchet.lock().await =
+        RatchetStore::open(conf.forward_secrecy.state_path.clone(), identity).await?;
+    Ok(store)
+}
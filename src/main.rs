@@ -1,14 +1,27 @@
 use bisky::atproto::{ClientBuilder, UserSession};
 use bisky::firehose::cbor::Body as FirehoseBody;
 use bisky::storage::File;
+use bmail::cli::{run_devices_command, run_keys_command, run_lock_command, Cli, Command};
 use bmail::conf::get_configuration;
 use bmail::errors::BmailError;
 use bmail::key::get_identity;
-use bmail::message::{BmailLike, FirehoseBmailMessageRecord, FirehoseMessages};
+use bmail::keybindings::Keymap;
+use bmail::keylog::KeyLog;
+use bmail::message::{
+    BmailLike, BmailMessageRecord, FirehoseBmailLike, FirehoseBmailMessageRecord, FirehoseMessages,
+    FirehoseProfileKeys,
+};
+use bmail::notify::Notifier;
+use bmail::ratchet::RatchetStore;
+use bmail::session::{AppView, Session};
+use bmail::store::LocalStore;
+use bmail::theme::Theme;
+use bmail::time::TimeFormatter;
 use bmail::ui::{run_app, App};
 use bmail::SharableBluesky;
+use clap::Parser;
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture},
+    event::{DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -16,28 +29,37 @@ use futures::StreamExt as _;
 use ratatui::{backend::CrosstermBackend, Terminal};
 use serde_cbor::value::from_value;
 use serde_cbor::Value::Text;
+use std::collections::HashMap;
 use std::io::{self, Cursor};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::Mutex;
 use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use url::Url;
 
 #[tokio::main]
 async fn main() -> Result<(), BmailError> {
-    let (socket, _response) = tokio_tungstenite::connect_async(
-        Url::parse("wss://bsky.social/xrpc/com.atproto.sync.subscribeRepos").unwrap(),
-    )
-    .await
-    .unwrap();
-
-    // Create a new channel to send Posts from the Firehose thread
-    let (tx, rx) = mpsc::channel(32);
-
+    let cli = Cli::parse();
     let conf = get_configuration()?;
-    let identity = get_identity(&conf.key.file_path)?;
+    // Commands that only look at this computer don't need us to log in
+    let command = match cli.command {
+        Some(Command::Keys(command)) => return run_keys_command(command, &conf).await,
+        Some(Command::Lock(command)) => return run_lock_command(command, &conf),
+        command => command,
+    };
+
+    let identity = get_identity(&conf.key)?;
+    let store = LocalStore::open(conf.store.file_path.clone(), &identity).await?;
+    let key_log = KeyLog::open(conf.store.key_log_path.clone())?;
+    let ratchet = Arc::new(Mutex::new(
+        RatchetStore::open(conf.forward_secrecy.state_path.clone(), &identity).await?,
+    ));
+    let time = TimeFormatter::new(conf.time.clone())?;
+    let keymap = Keymap::new(&conf.keybindings)?;
+    let theme = Theme::new(&conf.theme)?;
 
     let storage = Arc::new(File::<UserSession>::new(PathBuf::from(
         "keys/bsky_creds.secret",
@@ -58,6 +80,7 @@ async fn main() -> Result<(), BmailError> {
         .unwrap();
 
     let bsky = SharableBluesky::new(client);
+    let appview = AppView::new(&conf.network.appview_url);
     let user_did = {
         let mut bsky_client = bsky.0.write().await;
         bsky_client
@@ -66,6 +89,27 @@ async fn main() -> Result<(), BmailError> {
             .await?
     };
 
+    if let Some(Command::Devices(command)) = command {
+        let session = Session {
+            bluesky: bsky.clone(),
+            identity: identity.clone(),
+            conf: conf.clone(),
+            user_did: Some(user_did.clone()),
+            ratchet: ratchet.clone(),
+            appview: appview.clone(),
+        };
+        return run_devices_command(command, &session).await;
+    }
+
+    let (socket, _response) = tokio_tungstenite::connect_async(
+        Url::parse("wss://bsky.social/xrpc/com.atproto.sync.subscribeRepos").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    // Create a new channel to send Posts from the Firehose thread
+    let (tx, rx) = mpsc::channel(32);
+
     // create app and run it
     let mut app = App {
         bluesky: bsky.clone(),
@@ -73,27 +117,57 @@ async fn main() -> Result<(), BmailError> {
         message_rx: Some(rx),
         user_did: Some(user_did.clone()),
         conf: conf.clone(),
+        known_handles: HashMap::from([(user_did.clone(), conf.user.handle.clone())]),
+        notifier: Notifier::new(conf.notifications.clone()),
+        store,
+        key_log,
+        ratchet,
+        appview,
+        time,
+        keymap,
+        theme,
         ..Default::default()
     };
 
     // Initialize Profile for Bmail Message Sending
     app.initialize().await?;
+    app.load_follows();
+    if conf.sync.on_startup {
+        app.spawn_startup_sync(tx.clone());
+    }
+
+    // Only Likes of our notification post are addressed to us. It's been made by now if it wasn't there
+    let profile = app.session().get_bmail_profile(&conf.user.handle).await?;
+    let notification_post = profile
+        .value
+        .bmail_notification_uri
+        .zip(profile.value.bmail_notification_cid);
 
-    //app.delete_rc_map_from_profile().await?;
-    //app.load_conversation(vec!["benw.is".to_string()]).await?;
+    //app.session().delete_rc_map_from_profile().await?;
+    //app.load_conversation(vec!["benw.is".to_string()]);
 
     // A new task is spawned for processing firehose messages. The socket is
     // moved to the new task and processed there.
     let _firehose = tokio::spawn(async move {
-        let _ = process_message(socket, tx, user_did).await;
+        let _ = process_message(socket, tx, user_did, notification_post).await;
     });
 
     //firehose.await.unwrap();
 
+    // Anything the key needs has been asked for by now, and the terminal is about to be taken over
+    if let Some(callbacks) = identity.callbacks() {
+        callbacks.start_app();
+    }
+
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    execute!(
+        stdout,
+        EnterAlternateScreen,
+        EnableMouseCapture,
+        EnableBracketedPaste
+    )?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
@@ -104,7 +178,8 @@ async fn main() -> Result<(), BmailError> {
     execute!(
         terminal.backend_mut(),
         LeaveAlternateScreen,
-        DisableMouseCapture
+        DisableMouseCapture,
+        DisableBracketedPaste
     )?;
     terminal.show_cursor()?;
 
@@ -115,10 +190,38 @@ async fn main() -> Result<(), BmailError> {
     Ok(())
 }
 
+/// Get a field of a CBOR map by name
+fn map_field<'a>(value: &'a serde_cbor::Value, field: &str) -> Option<&'a serde_cbor::Value> {
+    match value {
+        serde_cbor::Value::Map(m) => m.get(&Text(field.to_string())),
+        _ => None,
+    }
+}
+
+/// Get what kind of Bmail record this is, if it is one
+fn bmail_type(value: &serde_cbor::Value) -> Option<&str> {
+    match map_field(value, "bmail_type") {
+        Some(Text(t)) => Some(t.as_str()),
+        _ => None,
+    }
+}
+
+/// Whether a Like is for our notification post, given as its URI and CID
+fn likes_our_post(value: &serde_cbor::Value, notification_post: &Option<(String, String)>) -> bool {
+    let Some((post_uri, post_cid)) = notification_post else {
+        return false;
+    };
+    let subject = map_field(value, "subject");
+    let uri = subject.and_then(|subject| map_field(subject, "uri"));
+    let cid = subject.and_then(|subject| map_field(subject, "cid"));
+    matches!((uri, cid), (Some(Text(uri)), Some(Text(cid))) if uri == post_uri && cid == post_cid)
+}
+
 pub async fn process_message(
     mut socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
     tx: Sender<FirehoseMessages>,
     user_did: String,
+    notification_post: Option<(String, String)>,
 ) -> Result<(), BmailError> {
     while let Some(Ok(Message::Binary(message))) = socket.next().await {
         let (_header, body) = bisky::firehose::cbor::read(&message).unwrap();
@@ -127,7 +230,10 @@ pub async fn process_message(
                 continue;
             }
             let operation = &commit.operations[0];
+            let is_profile = operation.path == "app.bsky.actor.profile/self";
+            // Bmails live in profile records, notifications are Likes
             if !operation.path.starts_with("app.bsky.actor.profile/")
+                && !operation.path.starts_with("app.bsky.feed.like/")
             {
                 continue;
             }
@@ -136,33 +242,60 @@ pub async fn process_message(
                 let _car_header = bisky::firehose::car::read_header(&mut car_reader).unwrap();
                 let car_blocks = bisky::firehose::car::read_blocks(&mut car_reader).unwrap();
 
-                let record_reader = Cursor::new(car_blocks.get(&cid).unwrap());
-                let value = serde_cbor::from_reader::<serde_cbor::Value, _>(record_reader)
+                let Some(block) = car_blocks.get(&cid) else {
+                    continue;
+                };
+                let value = serde_cbor::from_reader::<serde_cbor::Value, _>(Cursor::new(block))
                     .map_err::<BmailError, _>(Into::into);
 
-                match &value {
-                    Ok(serde_cbor::Value::Map(r)) => {
-                        if r.get(&Text("bmail_type".to_string()))
-                            == Some(&Text("bmail".to_string()))
-                        {
-                            let bmail: FirehoseBmailMessageRecord = from_value(value.unwrap()).unwrap();
-                            //println!("{bmail:#?}");
-                            if bmail.bmail_recipients.contains(&user_did) {
-                                tx.send(FirehoseMessages::Bmail(bmail.try_into()?))
-                                    .await
-                                    .map_err::<BmailError, _>(Into::into)?;
-                            }
-                        } else if r.get(&Text("bmail_type".to_string()))
-                            == Some(&Text("notification".to_string()))
-                        {
-                            // println!("Found New Bmail Notification");
-                            let _notif: BmailLike = from_value(value.unwrap()).unwrap();
-                            // tx.send(FirehoseMessages::BmailLike(notif))
-                            //     .await
-                            //     .map_err::<BmailError, _>(Into::into)?;
+                // Anyone on the network can post these records, so one that doesn't parse is skipped
+                // rather than ending the Firehose
+                match value {
+                    // Profile updates carry people's keys, which go in the key log
+                    Ok(v @ serde_cbor::Value::Map(_)) if is_profile => {
+                        let keys = from_value::<FirehoseProfileKeys>(v).ok();
+                        if let Some(key) = keys.and_then(|k| k.key_set()) {
+                            tx.send(FirehoseMessages::PublicKey {
+                                did: commit.repo.clone(),
+                                key,
+                            })
+                            .await
+                            .map_err::<BmailError, _>(Into::into)?;
                         }
                     }
-                    Err(_) => (),
+                    Ok(v @ serde_cbor::Value::Map(_)) => match bmail_type(&v) {
+                        Some("bmail") => {
+                            let Ok(bmail) = from_value::<FirehoseBmailMessageRecord>(v) else {
+                                continue;
+                            };
+                            if !bmail.bmail_recipients.contains(&user_did) {
+                                continue;
+                            }
+                            let Ok(bmail) = BmailMessageRecord::try_from(bmail) else {
+                                continue;
+                            };
+                            tx.send(FirehoseMessages::Bmail(bmail))
+                                .await
+                                .map_err::<BmailError, _>(Into::into)?;
+                        }
+                        // Notification Likes are addressed to us if they like our notification post.
+                        // That's checked first, so Likes meant for other people aren't parsed at all
+                        Some("notification") if likes_our_post(&v, &notification_post) => {
+                            let Ok(notif) = from_value::<FirehoseBmailLike>(v) else {
+                                continue;
+                            };
+                            let Ok(like) = BmailLike::try_from(notif) else {
+                                continue;
+                            };
+                            tx.send(FirehoseMessages::BmailLike {
+                                did: commit.repo.clone(),
+                                like,
+                            })
+                            .await
+                            .map_err::<BmailError, _>(Into::into)?;
+                        }
+                        _ => (),
+                    },
                     _ => (),
                 };
             }
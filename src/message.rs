@@ -1,15 +1,21 @@
 use crate::{
     errors::BmailError,
-    key::{decrypt_and_decode, encrypt_and_encode, get_recipient_for_bskyer},
+    key::{decrypt_and_decode, encrypt_and_encode, get_recipient_for_bskyer, BmailIdentity},
+    ratchet::{decrypt_bmail, message_id, SharedRatchet, SignedPrekey},
     SharableBluesky,
 };
 use age::{x25519::Identity, Recipient as RecipientTrait};
 use bisky::lexicon::com::atproto::repo::{Blob, StrongRef};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::from_value;
 use std::{collections::BTreeMap, collections::HashMap};
 use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Bmails from this version on encrypt a [BmailPayload] as JSON, instead of just the text. Only Bmails that
+/// need the extra fields use it, so older clients can still read the rest
+pub const PAYLOAD_VERSION: usize = 1;
 
 #[derive(Default)]
 pub struct Conversation {
@@ -22,52 +28,39 @@ pub struct Conversation {
     pub recipient_active_time: HashMap<String, DateTime<Utc>>,
     /// The DID of the participants in a Conversation. Used so we know whose accounts to try to find messages on.
     pub participants: Vec<String>,
+    /// Number of notifications received for this Conversation since it was last viewed
+    pub unread_count: usize,
 }
 
 impl Conversation {
     /// Get Message Records for each Participant. If newer messages exist, add them to the local conversation
     /// This is run on initial conversation load in the UI, in case it's been updated since you last viewed it
-    /// by others or on another client
+    /// by others or on another client. Bmails sent from a key that doesn't match the sender's pinned keys are
+    /// left out, and the DIDs that sent them are returned so they can be warned about
     pub async fn update_with_messages_from_participants(
         &mut self,
         bsky: SharableBluesky,
         user_handle: &str,
-        identity: &Identity,
+        identity: &BmailIdentity,
+        ratchet: &SharedRatchet,
         participant_dids: Vec<String>,
-    ) -> Result<(), BmailError> {
+        sender_keys: &HashMap<String, String>,
+    ) -> Result<Vec<String>, BmailError> {
+        let mut key_changed: Vec<String> = Vec::new();
         let mixer_map: BTreeMap<MessageKey, DecryptedMessage> = BTreeMap::new();
         // 0. Get date of latest message for each participant from storage
         // This is covered by the recipient_active_time field
         // 1. Get All Message Records for each participant
-        let mut bsky = bsky.0.write().await;
-        let mut user = bsky.user(user_handle)?;
-
         // Is this cursed? Probably. Am I going to fix it now? Obviously not
         for participant in participant_dids.iter() {
-            let records = user
-                .list_all_records::<serde_json::Value>("app.bsky.actor.profile", participant, true)
-                .await?;
-
-            // Parse into final value
-            let mut bmail_records: Vec<BmailMessageRecord> = records
-                .into_iter()
-                .filter_map(|record| {
-                    if let serde_json::Value::Object(r) = &record.value {
-                        if r.get("bmail_type")
-                            == Some(&serde_json::Value::String("bmail".to_string()))
-                        {
-                            from_value(record.value).ok()
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
             // 1.1. Filter by Conversation ID
-            bmail_records.drain_filter(|r| r.bmail_conversation_id != self.conversation_id);
+            let mut bmail_records = get_bmail_records_for_participant(
+                bsky.clone(),
+                user_handle,
+                participant,
+                &self.conversation_id,
+            )
+            .await?;
             // 1.2. Drop/Drain any that are older than the latest for each participant
             // TODO: This is being skipped because active_time is not updated
             let latest_post = self.recipient_active_time.get(&participant.to_string());
@@ -76,20 +69,65 @@ impl Conversation {
             }
             // 1.3 Add Them to the Mixer Map
             for record in bmail_records.into_iter() {
-                let d_msg = record.into_decrypted_message(identity).await?;
-                insert_with_collisions(&mut self.messages, &d_msg);
+                let keys = sender_keys.get(&record.bmail_creator).map(String::as_str);
+                let d_msg = match decrypt_bmail(&record, identity, ratchet, keys).await {
+                    Ok(d_msg) => d_msg,
+                    Err(BmailError::KeyChanged(did)) => {
+                        if !key_changed.contains(&did) {
+                            key_changed.push(did);
+                        }
+                        continue;
+                    }
+                    // Bmails sent to a group before we were added weren't encrypted to us
+                    Err(BmailError::DecryptError(age::DecryptError::NoMatchingKeys))
+                    | Err(BmailError::MissingRecipientIdentity) => continue,
+                    Err(e) => return Err(e),
+                };
+                insert_with_collisions(&mut self.messages, d_msg);
             }
         }
 
         //2. Drain mixer_map into conversation
         mixer_map
             .into_iter()
-            .for_each(|(_k, v)| insert_with_collisions(&mut self.messages, &v));
+            .for_each(|(_k, v)| insert_with_collisions(&mut self.messages, v));
         
-        Ok(())
+        Ok(key_changed)
     }
 }
 
+/// Get all the Bmail Message Records a participant has stored in their repo for a Conversation
+pub async fn get_bmail_records_for_participant(
+    bsky: SharableBluesky,
+    user_handle: &str,
+    participant: &str,
+    conversation_id: &Uuid,
+) -> Result<Vec<BmailMessageRecord>, BmailError> {
+    let mut bsky = bsky.0.write().await;
+    let mut user = bsky.user(user_handle)?;
+    let records = user
+        .list_all_records::<serde_json::Value>("app.bsky.actor.profile", participant, true)
+        .await?;
+
+    // Parse into final value
+    let mut bmail_records: Vec<BmailMessageRecord> = records
+        .into_iter()
+        .filter_map(|record| {
+            if let serde_json::Value::Object(r) = &record.value {
+                if r.get("bmail_type") == Some(&serde_json::Value::String("bmail".to_string())) {
+                    from_value(record.value).ok()
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        })
+        .collect();
+    bmail_records.drain_filter(|r| &r.bmail_conversation_id != conversation_id);
+    Ok(bmail_records)
+}
+
 /// Keeps track of the messages seen on this client
 pub struct MyConversationPortion {
     pub conversation_id: Uuid,
@@ -135,28 +173,90 @@ pub struct BmailMessageRecord {
     pub bmail_creator_handle: String,
     pub bmail_version: usize,
     pub bmail_recipients: Vec<String>,
+    /// The key each recipient was encrypted to, by DID. Lets recipients compare the keys they've been shown
+    #[serde(default)]
+    pub bmail_recipient_keys: HashMap<String, String>,
+    /// Forward secret envelopes, by device key. When these are set bmail_cipher_text is empty
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub bmail_ratchet: HashMap<String, String>,
 }
 
 impl BmailMessageRecord {
     /// Convert a Bmail message from a BmailMessageRecord into a Message struct
     pub async fn into_decrypted_message(
         &self,
-        identity: &Identity,
+        identity: &BmailIdentity,
     ) -> Result<DecryptedMessage, BmailError> {
         let binary_message = decrypt_and_decode(identity, &self.bmail_cipher_text).await?;
+        self.with_plaintext(Zeroizing::new(
+            String::from_utf8(binary_message).map_err(BmailError::FromStringError)?,
+        ))
+    }
 
+    /// Make the Message from the decrypted text, unpacking the [BmailPayload] if it's in one
+    pub fn with_plaintext(
+        &self,
+        plaintext: Zeroizing<String>,
+    ) -> Result<DecryptedMessage, BmailError> {
+        let payload = match self.bmail_version >= PAYLOAD_VERSION {
+            true => serde_json::from_str(&plaintext).map_err(|_| BmailError::MalformedBmail)?,
+            false => BmailPayload {
+                message: plaintext,
+                disappear_after: None,
+                group: None,
+            },
+        };
         Ok(DecryptedMessage {
             created_at: self.bmail_created_at,
             creator: self.bmail_creator.clone(),
             creator_handle: self.bmail_creator_handle.clone(),
             conversation_id: self.bmail_conversation_id,
-            message: String::from_utf8(binary_message).map_err(BmailError::FromStringError)?,
+            message: payload.message,
             recipients: self.bmail_recipients.clone(),
+            recipient_keys: self.bmail_recipient_keys.clone(),
             version: self.bmail_version,
+            disappear_after: payload.disappear_after,
+            group: payload.group,
         })
     }
 }
 
+/// What's encrypted in a Bmail from [PAYLOAD_VERSION] on
+#[derive(Serialize, Deserialize)]
+pub struct BmailPayload {
+    pub message: Zeroizing<String>,
+    /// Seconds after it was sent that the Bmail disappears
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disappear_after: Option<u64>,
+    /// Set on control messages that change a group, instead of a message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<GroupUpdate>,
+}
+
+/// A change to a group, sent as an encrypted control message so only the people in it can see it. It has the
+/// whole group as it is after the change, for anyone who's just been added and hasn't seen the earlier ones
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GroupUpdate {
+    pub name: String,
+    /// DIDs of everyone in the group after the change, sorted
+    pub members: Vec<String>,
+    pub change: GroupChange,
+}
+
+/// What a [GroupUpdate] changed, so it can be shown in the Conversation
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupChange {
+    Created,
+    Renamed,
+    /// The DIDs that were added
+    Added(Vec<String>),
+    /// The DIDs that were removed
+    Removed(Vec<String>),
+    /// The sender left
+    Left,
+}
+
 /// Record type that can encoded for a Bmail Message
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FirehoseBmailMessageRecord {
@@ -171,6 +271,10 @@ pub struct FirehoseBmailMessageRecord {
     pub bmail_creator_handle: String,
     pub bmail_version: usize,
     pub bmail_recipients: Vec<String>,
+    #[serde(default)]
+    pub bmail_recipient_keys: HashMap<String, String>,
+    #[serde(default)]
+    pub bmail_ratchet: HashMap<String, String>,
 }
 
 impl TryFrom<FirehoseBmailMessageRecord> for BmailMessageRecord {
@@ -186,6 +290,8 @@ impl TryFrom<FirehoseBmailMessageRecord> for BmailMessageRecord {
             bmail_creator_handle: message.bmail_creator_handle,
             bmail_version: message.bmail_version,
             bmail_recipients: message.bmail_recipients,
+            bmail_recipient_keys: message.bmail_recipient_keys,
+            bmail_ratchet: message.bmail_ratchet,
         })
     }
 }
@@ -196,45 +302,120 @@ pub struct DecryptedMessage {
     pub creator: String,
     pub creator_handle: String,
     pub conversation_id: Uuid,
-    pub message: String,
+    /// The plaintext, wiped from memory when it's dropped
+    pub message: Zeroizing<String>,
     pub recipients: Vec<String>,
+    /// The key each recipient was encrypted to, by DID, as the sender saw them
+    #[serde(default)]
+    pub recipient_keys: HashMap<String, String>,
     pub version: usize,
+    /// Seconds after it was sent that it disappears, if the Conversation has a timer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disappear_after: Option<u64>,
+    /// The change to the group, if this is a control message rather than a message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<GroupUpdate>,
 }
 
 impl DecryptedMessage {
-    /// Convert a Decrypted Message(PlainText) into a BmailProfileRecord(Encrypted)
+    /// Get when the Bmail disappears, if it does
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.disappear_after
+            .map(|seconds| self.created_at + Duration::seconds(seconds as i64))
+    }
+
+    pub fn is_expired(&self, now: &DateTime<Utc>) -> bool {
+        matches!(self.expires_at(), Some(expires_at) if expires_at <= *now)
+    }
+
+    /// Identify the Bmail, to find its plaintext again. See [message_id]
+    pub fn id(&self) -> String {
+        message_id(&self.conversation_id, &self.creator, &self.created_at)
+    }
+
+    /// Get what gets encrypted: the text, or a [BmailPayload] as JSON for newer versions
+    fn payload(&self) -> Result<Zeroizing<String>, BmailError> {
+        if self.version < PAYLOAD_VERSION {
+            return Ok(self.message.clone());
+        }
+        let payload = BmailPayload {
+            message: self.message.clone(),
+            disappear_after: self.disappear_after,
+            group: self.group.clone(),
+        };
+        Ok(Zeroizing::new(serde_json::to_string(&payload)?))
+    }
+
+    /// Convert a Decrypted Message(PlainText) into a BmailProfileRecord(Encrypted). A recipient's key has to
+    /// match the one pinned for them, if there is one. Also returns the keys it was encrypted to, by recipient.
+    /// If a ratchet is given and every device has a prekey or session, it's sent forward secret instead of
+    /// with age. The last value says whether it was
     pub async fn into_bmail_record(
         &self,
         bsky: SharableBluesky,
-    ) -> Result<BmailMessageRecord, BmailError> {
+        pinned_keys: &HashMap<String, String>,
+        allowed_plugins: &[String],
+        ratchet: Option<(&SharedRatchet, &Identity)>,
+    ) -> Result<(BmailMessageRecord, HashMap<String, String>, bool), BmailError> {
+        let mut used_keys = HashMap::new();
+        let mut devices: Vec<(String, Option<SignedPrekey>)> = Vec::new();
         let recipient_keys = {
             let mut keys: Vec<Box<dyn RecipientTrait + Send>> =
                 Vec::with_capacity(self.recipients.len());
             for recipient in &self.recipients {
-                let (recipient_key, _profile_record) =
-                    get_recipient_for_bskyer(bsky.clone(), recipient).await?;
-                if let Some(key) = recipient_key {
-                    keys.push(Box::new(key));
-                } else {
+                let (recipient_keys, profile_record) =
+                    get_recipient_for_bskyer(bsky.clone(), recipient, allowed_plugins).await?;
+                let Some(key_set) = profile_record.value.key_set() else {
                     return Err(BmailError::MissingRecipient(recipient.to_string()));
+                };
+                // Someone could have swapped the key on their profile, or added a device, to read this
+                if matches!(pinned_keys.get(recipient), Some(pinned) if *pinned != key_set) {
+                    return Err(BmailError::KeyChanged(recipient.to_string()));
                 }
+                used_keys.insert(recipient.to_string(), key_set);
+                devices.extend(profile_record.value.active_devices());
+                // Every one of their devices gets to read it
+                keys.extend(recipient_keys);
             }
             keys
         };
 
-        let encoded = encrypt_and_encode(recipient_keys, self.message.as_bytes()).await?;
-
-        Ok(BmailMessageRecord {
+        let mut record = BmailMessageRecord {
             bmail_created_at: self.created_at,
             bmail_conversation_id: self.conversation_id,
-            bmail_cipher_text: encoded,
+            bmail_cipher_text: String::new(),
             bmail_type: "bmail".to_string(),
             bmail_version: self.version,
             bmail_recipients: self.recipients.clone(),
             bmail_creator: self.creator.clone(),
             rust_type: "app.bsky.actor.profile".to_string(),
             bmail_creator_handle: self.creator_handle.clone(),
-        })
+            bmail_recipient_keys: used_keys.clone(),
+            bmail_ratchet: HashMap::new(),
+        };
+
+        let payload = self.payload()?;
+        if let Some((ratchet, identity)) = ratchet {
+            let mut ratchet = ratchet.lock().await;
+            // This device keeps the plaintext instead, since it can't have a session with itself
+            let our_key = identity.to_public().to_string();
+            devices.retain(|(key, _)| *key != our_key);
+            if devices
+                .iter()
+                .all(|(key, prekey)| ratchet.can_encrypt_to(key, prekey.as_ref()))
+            {
+                for (key, prekey) in &devices {
+                    let envelope =
+                        ratchet.encrypt(identity, key, prekey.as_ref(), payload.as_bytes())?;
+                    record.bmail_ratchet.insert(key.clone(), envelope);
+                }
+                ratchet.remember(self.id(), payload, self.expires_at());
+                return Ok((record, used_keys, true));
+            }
+        }
+
+        record.bmail_cipher_text = encrypt_and_encode(recipient_keys, payload.as_bytes()).await?;
+        Ok((record, used_keys, false))
     }
 }
 
@@ -251,13 +432,99 @@ pub struct BmailEnabledProfile {
     pub description: Option<String>,
     #[serde(rename(deserialize = "displayName", serialize = "displayName"))]
     pub display_name: Option<String>,
+    /// The key from before there were devices. Kept set to one of the devices, for older clients
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bmail_pub_key: Option<String>,
+    /// Every device that has used Bmail on this account, each with its own key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bmail_devices: Option<Vec<BmailDevice>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bmail_notification_uri: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bmail_notification_cid: Option<String>,
     pub bmail_rc_map: Option<String>,
+    /// Conversation titles, mutes and archives, encrypted to every one of our devices. See [crate::store::SyncedSettings]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bmail_settings: Option<String>,
+    /// The keys bmail_settings is encrypted to, so a device that can read it knows to share it with new ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bmail_settings_keys: Option<String>,
+}
+
+impl BmailEnabledProfile {
+    /// Get the keys Bmails to this person should be encrypted to. See [active_keys]
+    pub fn active_keys(&self) -> Vec<String> {
+        active_keys(&self.bmail_pub_key, &self.bmail_devices)
+    }
+
+    /// Get all the keys this person has published as one string. See [key_set]
+    pub fn key_set(&self) -> Option<String> {
+        key_set(self.active_keys())
+    }
+
+    /// Get the key and prekey of every device that hasn't been revoked
+    pub fn active_devices(&self) -> Vec<(String, Option<SignedPrekey>)> {
+        match &self.bmail_devices {
+            Some(devices) => devices
+                .iter()
+                .filter(|d| d.revoked_at.is_none())
+                .map(|d| (d.key.clone(), d.prekey.clone()))
+                .collect(),
+            None => self.bmail_pub_key.iter().map(|k| (k.clone(), None)).collect(),
+        }
+    }
+}
+
+/// One of the devices someone uses Bmail on
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BmailDevice {
+    pub label: String,
+    pub key: String,
+    pub added_at: DateTime<Utc>,
+    /// When the device was revoked. Revoked devices are kept in the list so their key can't be quietly added back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// A signed prekey, for starting forward secret sessions. Only there if forward secrecy is on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prekey: Option<SignedPrekey>,
+}
+
+/// Just the key fields of a profile, as they come off the Firehose
+#[derive(Debug, Clone, Deserialize)]
+pub struct FirehoseProfileKeys {
+    pub bmail_pub_key: Option<String>,
+    #[serde(default)]
+    pub bmail_devices: Option<Vec<BmailDevice>>,
+}
+
+impl FirehoseProfileKeys {
+    pub fn key_set(&self) -> Option<String> {
+        key_set(active_keys(&self.bmail_pub_key, &self.bmail_devices))
+    }
+}
+
+/// Get the keys of every device that hasn't been revoked. Profiles from before there were devices only have
+/// the one bmail_pub_key
+pub fn active_keys(pub_key: &Option<String>, devices: &Option<Vec<BmailDevice>>) -> Vec<String> {
+    match devices {
+        Some(devices) => devices
+            .iter()
+            .filter(|d| d.revoked_at.is_none())
+            .map(|d| d.key.clone())
+            .collect(),
+        None => pub_key.iter().cloned().collect(),
+    }
+}
+
+/// Join someone's keys into one string, sorted so it's the same whatever order they're listed in. This is
+/// what gets pinned, logged and fingerprinted, so adding or revoking a device shows up as a key change
+pub fn key_set(mut keys: Vec<String>) -> Option<String> {
+    if keys.is_empty() {
+        return None;
+    }
+    keys.sort();
+    keys.dedup();
+    Some(keys.join(","))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -266,16 +533,42 @@ pub struct BmailLike {
     #[serde(rename(serialize = "createdAt"))]
     pub created_at: DateTime<Utc>,
     pub subject: StrongRef,
+    /// The sorted DIDs of every participant in the Conversation, including the sender
     pub bmail_recipients: Vec<String>,
     pub bmail_conversation_id: Uuid,
     pub bmail_type: String,
 }
 
+/// Notification Like as it comes off the Firehose. CBOR doesn't give us a Uuid, so it has to be parsed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirehoseBmailLike {
+    #[serde(rename(deserialize = "createdAt"))]
+    #[serde(rename(serialize = "createdAt"))]
+    pub created_at: DateTime<Utc>,
+    pub subject: StrongRef,
+    pub bmail_recipients: Vec<String>,
+    pub bmail_conversation_id: String,
+    pub bmail_type: String,
+}
+
+impl TryFrom<FirehoseBmailLike> for BmailLike {
+    type Error = BmailError;
+    fn try_from(like: FirehoseBmailLike) -> Result<Self, Self::Error> {
+        Ok(Self {
+            bmail_conversation_id: Uuid::parse_str(&like.bmail_conversation_id)?,
+            created_at: like.created_at,
+            subject: like.subject,
+            bmail_recipients: like.bmail_recipients,
+            bmail_type: like.bmail_type,
+        })
+    }
+}
+
 /// Function that can be recursed over to insert into a BTreeMap with possible collisions
 /// If the value is present at the key, skip insert
 pub fn insert_with_collisions(
     map: &mut BTreeMap<MessageKey, DecryptedMessage>,
-    msg: &DecryptedMessage,
+    msg: DecryptedMessage,
 ) {
     let mut count = 0;
     let mut key = MessageKey::new_with_count(count, &msg.created_at);
@@ -283,13 +576,13 @@ pub fn insert_with_collisions(
         // If the key is present, and the messages match, we don't need to insert it again
         if map.contains_key(&key){
             let val = map.get(&key).unwrap(); 
-            if val == msg{
+            if *val == msg{
                 return;
             } 
         }
         // If the key is not present, then we need to insert it 
         else if !map.contains_key(&key) {
-            map.insert(key.clone(), msg.clone());
+            map.insert(key.clone(), msg);
             return;
         }
         count += 1;
@@ -301,5 +594,10 @@ pub fn insert_with_collisions(
 #[derive(Debug, Clone)]
 pub enum FirehoseMessages {
     Bmail(BmailMessageRecord),
-    BmailLike(BmailLike),
+    /// A notification Like, and the DID of whoever made it
+    BmailLike { did: String, like: BmailLike },
+    /// An older Bmail fetched by the startup sync rather than seen on the Firehose. These don't notify
+    SyncedBmail(BmailMessageRecord),
+    /// Someone's profile was updated with a Bmail key
+    PublicKey { did: String, key: String },
 }
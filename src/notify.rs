@@ -0,0 +1,76 @@
+use std::{collections::HashMap, io::Write, time::Duration, time::Instant};
+
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{conf::NotificationSettings, message::DecryptedMessage};
+
+/// Tells the user about new Bmails outside of the message list, with a desktop notification or the terminal bell
+#[derive(Default)]
+pub struct Notifier {
+    pub settings: NotificationSettings,
+    /// When we last notified about each Conversation, so a busy one doesn't spam the desktop
+    last_notified: HashMap<Uuid, Instant>,
+}
+
+impl Notifier {
+    pub fn new(settings: NotificationSettings) -> Self {
+        Self {
+            settings,
+            last_notified: HashMap::new(),
+        }
+    }
+
+    /// Notify about a new Bmail, unless we've already notified about this Conversation recently
+    pub fn notify(&mut self, msg: &DecryptedMessage, conversation_label: &str) {
+        if !self.settings.desktop && !self.settings.bell {
+            return;
+        }
+
+        let now = Instant::now();
+        let rate_limit = Duration::from_secs(self.settings.rate_limit_seconds);
+        if let Some(last) = self.last_notified.get(&msg.conversation_id) {
+            if now.duration_since(*last) < rate_limit {
+                return;
+            }
+        }
+        self.last_notified.insert(msg.conversation_id, now);
+
+        if !self.settings.desktop {
+            ring_bell();
+            return;
+        }
+
+        let summary = format!("Bmail from {}", msg.creator_handle);
+        // This can hold the plaintext, so it's sized up front to not leave copies behind and wiped once shown
+        let contents = match self.settings.show_contents {
+            true => msg.message.as_str(),
+            false => "New encrypted message",
+        };
+        let mut body = Zeroizing::new(String::with_capacity(
+            conversation_label.len() + 1 + contents.len(),
+        ));
+        body.push_str(conversation_label);
+        body.push('\n');
+        body.push_str(contents);
+        let bell = self.settings.bell;
+        // Talking to D-Bus blocks, so keep it off the UI thread
+        tokio::task::spawn_blocking(move || {
+            let shown = notify_rust::Notification::new()
+                .appname("bmail")
+                .summary(&summary)
+                .body(&body)
+                .show();
+            if shown.is_err() && bell {
+                ring_bell();
+            }
+        });
+    }
+}
+
+/// Ring the terminal bell. Most terminals will flash or mark the tab if it's in the background
+pub fn ring_bell() {
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(b"\x07");
+    let _ = stdout.flush();
+}
@@ -0,0 +1,931 @@
+use age::secrecy::ExposeSecret;
+use age::x25519::Identity;
+use base64::engine::general_purpose;
+use base64::Engine;
+use bech32::FromBase32;
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, KeyInit,
+};
+use chrono::{DateTime, Duration, Utc};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::errors::BmailError;
+use crate::key::{decrypt_and_decode, encrypt_and_encode, BmailIdentity};
+use crate::message::{BmailMessageRecord, DecryptedMessage};
+use crate::xeddsa;
+
+/// How many message keys a session keeps for Bmails that haven't arrived yet
+const MAX_SKIP: u32 = 1000;
+/// How long a signed prekey is published before it's replaced
+const PREKEY_LIFETIME_DAYS: i64 = 7;
+/// How many replaced prekeys are kept, for Bmails that were started with them before they were replaced
+const OLD_PREKEYS: usize = 2;
+
+/// The ratchet state, shared between the App and background tasks
+pub type SharedRatchet = Arc<Mutex<RatchetStore>>;
+
+/// A short lived key published with a device, signed by the device's own key. Starting a session with it
+/// means the device's long term key alone can't decrypt what's sent
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SignedPrekey {
+    /// base64 X25519 public key
+    pub key: String,
+    /// base64 XEdDSA signature by the device key
+    pub signature: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl SignedPrekey {
+    /// Check the prekey was signed by the device key it was published with, and get its bytes if it was
+    pub fn verify(&self, device_key: &str) -> Option<[u8; 32]> {
+        let device = recipient_bytes(device_key).ok()?;
+        let key = decode_key(&self.key).ok()?;
+        let signature = general_purpose::STANDARD_NO_PAD
+            .decode(&self.signature)
+            .ok()?;
+        xeddsa::verify(&device, &prekey_message(&key), &signature).then_some(key)
+    }
+}
+
+fn prekey_message(key: &[u8; 32]) -> Vec<u8> {
+    [b"bmail prekey\n".as_slice(), key.as_slice()].concat()
+}
+
+/// Sent with the first Bmails of a session, so the recipient can set their end up from their prekey
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct InitHeader {
+    ephemeral: [u8; 32],
+    prekey: [u8; 32],
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Header {
+    /// The sender's current ratchet key
+    dh: [u8; 32],
+    /// How many Bmails were sent on the sender's previous chain
+    previous: u32,
+    n: u32,
+    init: Option<InitHeader>,
+}
+
+/// A Bmail encrypted for one device
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    /// The device key of the sender, to find the session with
+    sender: String,
+    header: Header,
+    ciphertext: Vec<u8>,
+}
+
+/// One end of a Double Ratchet session with another device. See https://signal.org/docs/specifications/doubleratchet/
+#[derive(Clone, Serialize, Deserialize)]
+struct RatchetSession {
+    dh_secret: [u8; 32],
+    dh_public: [u8; 32],
+    their_dh: Option<[u8; 32]>,
+    root_key: [u8; 32],
+    send_chain: Option<[u8; 32]>,
+    receive_chain: Option<[u8; 32]>,
+    sent: u32,
+    received: u32,
+    previous_sent: u32,
+    /// Keys for Bmails that were skipped over, by ratchet key and number
+    skipped: HashMap<String, [u8; 32]>,
+    /// Set until they reply, so they can start the session from any of our Bmails
+    init: Option<InitHeader>,
+    /// Both device keys, so a Bmail can't be passed off as coming from a different session
+    associated_data: Vec<u8>,
+    /// Our prekey they started it with, if they started it
+    #[serde(default)]
+    started_from: Option<[u8; 32]>,
+}
+
+/// Old keys are what forward secrecy is about getting rid of, so they're wiped rather than just freed
+impl Drop for RatchetSession {
+    fn drop(&mut self) {
+        self.dh_secret.zeroize();
+        self.root_key.zeroize();
+        self.send_chain.zeroize();
+        self.receive_chain.zeroize();
+        self.skipped.values_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+impl RatchetSession {
+    /// Start a session with a device from its signed prekey
+    fn initiate(
+        our_secret: &[u8; 32],
+        their_device: &[u8; 32],
+        prekey: [u8; 32],
+    ) -> Result<Self, BmailError> {
+        let (ephemeral_secret, ephemeral) = generate_keypair();
+        let shared = agree(&[
+            (our_secret, &prekey),
+            (&ephemeral_secret, their_device),
+            (&ephemeral_secret, &prekey),
+        ])?;
+        let (dh_secret, dh_public) = generate_keypair();
+        let (root_key, send_chain) = kdf_root(&shared, &dh(&dh_secret, &prekey)?);
+        Ok(Self {
+            dh_secret,
+            dh_public,
+            their_dh: Some(prekey),
+            root_key,
+            send_chain: Some(send_chain),
+            receive_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: HashMap::new(),
+            init: Some(InitHeader { ephemeral, prekey }),
+            associated_data: [public_key(our_secret), *their_device].concat(),
+            started_from: None,
+        })
+    }
+
+    /// Set up our end of a session someone started with one of our prekeys
+    fn accept(
+        our_secret: &[u8; 32],
+        their_device: &[u8; 32],
+        prekey_secret: &[u8; 32],
+        init: &InitHeader,
+    ) -> Result<Self, BmailError> {
+        let shared = agree(&[
+            (prekey_secret, their_device),
+            (our_secret, &init.ephemeral),
+            (prekey_secret, &init.ephemeral),
+        ])?;
+        Ok(Self {
+            dh_secret: *prekey_secret,
+            dh_public: init.prekey,
+            their_dh: None,
+            root_key: shared,
+            send_chain: None,
+            receive_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: HashMap::new(),
+            init: None,
+            associated_data: [*their_device, public_key(our_secret)].concat(),
+            started_from: Some(init.prekey),
+        })
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<(Header, Vec<u8>), BmailError> {
+        let Some(send_chain) = self.send_chain else {
+            // We haven't heard back since they started the session, so start a new sending chain
+            self.ratchet_sending()?;
+            return self.encrypt(plaintext);
+        };
+        let (send_chain, message_key) = kdf_chain(&send_chain);
+        self.send_chain = Some(send_chain);
+        let header = Header {
+            dh: self.dh_public,
+            previous: self.previous_sent,
+            n: self.sent,
+            init: self.init.clone(),
+        };
+        self.sent += 1;
+        let ciphertext = seal(&message_key, plaintext, &self.header_data(&header)?)?;
+        Ok((header, ciphertext))
+    }
+
+    fn decrypt(&mut self, header: &Header, ciphertext: &[u8]) -> Result<Vec<u8>, BmailError> {
+        let header_data = self.header_data(header)?;
+        if let Some(message_key) = self.skipped.remove(&skipped_id(&header.dh, header.n)) {
+            return open(&message_key, ciphertext, &header_data);
+        }
+        if self.their_dh != Some(header.dh) {
+            self.skip_until(header.previous)?;
+            self.ratchet_receiving(header.dh)?;
+        }
+        self.skip_until(header.n)?;
+        let Some(receive_chain) = self.receive_chain else {
+            return Err(BmailError::RatchetError("No receiving chain".to_string()));
+        };
+        let (receive_chain, message_key) = kdf_chain(&receive_chain);
+        self.receive_chain = Some(receive_chain);
+        self.received += 1;
+        let plaintext = open(&message_key, ciphertext, &header_data)?;
+        // We've heard back, so they have the session and don't need to be told how to start it
+        self.init = None;
+        Ok(plaintext)
+    }
+
+    /// Keep the keys for Bmails on the current chain we haven't seen yet, up to a number
+    fn skip_until(&mut self, until: u32) -> Result<(), BmailError> {
+        let (Some(mut chain), Some(their_dh)) = (self.receive_chain, self.their_dh) else {
+            return Ok(());
+        };
+        if until > self.received + MAX_SKIP {
+            return Err(BmailError::RatchetError(
+                "Too many skipped Bmails".to_string(),
+            ));
+        }
+        while self.received < until {
+            let (next, message_key) = kdf_chain(&chain);
+            self.skipped
+                .insert(skipped_id(&their_dh, self.received), message_key);
+            chain = next;
+            self.received += 1;
+        }
+        self.receive_chain = Some(chain);
+        Ok(())
+    }
+
+    /// They've sent a new ratchet key, so step the root chain forward for receiving, then again with a new key
+    /// of our own for sending
+    fn ratchet_receiving(&mut self, their_dh: [u8; 32]) -> Result<(), BmailError> {
+        self.previous_sent = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.their_dh = Some(their_dh);
+        let (root_key, receive_chain) = kdf_root(&self.root_key, &dh(&self.dh_secret, &their_dh)?);
+        self.root_key = root_key;
+        self.receive_chain = Some(receive_chain);
+        self.ratchet_sending()
+    }
+
+    fn ratchet_sending(&mut self) -> Result<(), BmailError> {
+        let Some(their_dh) = self.their_dh else {
+            return Err(BmailError::RatchetError(
+                "They haven't sent a ratchet key yet".to_string(),
+            ));
+        };
+        let (dh_secret, dh_public) = generate_keypair();
+        self.dh_secret = dh_secret;
+        self.dh_public = dh_public;
+        let (root_key, send_chain) = kdf_root(&self.root_key, &dh(&self.dh_secret, &their_dh)?);
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+        Ok(())
+    }
+
+    fn header_data(&self, header: &Header) -> Result<Vec<u8>, BmailError> {
+        let mut data = self.associated_data.clone();
+        ciborium::ser::into_writer(header, &mut data)?;
+        Ok(data)
+    }
+}
+
+/// One of our prekeys, with its secret half
+#[derive(Clone, Serialize, Deserialize)]
+struct OwnPrekey {
+    secret: [u8; 32],
+    published: SignedPrekey,
+}
+
+impl Drop for OwnPrekey {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+/// Everything the ratchet keeps on this computer
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct RatchetData {
+    /// Our signed prekeys, newest first
+    prekeys: Vec<OwnPrekey>,
+    /// Sessions, by the other device's key
+    sessions: HashMap<String, RatchetSession>,
+    /// Forward secret Bmails we've already decrypted or sent, by [message_id]. Their keys are gone, so this is
+    /// the only way to show them again
+    plaintexts: HashMap<String, Zeroizing<String>>,
+    /// When plaintexts of disappearing Bmails have to be forgotten, by [message_id]
+    expiries: HashMap<String, DateTime<Utc>>,
+    /// Sessions that have been started with our prekeys, so they can't be started again with a replayed
+    /// Bmail. Only ones for prekeys we still have are kept, since the rest can't be started anyway
+    used_inits: Vec<InitHeader>,
+}
+
+/// A decrypted envelope, with the changes to the session it needs. See [RatchetStore::keep]
+pub struct Decrypted {
+    /// The sender's device key
+    pub sender: String,
+    pub plaintext: Vec<u8>,
+    /// The session after decrypting it, if it should replace the one we have
+    session: Option<RatchetSession>,
+    /// How the session was started, if the envelope says
+    init: Option<InitHeader>,
+}
+
+/// Forward secret sessions with other devices. Kept in a file encrypted to our own key, like the local store
+#[derive(Default)]
+pub struct RatchetStore {
+    /// Where the state is saved. If this is None it only lives in memory
+    path: Option<PathBuf>,
+    data: RatchetData,
+    /// Whether anything has changed since the last save
+    dirty: bool,
+}
+
+impl RatchetStore {
+    /// Open the state, starting an empty one if the file doesn't exist yet
+    pub async fn open(path: PathBuf, identity: &BmailIdentity) -> Result<Self, BmailError> {
+        let data = match tokio::fs::read_to_string(&path).await {
+            // Starting empty would overwrite it on the next save, so it's left for the user to deal with
+            Ok(contents) if !contents.is_empty() => decrypt_and_decode(identity, contents.trim())
+                .await
+                .map_err(|e| {
+                    BmailError::UnreadableStore(path.display().to_string(), e.to_string())
+                })?,
+            Ok(_) => RatchetData::default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RatchetData::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            data,
+            dirty: false,
+        })
+    }
+
+    /// Write the state out if anything has changed
+    pub async fn save(&mut self, identity: &BmailIdentity) -> Result<(), BmailError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        let encoded = encrypt_and_encode(vec![identity.recipient()?], &self.data).await?;
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, encoded).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Get the prekey to publish, making a new one if the current one is too old. The last few are kept so
+    /// sessions started with them still work
+    pub fn current_prekey(&mut self, identity: &Identity) -> Result<SignedPrekey, BmailError> {
+        let fresh = matches!(self.data.prekeys.first(),
+            Some(p) if Utc::now() - p.published.created_at < Duration::days(PREKEY_LIFETIME_DAYS));
+        if !fresh {
+            let (secret, public) = generate_keypair();
+            let signature = xeddsa::sign(&identity_bytes(identity)?, &prekey_message(&public));
+            self.data.prekeys.insert(
+                0,
+                OwnPrekey {
+                    secret,
+                    published: SignedPrekey {
+                        key: general_purpose::STANDARD_NO_PAD.encode(public),
+                        signature: general_purpose::STANDARD_NO_PAD.encode(signature),
+                        created_at: Utc::now(),
+                    },
+                },
+            );
+            self.data.prekeys.truncate(OLD_PREKEYS + 1);
+            let prekeys: Vec<[u8; 32]> = self
+                .data
+                .prekeys
+                .iter()
+                .filter_map(|p| decode_key(&p.published.key).ok())
+                .collect();
+            self.data
+                .used_inits
+                .retain(|init| prekeys.contains(&init.prekey));
+            self.dirty = true;
+        }
+        Ok(self.data.prekeys[0].published.clone())
+    }
+
+    /// Whether we can send forward secret Bmails to a device, because we have a session with it or it has
+    /// published a prekey
+    pub fn can_encrypt_to(&self, device_key: &str, prekey: Option<&SignedPrekey>) -> bool {
+        self.data.sessions.contains_key(device_key)
+            || matches!(prekey, Some(p) if p.verify(device_key).is_some())
+    }
+
+    /// Encrypt a Bmail for one device, starting a session with it if there isn't one. Returns the envelope
+    /// to put in the record for that device
+    pub fn encrypt(
+        &mut self,
+        identity: &Identity,
+        device_key: &str,
+        prekey: Option<&SignedPrekey>,
+        plaintext: &[u8],
+    ) -> Result<String, BmailError> {
+        let mut session = match self.data.sessions.get(device_key) {
+            Some(session) => session.clone(),
+            None => {
+                let prekey = prekey.and_then(|p| p.verify(device_key)).ok_or_else(|| {
+                    BmailError::RatchetError(format!("No valid prekey for {}", device_key))
+                })?;
+                RatchetSession::initiate(
+                    &identity_bytes(identity)?,
+                    &recipient_bytes(device_key)?,
+                    prekey,
+                )?
+            }
+        };
+        let (header, ciphertext) = session.encrypt(plaintext)?;
+        self.data.sessions.insert(device_key.to_string(), session);
+        self.dirty = true;
+        let envelope = Envelope {
+            sender: identity.to_public().to_string(),
+            header,
+            ciphertext,
+        };
+        let mut buffer = Vec::new();
+        ciborium::ser::into_writer(&envelope, &mut buffer)?;
+        Ok(general_purpose::STANDARD_NO_PAD.encode(buffer))
+    }
+
+    /// Decrypt an envelope sent to this device. Nothing changes until the result is passed to
+    /// [RatchetStore::keep], so a bad or replayed Bmail doesn't break the session, and the sender can be
+    /// checked first
+    pub fn decrypt(&self, identity: &Identity, envelope: &str) -> Result<Decrypted, BmailError> {
+        let decoded = general_purpose::STANDARD_NO_PAD.decode(envelope)?;
+        let envelope: Envelope = ciborium::de::from_reader(decoded.as_slice())?;
+        let our_secret = identity_bytes(identity)?;
+        let their_device = recipient_bytes(&envelope.sender)?;
+
+        let existing = self.data.sessions.get(&envelope.sender);
+        let mut error = BmailError::RatchetError("No session with the sender".to_string());
+        if let Some(mut session) = existing.cloned() {
+            match session.decrypt(&envelope.header, &envelope.ciphertext) {
+                Ok(plaintext) => {
+                    return Ok(Decrypted {
+                        init: envelope.header.init.clone(),
+                        sender: envelope.sender,
+                        plaintext,
+                        session: Some(session),
+                    })
+                }
+                Err(e) => error = e,
+            }
+        }
+        // They may have started a new session, if they lost theirs or we both started one at once
+        let Some(init) = envelope.header.init.clone() else {
+            return Err(error);
+        };
+        // Envelopes are public, so an old one could be posted again to rewind the session to its start
+        if self.data.used_inits.contains(&init) {
+            return Err(BmailError::RatchetError(
+                "That session has already been started".to_string(),
+            ));
+        }
+        let prekey = self
+            .data
+            .prekeys
+            .iter()
+            .find(|p| decode_key(&p.published.key).ok() == Some(init.prekey))
+            .ok_or_else(|| BmailError::RatchetError("Unknown prekey".to_string()))?;
+        let mut session =
+            RatchetSession::accept(&our_secret, &their_device, &prekey.secret, &init)?;
+        let plaintext = session.decrypt(&envelope.header, &envelope.ciphertext)?;
+        // A session from an older prekey than the one we have was started before it, so it isn't the one
+        // they're using now. Its Bmail can still be read, but it doesn't replace the newer session
+        let newer = match existing.and_then(|s| s.started_from) {
+            Some(current) => self.prekey_age(&init.prekey) <= self.prekey_age(&current),
+            None => true,
+        };
+        Ok(Decrypted {
+            sender: envelope.sender,
+            plaintext,
+            session: newer.then_some(session),
+            init: Some(init),
+        })
+    }
+
+    /// Keep the session changes from decrypting an envelope, once the sender has been checked. Returns
+    /// the plaintext
+    pub fn keep(&mut self, decrypted: Decrypted) -> Vec<u8> {
+        let Decrypted {
+            sender,
+            plaintext,
+            session,
+            init,
+        } = decrypted;
+        if let Some(init) = init {
+            if !self.data.used_inits.contains(&init) {
+                self.data.used_inits.push(init);
+            }
+        }
+        if let Some(session) = session {
+            self.data.sessions.insert(sender, session);
+        }
+        self.dirty = true;
+        plaintext
+    }
+
+    /// How many prekeys have been made since this one. Ones we no longer have are the oldest of all
+    fn prekey_age(&self, prekey: &[u8; 32]) -> usize {
+        self.data
+            .prekeys
+            .iter()
+            .position(|p| decode_key(&p.published.key).ok() == Some(*prekey))
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Keep the plaintext of a forward secret Bmail, since it can't be decrypted again. Disappearing Bmails
+    /// are only kept until they expire
+    pub fn remember(
+        &mut self,
+        message_id: String,
+        plaintext: Zeroizing<String>,
+        expires_at: Option<DateTime<Utc>>,
+    ) {
+        if let Some(expires_at) = expires_at {
+            self.data.expiries.insert(message_id.clone(), expires_at);
+        }
+        self.data.plaintexts.insert(message_id, plaintext);
+        self.dirty = true;
+    }
+
+    pub fn recall(&self, message_id: &str) -> Option<&Zeroizing<String>> {
+        self.data.plaintexts.get(message_id)
+    }
+
+    /// Drop the plaintexts of disappearing Bmails that have expired. Returns how many were dropped
+    pub fn forget_expired(&mut self, now: &DateTime<Utc>) -> usize {
+        let expired: Vec<String> = self
+            .data
+            .expiries
+            .iter()
+            .filter(|(_, expires_at)| *expires_at <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired.iter() {
+            self.data.expiries.remove(id);
+            self.data.plaintexts.remove(id);
+        }
+        if !expired.is_empty() {
+            self.dirty = true;
+        }
+        expired.len()
+    }
+}
+
+/// Identify a Bmail, to find its plaintext again
+pub fn message_id(conversation_id: &Uuid, creator: &str, created_at: &DateTime<Utc>) -> String {
+    format!("{}|{}|{}", conversation_id, creator, created_at.to_rfc3339())
+}
+
+/// Decrypt a Bmail record, with the ratchet if it was sent forward secret. The sender has to be using a
+/// device key from `sender_keys`, if we know what their keys are
+pub async fn decrypt_bmail(
+    record: &BmailMessageRecord,
+    identity: &BmailIdentity,
+    ratchet: &SharedRatchet,
+    sender_keys: Option<&str>,
+) -> Result<DecryptedMessage, BmailError> {
+    if record.bmail_ratchet.is_empty() {
+        return record.into_decrypted_message(identity).await;
+    }
+    let id = message_id(
+        &record.bmail_conversation_id,
+        &record.bmail_creator,
+        &record.bmail_created_at,
+    );
+    let mut ratchet = ratchet.lock().await;
+    if let Some(plaintext) = ratchet.recall(&id) {
+        return record.with_plaintext(plaintext.clone());
+    }
+    // Only age X25519 keys can have ratchet sessions, so anything else wasn't sent one
+    let identity = identity
+        .x25519()
+        .ok_or(BmailError::MissingRecipientIdentity)?;
+    let envelope = record
+        .bmail_ratchet
+        .get(&identity.to_public().to_string())
+        .ok_or(BmailError::MissingRecipientIdentity)?;
+    let decrypted = ratchet.decrypt(identity, envelope)?;
+    // Checked before the session is kept, so someone else's device can't move our sessions along
+    if matches!(sender_keys, Some(keys) if !keys.split(',').any(|k| k == decrypted.sender)) {
+        return Err(BmailError::KeyChanged(record.bmail_creator.clone()));
+    }
+    let plaintext = ratchet.keep(decrypted);
+    let plaintext =
+        Zeroizing::new(String::from_utf8(plaintext).map_err(BmailError::FromStringError)?);
+    // The keys are gone now, so the plaintext is kept even if it turns out to be malformed
+    let message = record.with_plaintext(plaintext.clone());
+    let expires_at = message.as_ref().ok().and_then(|m| m.expires_at());
+    ratchet.remember(id, plaintext, expires_at);
+    message
+}
+
+/// Get the raw X25519 secret from an age identity
+fn identity_bytes(identity: &Identity) -> Result<[u8; 32], BmailError> {
+    bech32_bytes(identity.to_string().expose_secret())
+}
+
+/// Get the raw X25519 public key from an age recipient string
+fn recipient_bytes(key: &str) -> Result<[u8; 32], BmailError> {
+    bech32_bytes(key)
+}
+
+fn bech32_bytes(encoded: &str) -> Result<[u8; 32], BmailError> {
+    let (_hrp, data, _variant) =
+        bech32::decode(encoded).map_err(|_| BmailError::ParseRecipientError)?;
+    let bytes = Vec::<u8>::from_base32(&data).map_err(|_| BmailError::ParseRecipientError)?;
+    bytes
+        .try_into()
+        .map_err(|_| BmailError::ParseRecipientError)
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; 32], BmailError> {
+    let bytes = general_purpose::STANDARD_NO_PAD.decode(encoded)?;
+    bytes
+        .try_into()
+        .map_err(|_| BmailError::ParseRecipientError)
+}
+
+fn generate_keypair() -> ([u8; 32], [u8; 32]) {
+    let mut secret = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    let public = public_key(&secret);
+    (secret, public)
+}
+
+fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    x25519(*secret, X25519_BASEPOINT_BYTES)
+}
+
+/// Diffie-Hellman, refusing the all zero result a malicious low order key would give
+fn dh(secret: &[u8; 32], public: &[u8; 32]) -> Result<[u8; 32], BmailError> {
+    let shared = x25519(*secret, *public);
+    match shared == [0u8; 32] {
+        true => Err(BmailError::RatchetError("Invalid public key".to_string())),
+        false => Ok(shared),
+    }
+}
+
+/// Turn the three X3DH Diffie-Hellmans into the first root key
+fn agree(pairs: &[(&[u8; 32], &[u8; 32])]) -> Result<[u8; 32], BmailError> {
+    let mut input = vec![0xFFu8; 32];
+    for (secret, public) in pairs {
+        input.extend_from_slice(&dh(secret, public)?);
+    }
+    let mut root_key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &input)
+        .expand(b"bmail x3dh", &mut root_key)
+        .expect("32 bytes is a valid length");
+    Ok(root_key)
+}
+
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), dh_out)
+        .expand(b"bmail ratchet", &mut okm)
+        .expect("64 bytes is a valid length");
+    let (root, chain) = okm.split_at(32);
+    (root.try_into().unwrap(), chain.try_into().unwrap())
+}
+
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |byte: u8| -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(chain_key).expect("HMAC takes any key length");
+        mac.update(&[byte]);
+        mac.finalize().into_bytes().into()
+    };
+    (step(0x02), step(0x01))
+}
+
+fn skipped_id(dh: &[u8; 32], n: u32) -> String {
+    format!("{}:{}", general_purpose::STANDARD_NO_PAD.encode(dh), n)
+}
+
+/// Encrypt with a message key. The cipher key and nonce both come from it, and it's only ever used once
+fn seal(
+    message_key: &[u8; 32],
+    plaintext: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, BmailError> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .encrypt(
+            &nonce.into(),
+            Payload {
+                msg: plaintext,
+                aad: associated_data,
+            },
+        )
+        .map_err(|_| BmailError::RatchetError("Encryption failed".to_string()))
+}
+
+fn open(
+    message_key: &[u8; 32],
+    ciphertext: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, BmailError> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .decrypt(
+            &nonce.into(),
+            Payload {
+                msg: ciphertext,
+                aad: associated_data,
+            },
+        )
+        .map_err(|_| BmailError::RatchetError("Couldn't decrypt the Bmail".to_string()))
+}
+
+fn message_cipher(message_key: &[u8; 32]) -> (ChaCha20Poly1305, [u8; 12]) {
+    let mut okm = [0u8; 44];
+    Hkdf::<Sha256>::new(None, message_key)
+        .expand(b"bmail message", &mut okm)
+        .expect("44 bytes is a valid length");
+    let cipher = ChaCha20Poly1305::new_from_slice(&okm[..32]).expect("32 byte key");
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&okm[32..]);
+    (cipher, nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliver(
+        store: &mut RatchetStore,
+        identity: &Identity,
+        envelope: &str,
+    ) -> Result<Vec<u8>, BmailError> {
+        let decrypted = store.decrypt(identity, envelope)?;
+        Ok(store.keep(decrypted))
+    }
+
+    /// Alice's and Bob's devices and ratchets, with Bob's prekey published
+    fn pair() -> (Identity, RatchetStore, Identity, RatchetStore, SignedPrekey) {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let mut bob_store = RatchetStore::default();
+        let prekey = bob_store.current_prekey(&bob).unwrap();
+        (alice, RatchetStore::default(), bob, bob_store, prekey)
+    }
+
+    fn send(
+        store: &mut RatchetStore,
+        from: &Identity,
+        to: &Identity,
+        prekey: &SignedPrekey,
+        plaintext: &str,
+    ) -> String {
+        let device = to.to_public().to_string();
+        store
+            .encrypt(from, &device, Some(prekey), plaintext.as_bytes())
+            .unwrap()
+    }
+
+    fn age_prekey(store: &mut RatchetStore) {
+        store.data.prekeys[0].published.created_at -= Duration::days(PREKEY_LIFETIME_DAYS + 1);
+    }
+
+    #[test]
+    fn prekey_signature_checks() {
+        let (alice, _, bob, _, prekey) = pair();
+        let bob_device = bob.to_public().to_string();
+        assert!(prekey.verify(&bob_device).is_some());
+        assert!(prekey.verify(&alice.to_public().to_string()).is_none());
+
+        let mut tampered = prekey.clone();
+        tampered.key = RatchetStore::default().current_prekey(&alice).unwrap().key;
+        assert!(tampered.verify(&bob_device).is_none());
+
+        let mut resigned = prekey;
+        resigned.signature = RatchetStore::default()
+            .current_prekey(&alice)
+            .unwrap()
+            .signature;
+        assert!(resigned.verify(&bob_device).is_none());
+    }
+
+    #[test]
+    fn in_order() {
+        let (alice, mut alice_store, bob, mut bob_store, prekey) = pair();
+        let alice_prekey = alice_store.current_prekey(&alice).unwrap();
+        for i in 0..3 {
+            let text = format!("to bob {}", i);
+            let envelope = send(&mut alice_store, &alice, &bob, &prekey, &text);
+            assert_eq!(
+                deliver(&mut bob_store, &bob, &envelope).unwrap(),
+                text.as_bytes()
+            );
+        }
+        // Bob replies over the session Alice started, then they keep going back and forth
+        for i in 0..3 {
+            let text = format!("to alice {}", i);
+            let envelope = send(&mut bob_store, &bob, &alice, &alice_prekey, &text);
+            assert_eq!(
+                deliver(&mut alice_store, &alice, &envelope).unwrap(),
+                text.as_bytes()
+            );
+            let text = format!("to bob {}", i);
+            let envelope = send(&mut alice_store, &alice, &bob, &prekey, &text);
+            assert_eq!(
+                deliver(&mut bob_store, &bob, &envelope).unwrap(),
+                text.as_bytes()
+            );
+        }
+        // Alice heard back, so Bob doesn't need telling how to start the session any more
+        assert!(alice_store.data.sessions[&bob.to_public().to_string()]
+            .init
+            .is_none());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (alice, mut alice_store, bob, mut bob_store, prekey) = pair();
+        let envelopes: Vec<String> = (0..5)
+            .map(|i| send(&mut alice_store, &alice, &bob, &prekey, &i.to_string()))
+            .collect();
+        for i in [3, 0, 4, 1, 2] {
+            let plaintext = deliver(&mut bob_store, &bob, &envelopes[i]).unwrap();
+            assert_eq!(plaintext, i.to_string().as_bytes());
+        }
+        let session = &bob_store.data.sessions[&alice.to_public().to_string()];
+        assert!(session.skipped.is_empty());
+        // Each key is only good once
+        assert!(deliver(&mut bob_store, &bob, &envelopes[2]).is_err());
+    }
+
+    #[test]
+    fn too_many_skipped_fails() {
+        let (alice, mut alice_store, bob, mut bob_store, prekey) = pair();
+        let first = send(&mut alice_store, &alice, &bob, &prekey, "first");
+        deliver(&mut bob_store, &bob, &first).unwrap();
+        let later: Vec<String> = (0..MAX_SKIP + 2)
+            .map(|_| send(&mut alice_store, &alice, &bob, &prekey, "later"))
+            .collect();
+        assert!(deliver(&mut bob_store, &bob, &later[MAX_SKIP as usize + 1]).is_err());
+        assert!(deliver(&mut bob_store, &bob, &later[MAX_SKIP as usize - 1]).is_ok());
+    }
+
+    #[test]
+    fn bad_ciphertext_leaves_session() {
+        let (alice, mut alice_store, bob, mut bob_store, prekey) = pair();
+        let first = send(&mut alice_store, &alice, &bob, &prekey, "first");
+        deliver(&mut bob_store, &bob, &first).unwrap();
+        let second = send(&mut alice_store, &alice, &bob, &prekey, "second");
+
+        let decoded = general_purpose::STANDARD_NO_PAD.decode(&second).unwrap();
+        let mut envelope: Envelope = ciborium::de::from_reader(decoded.as_slice()).unwrap();
+        envelope.ciphertext[0] ^= 1;
+        let mut buffer = Vec::new();
+        ciborium::ser::into_writer(&envelope, &mut buffer).unwrap();
+        let tampered = general_purpose::STANDARD_NO_PAD.encode(buffer);
+
+        assert!(deliver(&mut bob_store, &bob, &tampered).is_err());
+        assert_eq!(deliver(&mut bob_store, &bob, &second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn replayed_start_is_rejected() {
+        let (alice, mut alice_store, bob, mut bob_store, prekey) = pair();
+        let alice_prekey = alice_store.current_prekey(&alice).unwrap();
+        let first = send(&mut alice_store, &alice, &bob, &prekey, "first");
+        deliver(&mut bob_store, &bob, &first).unwrap();
+        let reply = send(&mut bob_store, &bob, &alice, &alice_prekey, "reply");
+        deliver(&mut alice_store, &alice, &reply).unwrap();
+        let second = send(&mut alice_store, &alice, &bob, &prekey, "second");
+
+        assert!(deliver(&mut bob_store, &bob, &first).is_err());
+        // The session wasn't rewound, so it carries on
+        assert_eq!(deliver(&mut bob_store, &bob, &second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn old_prekeys_are_kept_for_a_while() {
+        let (alice, mut alice_store, bob, mut bob_store, prekey) = pair();
+        for _ in 0..OLD_PREKEYS {
+            age_prekey(&mut bob_store);
+            assert_ne!(bob_store.current_prekey(&bob).unwrap(), prekey);
+        }
+        let envelope = send(&mut alice_store, &alice, &bob, &prekey, "still works");
+        assert_eq!(
+            deliver(&mut bob_store, &bob, &envelope).unwrap(),
+            b"still works"
+        );
+
+        let carol = Identity::generate();
+        let mut carol_store = RatchetStore::default();
+        age_prekey(&mut bob_store);
+        bob_store.current_prekey(&bob).unwrap();
+        assert_eq!(bob_store.data.prekeys.len(), OLD_PREKEYS + 1);
+        let envelope = send(&mut carol_store, &carol, &bob, &prekey, "too late");
+        assert!(deliver(&mut bob_store, &bob, &envelope).is_err());
+        // Starts with prekeys that are gone can't be replayed anyway, so they're forgotten too
+        assert!(bob_store.data.used_inits.is_empty());
+    }
+
+    #[test]
+    fn fresh_prekey_is_reused() {
+        let (_, _, bob, mut bob_store, prekey) = pair();
+        assert_eq!(bob_store.current_prekey(&bob).unwrap(), prekey);
+    }
+}
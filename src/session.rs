@@ -0,0 +1,1058 @@
+use age::Recipient as RecipientTrait;
+use bisky::lexicon::{
+    app::bsky::feed::Post,
+    com::atproto::repo::{Record, StrongRef},
+};
+use chrono::{TimeZone, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    autocomplete::{Candidate, MAX_SUGGESTIONS},
+    conf::{NetworkSettings, Settings},
+    errors::BmailError,
+    key::{
+        decode, decrypt_and_decode, encode, encrypt_and_encode, fingerprint, parse_recipients,
+        BmailIdentity,
+    },
+    message::{
+        BmailDevice, BmailEnabledProfile, BmailLike, Conversation, DecryptedMessage, GroupChange,
+        GroupUpdate, PAYLOAD_VERSION,
+    },
+    ratchet::{SharedRatchet, SignedPrekey},
+    store::{Group, SyncedSettings},
+    SharableBluesky,
+};
+
+/// Everything needed to talk to Bluesky on behalf of the user. It's cheap to clone, so network
+/// operations can be moved into background tasks without holding on to the App
+#[derive(Clone)]
+pub struct Session {
+    /// Bluesky object for API Calls
+    pub bluesky: SharableBluesky,
+    /// Identity for Decrypting DMs
+    pub identity: BmailIdentity,
+    /// App Settings
+    pub conf: Settings,
+    /// The DID of the current user
+    pub user_did: Option<String>,
+    /// Forward secret sessions with other devices
+    pub ratchet: SharedRatchet,
+    /// For lookups that don't need to be logged in
+    pub appview: AppView,
+}
+
+#[derive(Deserialize)]
+struct FollowsPage {
+    follows: Vec<Candidate>,
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TypeaheadResults {
+    actors: Vec<Candidate>,
+}
+
+#[derive(Deserialize)]
+struct ResolvedHandle {
+    did: String,
+}
+
+/// The body of a failed XRPC call
+#[derive(Deserialize)]
+struct XrpcError {
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct FetchedRecord {
+    cid: Option<String>,
+}
+
+/// Bluesky's AppView, for read only lookups that don't need to be logged in. It's cheap to clone, and clones
+/// share their connections
+#[derive(Clone)]
+pub struct AppView {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl AppView {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Start a call to an XRPC method, like app.bsky.graph.getFollows
+    fn get(&self, method: &str) -> reqwest::RequestBuilder {
+        self.client.get(format!("{}/xrpc/{}", self.url, method))
+    }
+
+    /// Look up the DID for a handle. DIDs are passed straight through
+    pub async fn resolve_did(&self, handle: &str) -> Result<String, BmailError> {
+        let handle = handle.trim().trim_start_matches('@');
+        if handle.starts_with("did:") {
+            return Ok(handle.to_string());
+        }
+        let resolved: ResolvedHandle = self
+            .get("com.atproto.identity.resolveHandle")
+            .query(&[("handle", handle)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resolved.did)
+    }
+}
+
+impl Default for AppView {
+    fn default() -> Self {
+        Self::new(&NetworkSettings::default().appview_url)
+    }
+}
+
+/// A Conversation loaded in the background, ready to be merged into the App
+pub struct LoadedConversation {
+    pub conversation: Conversation,
+    /// Handles resolved while loading, keyed by DID
+    pub handles: HashMap<String, String>,
+    /// What each participant has published for a Bmail key, keyed by DID
+    pub keys: HashMap<String, ParticipantKey>,
+    /// Participants whose Bmails were left out because they came from a key that isn't pinned
+    pub key_changed: Vec<String>,
+}
+
+/// A Conversation's messages fetched from its participants
+pub struct FetchedConversation {
+    pub conversation: Conversation,
+    /// Participants whose Bmails were left out because they came from a key that isn't pinned
+    pub key_changed: Vec<String>,
+}
+
+/// What we found on a participant's profile about their Bmail key
+#[derive(Clone, Debug)]
+pub enum ParticipantKey {
+    /// Their public key, which Bmails to them are encrypted to
+    Published(String),
+    /// They haven't set up Bmail, so they can't be sent Bmails
+    Missing,
+    /// Their profile couldn't be read, so we don't know
+    Unavailable(String),
+}
+
+impl ParticipantKey {
+    /// Whether we can't send to this participant
+    pub fn is_missing(&self) -> bool {
+        matches!(self, ParticipantKey::Missing)
+    }
+}
+
+/// A Bmail sent in the background, ready to be added to its Conversation
+pub struct SentBmail {
+    pub message: DecryptedMessage,
+    /// Handles resolved while sending, keyed by DID
+    pub handles: HashMap<String, String>,
+    /// The Bmail is sent even if notifying the recipients fails, so that's reported separately
+    pub notified: Result<(), BmailError>,
+    /// The keys it was encrypted to, keyed by DID
+    pub keys: HashMap<String, String>,
+    /// Whether it was sent with the ratchet instead of age
+    pub forward_secret: bool,
+    /// Where the record was stored, so it can be deleted when it disappears
+    pub uri: String,
+}
+
+/// A change to make to a group, from the UI. Members are given as handles
+#[derive(Clone, Debug)]
+pub enum GroupRequest {
+    Create { name: String, members: Vec<String> },
+    Rename(String),
+    Add(Vec<String>),
+    Remove(Vec<String>),
+    Leave,
+}
+
+impl Session {
+    /// Load a conversation. If there is a conversation with the recipients in memory, use it. If there isn't one,
+    /// check the profile self storage for a conversation that matches. If that fails, create a new conversation and upload it
+    /// to the profile storage. This takes handles from the UI, so they'll be parsed into DIDs
+    pub async fn load_conversation(
+        &self,
+        mut recipients: Vec<String>,
+        known_conversations: HashMap<Vec<String>, Uuid>,
+        sender_keys: HashMap<String, String>,
+    ) -> Result<LoadedConversation, BmailError> {
+        // 0. Add myself to the participants
+        recipients.push(self.conf.user.handle.clone());
+
+        // 1. Get DIDs for recipients
+        let (mut participant_dids, handles) = self.resolve_participant_dids(&recipients).await?;
+        participant_dids.dedup();
+
+        // 2. Check if all DIDs are present in Conversation Storage as a key
+        // We need the Vec as the HashMap Key to be sorted
+        let conversation_id = match known_conversations.get(&participant_dids) {
+            Some(c_id) => *c_id,
+            None => {
+                // 3. Check if present in profile storage, then in the participant profiles in case
+                // they made it with us. Else create a new one
+                let c_id = if let Ok(Some(c_id)) = self
+                    .get_cid_from_rc_map_from_profile(participant_dids.clone())
+                    .await
+                {
+                    c_id
+                } else if let Ok(Some(c_id)) = self
+                    .get_cid_from_rc_map_from_participants_profiles(participant_dids.clone())
+                    .await
+                {
+                    c_id
+                } else {
+                    Uuid::new_v4()
+                };
+                self.upload_rc_map_to_profile(participant_dids.clone(), c_id)
+                    .await?;
+                c_id
+            }
+        };
+
+        // 4. Check who can receive Bmails, before anyone writes a message they can't send
+        let keys = self.check_participant_keys(&participant_dids).await;
+
+        // 5. Get all Conversation Records with that Conversation ID from each participant
+        let fetched = self
+            .fetch_conversation(conversation_id, participant_dids, &sender_keys)
+            .await?;
+
+        Ok(LoadedConversation {
+            conversation: fetched.conversation,
+            handles,
+            keys,
+            key_changed: fetched.key_changed,
+        })
+    }
+
+    /// Look up the Bmail key each participant has published on their profile
+    pub async fn check_participant_keys(
+        &self,
+        participant_dids: &[String],
+    ) -> HashMap<String, ParticipantKey> {
+        let mut keys = HashMap::new();
+        for did in participant_dids {
+            let key = match self.get_bmail_profile(did).await {
+                Ok(profile) => match profile.value.key_set() {
+                    Some(k) => ParticipantKey::Published(k),
+                    None => ParticipantKey::Missing,
+                },
+                Err(e) => ParticipantKey::Unavailable(e.to_string()),
+            };
+            keys.insert(did.clone(), key);
+        }
+        keys
+    }
+
+    /// Fetch every message in a Conversation from its participants. Bmails have to come from the sender's
+    /// keys in `sender_keys`, if they're in it
+    pub async fn fetch_conversation(
+        &self,
+        conversation_id: Uuid,
+        participant_dids: Vec<String>,
+        sender_keys: &HashMap<String, String>,
+    ) -> Result<FetchedConversation, BmailError> {
+        let mut conversation = Conversation {
+            conversation_id,
+            participants: participant_dids.clone(),
+            ..Default::default()
+        };
+        let key_changed = conversation
+            .update_with_messages_from_participants(
+                self.bluesky.clone(),
+                &self.conf.user.handle,
+                &self.identity,
+                &self.ratchet,
+                participant_dids,
+                sender_keys,
+            )
+            .await?;
+        Ok(FetchedConversation {
+            conversation,
+            key_changed,
+        })
+    }
+
+    /// Get the sorted DIDs for a list of participants, and the handles we resolved along the way.
+    /// These can be handles from the UI, or DIDs from the Conversation sidebar, which are used as is
+    pub async fn resolve_participant_dids(
+        &self,
+        participants: &[String],
+    ) -> Result<(Vec<String>, HashMap<String, String>), BmailError> {
+        let mut dids: Vec<String> = Vec::with_capacity(participants.len());
+        let mut handles: HashMap<String, String> = HashMap::new();
+        let mut bsky = self.bluesky.0.write().await;
+        let mut user = bsky.user(&self.conf.user.handle)?;
+        for participant in participants {
+            let participant = participant.trim();
+            if participant.starts_with("did:") {
+                dids.push(participant.to_string());
+                continue;
+            }
+            let participant_did = user.resolve_handle(participant).await?;
+            handles.insert(participant_did.clone(), participant.to_string());
+            dids.push(participant_did);
+        }
+        dids.sort();
+        Ok((dids, handles))
+    }
+
+    /// Look up the handles for some DIDs. If we can't find one, it's left out
+    pub async fn resolve_handles(&self, dids: &[String]) -> HashMap<String, String> {
+        let mut handles = HashMap::new();
+        let mut bsky = self.bluesky.0.write().await;
+        for did in dids {
+            let Ok(mut user) = bsky.user(did) else {
+                continue;
+            };
+            if let Ok(profile) = user.get_profile().await {
+                handles.insert(did.clone(), profile.handle);
+            }
+        }
+        handles
+    }
+
+    /// Load a group from everyone who's been in it, since Bmails from people who have left are still part of it
+    pub async fn load_group(
+        &self,
+        conversation_id: Uuid,
+        group: Group,
+        sender_keys: HashMap<String, String>,
+    ) -> Result<LoadedConversation, BmailError> {
+        let everyone = group.everyone();
+        let keys = self.check_participant_keys(&group.members).await;
+        let handles = self.resolve_handles(&everyone).await;
+        let mut fetched = self
+            .fetch_conversation(conversation_id, everyone, &sender_keys)
+            .await?;
+        fetched.conversation.participants = group.members;
+        Ok(LoadedConversation {
+            conversation: fetched.conversation,
+            handles,
+            keys,
+            key_changed: fetched.key_changed,
+        })
+    }
+
+    /// Make a change to a group and tell everyone in it, including anyone just removed, with an encrypted
+    /// control message. A new group gets a new Conversation ID, which stays the same whoever comes and goes
+    pub async fn update_group(
+        &self,
+        current: Option<(Uuid, Group)>,
+        request: GroupRequest,
+        pinned_keys: HashMap<String, String>,
+        disappear_after: Option<u64>,
+    ) -> Result<SentBmail, BmailError> {
+        let Some(user_did) = self.user_did.clone() else {
+            return Err(BmailError::InternalServerError);
+        };
+        let group_error = |e: &str| Err(BmailError::GroupError(e.to_string()));
+        let mut handles = HashMap::new();
+        let (conversation_id, old_members, update) = match (current, request) {
+            (None, GroupRequest::Create { name, members }) => {
+                let (mut members, resolved) = self.resolve_participant_dids(&members).await?;
+                handles = resolved;
+                members.push(user_did.clone());
+                members.sort();
+                members.dedup();
+                if name.trim().is_empty() || members.len() < 2 {
+                    return group_error("A group needs a name and someone else in it");
+                }
+                let update = GroupUpdate {
+                    name: name.trim().to_string(),
+                    members,
+                    change: GroupChange::Created,
+                };
+                (Uuid::new_v4(), Vec::new(), update)
+            }
+            (None, _) => return group_error("This Conversation isn't a group"),
+            (Some((_, group)), _) if !group.members.contains(&user_did) => {
+                return group_error("You're not in this group")
+            }
+            (Some((c_id, group)), request) => {
+                let mut members = group.members.clone();
+                let mut name = group.name.clone();
+                let change = match request {
+                    GroupRequest::Create { .. } => {
+                        return group_error("This Conversation is already a group")
+                    }
+                    GroupRequest::Rename(new_name) => {
+                        if new_name.trim().is_empty() {
+                            return group_error("A group needs a name");
+                        }
+                        name = new_name.trim().to_string();
+                        GroupChange::Renamed
+                    }
+                    GroupRequest::Add(added) => {
+                        let (added, resolved) = self.resolve_participant_dids(&added).await?;
+                        handles = resolved;
+                        let added: Vec<String> =
+                            added.into_iter().filter(|d| !members.contains(d)).collect();
+                        if added.is_empty() {
+                            return group_error("They're already in the group");
+                        }
+                        members.extend(added.iter().cloned());
+                        GroupChange::Added(added)
+                    }
+                    GroupRequest::Remove(removed) => {
+                        let (removed, resolved) = self.resolve_participant_dids(&removed).await?;
+                        handles = resolved;
+                        if removed.contains(&user_did) {
+                            return group_error("Leave the group to remove yourself");
+                        }
+                        let removed: Vec<String> =
+                            removed.into_iter().filter(|d| members.contains(d)).collect();
+                        if removed.is_empty() {
+                            return group_error("They're not in the group");
+                        }
+                        members.retain(|m| !removed.contains(m));
+                        GroupChange::Removed(removed)
+                    }
+                    GroupRequest::Leave => {
+                        members.retain(|m| m != &user_did);
+                        GroupChange::Left
+                    }
+                };
+                members.sort();
+                members.dedup();
+                let update = GroupUpdate {
+                    name,
+                    members,
+                    change,
+                };
+                (c_id, group.members, update)
+            }
+        };
+        // Everyone who was in it or is now, so the removed find out too
+        let mut recipients: Vec<String> = old_members
+            .into_iter()
+            .chain(update.members.iter().cloned())
+            .filter(|m| m != &user_did)
+            .collect();
+        recipients.sort();
+        recipients.dedup();
+        let mut sent = self
+            .send_bmail(
+                conversation_id,
+                recipients,
+                "",
+                pinned_keys,
+                disappear_after,
+                Some(update),
+            )
+            .await?;
+        sent.handles.extend(handles);
+        Ok(sent)
+    }
+
+    /// Send a Bmail by adding your message to your ConversationPortion in your profile Record. A disappearing
+    /// Bmail carries its timer in the encrypted payload, which older clients can't read, as do group changes
+    pub async fn send_bmail(
+        &self,
+        conversation_id: Uuid,
+        recipients: Vec<String>,
+        msg: &str,
+        pinned_keys: HashMap<String, String>,
+        disappear_after: Option<u64>,
+        group: Option<GroupUpdate>,
+    ) -> Result<SentBmail, BmailError> {
+        let Some(user_did) = self.user_did.clone() else {
+            return Err(BmailError::InternalServerError);
+        };
+
+        // 0. Get DIDS for participants
+        let (participant_dids, handles) = self.resolve_participant_dids(&recipients).await?;
+        // Everyone in the Conversation, including us, for the notification
+        let mut conversation_participants = participant_dids.clone();
+        conversation_participants.push(user_did.clone());
+        conversation_participants.sort();
+        conversation_participants.dedup();
+        // Create Message
+        let mut msg = DecryptedMessage {
+            created_at: Utc::now(),
+            creator: user_did,
+            message: Zeroizing::new(msg.to_string()),
+            conversation_id,
+            recipients: participant_dids,
+            recipient_keys: HashMap::new(),
+            version: match disappear_after.is_some() || group.is_some() {
+                true => PAYLOAD_VERSION,
+                false => 0,
+            },
+            creator_handle: self.conf.user.handle.clone(),
+            disappear_after,
+            group,
+        };
+        // Forward secrecy needs an age X25519 key to sign prekeys and run the ratchet with
+        let ratchet = match (self.conf.forward_secrecy.enabled, self.identity.x25519()) {
+            (true, Some(identity)) => Some((&self.ratchet, identity)),
+            _ => None,
+        };
+        let allowed_plugins = self.identity.allowed_plugins(&self.conf.key);
+        let (record, keys, forward_secret) = msg
+            .into_bmail_record(
+                self.bluesky.clone(),
+                &pinned_keys,
+                &allowed_plugins,
+                ratchet,
+            )
+            .await?;
+        msg.recipient_keys = keys.clone();
+        // Send Bmail by creating a profile post with the contents
+        let created = {
+            let mut bsky = self.bluesky.0.write().await;
+            let mut me = bsky.me().map_err::<BmailError, _>(Into::into)?;
+            me.create_record("app.bsky.actor.profile", None, None, None, record)
+                .await?
+        };
+        // Notify recipients that we have sent them a Bmail
+        let notified = self
+            .notify_recipients(conversation_id, conversation_participants)
+            .await;
+
+        Ok(SentBmail {
+            message: msg,
+            handles,
+            notified,
+            keys,
+            forward_secret,
+            uri: created.uri,
+        })
+    }
+
+    /// Merge our Conversation settings with the ones on our profile, which are encrypted to all our devices.
+    /// They're put back if anything changed, or if we've added a device they aren't encrypted to yet.
+    /// Returns the merged settings
+    pub async fn sync_settings(&self, local: SyncedSettings) -> Result<SyncedSettings, BmailError> {
+        let mut profile_record = self.get_bmail_profile(&self.conf.user.handle).await?;
+        let keys = profile_record.value.key_set();
+        let mut settings = match &profile_record.value.bmail_settings {
+            Some(encrypted) => match decrypt_and_decode(&self.identity, encrypted).await {
+                Ok(settings) => settings,
+                // Putting ours back would wipe out everything our other devices have set
+                Err(BmailError::DecryptError(age::DecryptError::NoMatchingKeys)) => {
+                    return Err(BmailError::SettingsError(
+                        "they haven't been shared with this device yet. Open Bmail on one of your \
+                         other devices to share them"
+                            .to_string(),
+                    ))
+                }
+                Err(e) => return Err(e),
+            },
+            None => SyncedSettings::default(),
+        };
+        let changed = settings.merge(&local);
+        let missing_devices = profile_record.value.bmail_settings.is_some()
+            && profile_record.value.bmail_settings_keys != keys;
+        if !changed && !missing_devices {
+            return Ok(settings);
+        }
+        let allowed_plugins = self.identity.allowed_plugins(&self.conf.key);
+        let recipients = parse_recipients(&profile_record.value.active_keys(), &allowed_plugins)?;
+        let encrypted = encrypt_and_encode(recipients, &settings).await?;
+        profile_record.value.bmail_settings = Some(encrypted);
+        profile_record.value.bmail_settings_keys = keys;
+        self.put_profile(&profile_record).await?;
+        Ok(settings)
+    }
+
+    /// Delete one of our Bmail records, once it has disappeared. Anyone who already fetched it keeps their copy
+    pub async fn delete_bmail_record(&self, uri: &str) -> Result<(), BmailError> {
+        // at://did/app.bsky.actor.profile/rkey
+        let Some(rkey) = uri.rsplit('/').next() else {
+            return Err(BmailError::MalformedBmail);
+        };
+        let mut bsky = self.bluesky.0.write().await;
+        let mut me = bsky.me()?;
+        me.delete_record("app.bsky.actor.profile", rkey, None, None)
+            .await?;
+        Ok(())
+    }
+
+    /// We're storing a HashMap of recipients in a Conversation to conversation IDs in the profile(encrypted)
+    /// so that multiple clients can fetch them, and so that we can recover them when the app is restarted.
+    /// Get that Hashmap
+    pub async fn get_rc_map_from_profile(
+        &self,
+        handle: &str,
+    ) -> Result<Option<HashMap<Vec<String>, Uuid>>, BmailError> {
+        let mut bsky = self.bluesky.0.write().await;
+        let mut user = bsky.user(&self.conf.user.handle)?;
+        let profile_record = user
+            .get_record::<BmailEnabledProfile>(handle, "app.bsky.actor.profile", "self")
+            .await?;
+
+        if let Some(r_map) = profile_record.value.bmail_rc_map {
+            let decoded = decode(&r_map).await?;
+            Ok(Some(decoded))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// We're storing a HashMap of recipients in a Conversation to conversation IDs in the profile(encrypted)
+    /// so that multiple clients can fetch them, and so that we can recover them when the app is restarted.
+    /// Get that Hashmap
+    pub async fn get_cid_from_rc_map_from_profile(
+        &self,
+        participants: Vec<String>,
+    ) -> Result<Option<Uuid>, BmailError> {
+        let handle = self.conf.user.handle.clone();
+        let profile_rc_map = self.get_rc_map_from_profile(&handle).await?;
+        if let Some(rc_map) = &profile_rc_map {
+            let mut conversation_id = None;
+            for key in rc_map.keys() {
+                match key == &participants {
+                    // match key.iter().all(|item| participants.contains(item)) && participants.len() == keys.len() {
+                    true => {
+                        let c_id = rc_map.get(key).unwrap();
+                        //println!("K P: {:?}||{:?}||{:?}", key, participants, c_id);
+                        conversation_id = Some(*c_id);
+                        break;
+                    }
+                    false => continue,
+                };
+            }
+            Ok(conversation_id)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Check for a conversation ID in each participants profiles, so that if they make a conversation with me, and I try to make one later,
+    /// it will find their ID and use it for my local conversation
+    pub async fn get_cid_from_rc_map_from_participants_profiles(
+        &self,
+        participants: Vec<String>,
+    ) -> Result<Option<Uuid>, BmailError> {
+        for participant in participants.iter() {
+            if let Some(user_did) = &self.user_did {
+                // Skip if it's me
+                if participant == user_did {
+                    continue;
+                }
+            }
+
+            let profile_rc_map = self.get_rc_map_from_profile(participant).await?;
+            //println!("profile_rc_map: {:?}", profile_rc_map);
+            if let Some(rc_map) = &profile_rc_map {
+                for key in rc_map.keys() {
+                    // Need to check length because we might have one vector contain all of another
+                    match key == &participants {
+                        true => {
+                            let c_id = rc_map.get(key).unwrap();
+                            //println!("FOUND KEYS: {:#?}", keys);
+                            return Ok(Some(*c_id));
+                        }
+                        false => continue,
+                    };
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the current rc_map from profile and add a new value to it
+    pub async fn upload_rc_map_to_profile(
+        &self,
+        key: Vec<String>,
+        value: Uuid,
+    ) -> Result<(), BmailError> {
+        let handle = self.conf.user.handle.clone();
+
+        let mut profile_record = {
+            let mut bsky = self.bluesky.0.write().await;
+            let mut user = bsky.user(&handle)?;
+            user.get_record::<BmailEnabledProfile>(&handle, "app.bsky.actor.profile", "self")
+                .await?
+        };
+
+        match &mut profile_record.value.bmail_rc_map {
+            Some(m) => {
+                let mut rc_map: HashMap<Vec<String>, Uuid> = decode(m).await?;
+                rc_map.insert(key, value);
+                let encoded = encode(rc_map).await?;
+                profile_record.value.bmail_rc_map = Some(encoded);
+            }
+            None => {
+                let mut new_rc_map = HashMap::new();
+                new_rc_map.insert(key, value);
+                let encoded_map = encode(new_rc_map).await?;
+                profile_record.value.bmail_rc_map = Some(encoded_map);
+            }
+        };
+
+        let mut bsky = self.bluesky.0.write().await;
+        let mut me = bsky.me()?;
+        me.put_record(
+            "app.bsky.actor.profile",
+            "self",
+            None,
+            None,
+            Some(&profile_record.cid),
+            &profile_record.value,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the current rc_map from profile and add a new value to it
+    pub async fn delete_rc_map_from_profile(&self) -> Result<(), BmailError> {
+        let handle = self.conf.user.handle.clone();
+
+        let mut profile_record = {
+            let mut bsky = self.bluesky.0.write().await;
+            let mut user = bsky.user(&handle)?;
+            user.get_record::<BmailEnabledProfile>(&handle, "app.bsky.actor.profile", "self")
+                .await?
+        };
+
+        profile_record.value.bmail_rc_map = None;
+
+        let mut bsky = self.bluesky.0.write().await;
+        let mut me = bsky.me()?;
+        me.put_record(
+            "app.bsky.actor.profile",
+            "self",
+            None,
+            None,
+            Some(&profile_record.cid),
+            &profile_record.value,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Scrape the recipient's Profile for the Public Keys of their devices so we can encrypt this thing
+    pub async fn get_recipient_for_bskyer(
+        &self,
+        handle: &str,
+    ) -> Result<(Vec<Box<dyn RecipientTrait + Send>>, Record<BmailEnabledProfile>), BmailError> {
+        let profile_record = self.get_bmail_profile(handle).await?;
+        let allowed_plugins = self.identity.allowed_plugins(&self.conf.key);
+        let recipients = parse_recipients(&profile_record.value.active_keys(), &allowed_plugins)?;
+        Ok((recipients, profile_record))
+    }
+
+    /// Get someone's profile record with their Bmail keys on it. Unlike [Session::get_recipient_for_bskyer]
+    /// the keys aren't parsed, so plugin keys work without the plugin installed
+    pub async fn get_bmail_profile(
+        &self,
+        handle: &str,
+    ) -> Result<Record<BmailEnabledProfile>, BmailError> {
+        let mut bsky = self.bluesky.0.write().await;
+        let mut user = bsky.user(&self.conf.user.handle)?;
+
+        Ok(user
+            .get_record::<BmailEnabledProfile>(handle, "app.bsky.actor.profile", "self")
+            .await?)
+    }
+
+    /// Get everyone the user follows, for suggesting recipients
+    pub async fn fetch_follows(&self) -> Result<Vec<Candidate>, BmailError> {
+        let mut follows = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("actor", self.conf.user.handle.clone()),
+                ("limit", "100".to_string()),
+            ];
+            if let Some(cursor) = cursor {
+                query.push(("cursor", cursor));
+            }
+            let page: FollowsPage = self
+                .appview
+                .get("app.bsky.graph.getFollows")
+                .query(&query)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let done = page.follows.is_empty();
+            follows.extend(page.follows);
+            cursor = page.cursor;
+            if done || cursor.is_none() {
+                return Ok(follows);
+            }
+        }
+    }
+
+    /// Search for accounts whose handle or name starts with a query, like the Bluesky app does when you type a mention
+    pub async fn search_actors(&self, query: &str) -> Result<Vec<Candidate>, BmailError> {
+        let limit = MAX_SUGGESTIONS.to_string();
+        let results: TypeaheadResults = self
+            .appview
+            .get("app.bsky.actor.searchActorsTypeahead")
+            .query(&[("q", query), ("limit", limit.as_str())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(results.actors)
+    }
+
+    /// Check who has published a bmail_pub_key. Anyone whose profile can't be read is treated as not having one
+    pub async fn check_bmail_capable(&self, dids: &[String]) -> HashMap<String, bool> {
+        let mut capable = HashMap::new();
+        for did in dids {
+            let has_key = matches!(self.get_bmail_profile(did).await,
+                Ok(profile) if profile.value.key_set().is_some());
+            capable.insert(did.clone(), has_key);
+        }
+        capable
+    }
+
+    /// Check whether the notification post referenced by our profile is still around. Deleting it
+    /// would leave senders liking a post that doesn't exist, so one that's gone, or doesn't match the
+    /// stored CID, is missing. Anything else that goes wrong is an error, so a post isn't made twice
+    pub async fn notification_post_exists(
+        &self,
+        profile: &BmailEnabledProfile,
+    ) -> Result<bool, BmailError> {
+        let (Some(uri), Some(cid)) = (
+            &profile.bmail_notification_uri,
+            &profile.bmail_notification_cid,
+        ) else {
+            return Ok(false);
+        };
+        // at://did/app.bsky.feed.post/rkey
+        let mut parts = uri.trim_start_matches("at://").split('/');
+        let (Some(repo), Some(collection), Some(rkey)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Ok(false);
+        };
+
+        let response = self
+            .appview
+            .get("com.atproto.repo.getRecord")
+            .query(&[("repo", repo), ("collection", collection), ("rkey", rkey)])
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::BAD_REQUEST {
+            let error: XrpcError = response.json().await?;
+            return match error.error.as_str() {
+                "RecordNotFound" => Ok(false),
+                _ => Err(BmailError::XrpcError(error.error)),
+            };
+        }
+        let record: FetchedRecord = response.error_for_status()?.json().await?;
+        Ok(record.cid.as_ref() == Some(cid))
+    }
+
+    /// Create an extremely old post that can be liked to indicate you have a new Bmail. It's backdated
+    /// so that it's buried at the bottom of your feed, and the StrongRef is stored in your profile so
+    /// senders know what to like
+    pub async fn create_notification_post(&self) -> Result<(), BmailError> {
+        let handle = &self.conf.user.handle.clone();
+
+        let notif_post = {
+            let mut bsky = self.bluesky.0.write().await;
+            let mut me = bsky.me()?;
+            me.create_record(
+                "app.bsky.feed.post",
+                None,
+                None,
+                None,
+                Post {
+                    rust_type: Some("app.bsky.feed.post".to_string()),
+                    text: "You've got Bmail".to_string(),
+                    created_at: Utc.with_ymd_and_hms(1970, 1, 1, 0, 0, 0).unwrap(),
+                    embed: None,
+                    reply: None,
+                },
+            )
+            .await?
+        }; //Get existing Record so we can only change one thing
+
+        let mut profile_record = match self.get_bmail_profile(handle).await {
+            Ok(r) => r,
+            Err(_) => return Err(BmailError::InternalServerError),
+        };
+
+        profile_record.value.bmail_notification_uri = Some(notif_post.uri);
+        profile_record.value.bmail_notification_cid = Some(notif_post.cid);
+
+        let mut bsky = self.bluesky.0.write().await;
+        bsky.me()?
+            .put_record(
+                "app.bsky.actor.profile",
+                "self",
+                None,
+                None,
+                Some(&profile_record.cid),
+                &profile_record.value,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Add this device's key to the devices on our profile, if it isn't there yet, with its signed prekey if
+    /// forward secrecy is on. Profiles from before there were devices have their bmail_pub_key turned into the
+    /// first device. Fails if this device was revoked. Returns the keys of all our devices. See
+    /// [crate::message::key_set]
+    pub async fn register_device(
+        &self,
+        label: &str,
+        prekey: Option<SignedPrekey>,
+    ) -> Result<String, BmailError> {
+        let handle = &self.conf.user.handle.clone();
+
+        //Get existing Record so we can only change one thing
+        let mut profile_record = match self.get_bmail_profile(handle).await {
+            Ok(r) => r,
+            Err(_) => return Err(BmailError::InternalServerError),
+        };
+        let profile = &mut profile_record.value;
+        let our_key = self.identity.public_key();
+
+        let devices = profile.bmail_devices.get_or_insert_with(|| {
+            profile
+                .bmail_pub_key
+                .iter()
+                .filter(|key| **key != our_key)
+                .map(|key| BmailDevice {
+                    label: "Original device".to_string(),
+                    key: key.clone(),
+                    added_at: Utc::now(),
+                    revoked_at: None,
+                    prekey: None,
+                })
+                .collect()
+        });
+        match devices.iter_mut().find(|d| d.key == our_key) {
+            Some(device) if device.revoked_at.is_some() => {
+                return Err(BmailError::DeviceRevoked(device.label.clone()))
+            }
+            // Already registered, and the profile was already up to date
+            Some(device) if device.prekey == prekey && profile.bmail_pub_key.is_some() => {
+                return Ok(profile.key_set().unwrap_or_default())
+            }
+            Some(device) => device.prekey = prekey,
+            None => devices.push(BmailDevice {
+                label: label.to_string(),
+                key: our_key.clone(),
+                added_at: Utc::now(),
+                revoked_at: None,
+                prekey,
+            }),
+        }
+        if profile.bmail_pub_key.is_none() {
+            profile.bmail_pub_key = Some(our_key);
+        }
+        let key_set = profile.key_set().unwrap_or_default();
+        self.put_profile(&profile_record).await?;
+        Ok(key_set)
+    }
+
+    /// Get every device on our profile, including revoked ones
+    pub async fn list_devices(&self) -> Result<Vec<BmailDevice>, BmailError> {
+        let profile_record = self.get_bmail_profile(&self.conf.user.handle).await?;
+        Ok(profile_record.value.bmail_devices.unwrap_or_default())
+    }
+
+    /// Stop Bmails being encrypted to a device, found by its label or key fingerprint. Its key stays in the
+    /// list, marked revoked, so it can't be added back. Returns the revoked device
+    pub async fn revoke_device(&self, label_or_fingerprint: &str) -> Result<BmailDevice, BmailError> {
+        let mut profile_record = self.get_bmail_profile(&self.conf.user.handle).await?;
+        let profile = &mut profile_record.value;
+        let target = label_or_fingerprint.trim();
+        let target_fingerprint = target.replace(' ', "");
+        let wanted = |d: &&mut BmailDevice| {
+            let device_fingerprint = fingerprint(&d.key).replace(' ', "");
+            d.revoked_at.is_none()
+                && (d.label == target || device_fingerprint.starts_with(&target_fingerprint))
+        };
+        let devices = profile.bmail_devices.get_or_insert_with(Vec::new);
+        let matching = devices.iter_mut().filter(wanted).count();
+        if matching != 1 {
+            return Err(BmailError::DeviceNotFound(format!(
+                "{} ({} devices match)",
+                target, matching
+            )));
+        }
+        let device = devices.iter_mut().find(wanted).unwrap();
+        device.revoked_at = Some(Utc::now());
+        let revoked = device.clone();
+        // Older clients only know about bmail_pub_key, so point it at a device that's still good
+        if profile.bmail_pub_key.as_ref() == Some(&revoked.key) {
+            profile.bmail_pub_key = profile.active_keys().into_iter().next();
+        }
+        self.put_profile(&profile_record).await?;
+        Ok(revoked)
+    }
+
+    /// Write our profile record back
+    async fn put_profile(&self, profile_record: &Record<BmailEnabledProfile>) -> Result<(), BmailError> {
+        let mut bsky = self.bluesky.0.write().await;
+        let mut me = bsky.me()?;
+        me.put_record(
+            "app.bsky.actor.profile",
+            "self",
+            None,
+            None,
+            Some(&profile_record.cid),
+            &profile_record.value,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Notify Recipients they have a Bmail by liking their bmail notification post.
+    /// Takes the DIDs of every participant, including us, so the Like is enough to find the Conversation
+    pub async fn notify_recipients(
+        &self,
+        conversation_id: Uuid,
+        participants: Vec<String>,
+    ) -> Result<(), BmailError> {
+        for recipient in participants.iter() {
+            // Don't notify ourselves
+            if Some(recipient) == self.user_did.as_ref() {
+                continue;
+            }
+            let profile_record = self.get_bmail_profile(recipient).await?;
+            let mut bsky = self.bluesky.0.write().await;
+            let mut me = bsky.me().map_err::<BmailError, _>(Into::into)?;
+            me.create_record(
+                "app.bsky.feed.like",
+                None,
+                None,
+                None,
+                BmailLike {
+                    subject: StrongRef {
+                        cid: profile_record
+                            .value
+                            .bmail_notification_cid
+                            .ok_or_else(|| BmailError::MalformedBmail)?,
+                        uri: profile_record
+                            .value
+                            .bmail_notification_uri
+                            .ok_or_else(|| BmailError::MalformedBmail)?,
+                    },
+                    created_at: Utc::now(),
+                    bmail_recipients: participants.clone(),
+                    bmail_conversation_id: conversation_id,
+                    bmail_type: "notification".to_string(),
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
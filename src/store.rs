@@ -0,0 +1,601 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::errors::BmailError;
+use crate::key::{decrypt_and_decode, encrypt_and_encode, BmailIdentity};
+use crate::message::{GroupChange, GroupUpdate};
+
+/// How far ahead of our clock something can say it was sent, since clocks are never quite in step
+const MAX_CLOCK_SKEW_MINUTES: i64 = 5;
+
+/// Everything we keep on this computer between runs
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreData {
+    /// Unsent messages, by Conversation
+    pub drafts: HashMap<Uuid, Zeroizing<String>>,
+    /// The Bmail key we trust for each contact, by DID
+    pub pinned_keys: HashMap<String, PinnedKey>,
+    /// The disappearing messages timer for each Conversation that has had one
+    pub disappearing_timers: HashMap<Uuid, DisappearingTimer>,
+    /// Bmails we sent that disappear, so their records can be deleted once they have
+    pub expiring_records: Vec<ExpiringRecord>,
+    /// Named group Conversations, by Conversation ID
+    pub groups: HashMap<Uuid, Group>,
+    /// Titles, mutes and archives, kept here as well so changes aren't lost if syncing them fails
+    pub synced_settings: SyncedSettings,
+}
+
+/// What we've set for a Conversation on any of our devices
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConversationSettings {
+    /// Shown instead of who's in it
+    pub title: Option<String>,
+    /// Don't notify about new Bmails
+    pub muted: bool,
+    /// Hidden from the sidebar
+    pub archived: bool,
+    /// When it was last changed, so the newest change wins when devices are merged
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Conversation settings synced between our devices, in an encrypted field on our profile
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncedSettings {
+    pub conversations: HashMap<Uuid, ConversationSettings>,
+}
+
+impl SyncedSettings {
+    /// Take any settings from another device that are newer than ours. Returns whether anything changed
+    pub fn merge(&mut self, other: &SyncedSettings) -> bool {
+        let mut changed = false;
+        for (c_id, theirs) in other.conversations.iter() {
+            let ours_newer = matches!(
+                self.conversations.get(c_id),
+                Some(ours) if ours.updated_at >= theirs.updated_at
+            );
+            if ours_newer {
+                continue;
+            }
+            self.conversations.insert(*c_id, theirs.clone());
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// A named group Conversation, built up from the control messages sent in it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    /// DIDs of everyone in it now, sorted
+    pub members: Vec<String>,
+    /// DIDs of people who've left or been removed, so their old Bmails can still be fetched
+    pub former_members: Vec<String>,
+    /// When the last change we applied was sent
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Group {
+    /// Everyone who might have Bmails in the group, past and present
+    pub fn everyone(&self) -> Vec<String> {
+        let mut everyone: Vec<String> = self
+            .members
+            .iter()
+            .chain(self.former_members.iter())
+            .cloned()
+            .collect();
+        everyone.sort();
+        everyone.dedup();
+        everyone
+    }
+}
+
+/// How long Bmails in a Conversation last, and when that was last set by anyone in it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DisappearingTimer {
+    /// Seconds a Bmail lasts after it's sent. None means they don't disappear
+    pub seconds: Option<u64>,
+    pub set_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExpiringRecord {
+    pub uri: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A contact's public key, pinned the first time we saw it so a swapped key can't slip by unnoticed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PinnedKey {
+    pub key: String,
+    pub first_seen: DateTime<Utc>,
+    /// Whether the fingerprint has been compared with the contact some other way
+    pub verified: bool,
+}
+
+/// What we found when checking a key against the one we pinned
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyCheck {
+    /// We hadn't seen a key for them before, so this one is pinned now
+    FirstUse,
+    Matches,
+    /// The key is different from the one we pinned. Either they made a new key, or someone is
+    /// pretending to be them
+    Changed,
+}
+
+/// Local storage, kept in a file encrypted to our own key since drafts are as private as the Bmails themselves
+#[derive(Default)]
+pub struct LocalStore {
+    /// Where the store is saved. If this is None it only lives in memory
+    path: Option<PathBuf>,
+    data: StoreData,
+    /// Whether anything has changed since the last save
+    dirty: bool,
+}
+
+impl LocalStore {
+    /// Open the store, starting an empty one if the file doesn't exist yet. One we can't decrypt is an
+    /// error, since it may be encrypted to a key we can get back
+    pub async fn open(path: PathBuf, identity: &BmailIdentity) -> Result<Self, BmailError> {
+        let data = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) if !contents.is_empty() => decrypt_and_decode(identity, contents.trim())
+                .await
+                .map_err(|e| {
+                    BmailError::UnreadableStore(path.display().to_string(), e.to_string())
+                })?,
+            Ok(_) => StoreData::default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoreData::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            data,
+            dirty: false,
+        })
+    }
+
+    /// Get the unsent draft for a Conversation
+    pub fn draft(&self, conversation_id: &Uuid) -> Option<&str> {
+        self.data.drafts.get(conversation_id).map(|d| d.as_str())
+    }
+
+    /// Set the draft for a Conversation. An empty draft is removed
+    pub fn set_draft(&mut self, conversation_id: Uuid, draft: &str) {
+        if self.draft(&conversation_id).unwrap_or_default() == draft {
+            return;
+        }
+        if draft.is_empty() {
+            self.data.drafts.remove(&conversation_id);
+        } else {
+            self.data
+                .drafts
+                .insert(conversation_id, Zeroizing::new(draft.to_string()));
+        }
+        self.dirty = true;
+    }
+
+    /// Get everything in the store, for backups
+    pub fn data(&self) -> &StoreData {
+        &self.data
+    }
+
+    /// Replace everything in the store, like when restoring a backup
+    pub fn replace(&mut self, data: StoreData) {
+        self.data = data;
+        self.dirty = true;
+    }
+
+    /// Get the key we trust for a contact
+    pub fn pinned_key(&self, did: &str) -> Option<&PinnedKey> {
+        self.data.pinned_keys.get(did)
+    }
+
+    /// Get the keys we trust for every contact, by DID
+    pub fn pinned_keys(&self) -> HashMap<String, String> {
+        self.data
+            .pinned_keys
+            .iter()
+            .map(|(did, pin)| (did.clone(), pin.key.clone()))
+            .collect()
+    }
+
+    /// Check a contact's key against the pinned one, pinning it if it's the first one we've seen
+    pub fn check_key(&mut self, did: &str, key: &str) -> KeyCheck {
+        match self.data.pinned_keys.get(did) {
+            Some(pin) if pin.key == key => KeyCheck::Matches,
+            Some(_) => KeyCheck::Changed,
+            None => {
+                self.pin_key(did, key);
+                KeyCheck::FirstUse
+            }
+        }
+    }
+
+    /// Trust a new key for a contact, replacing any we had. It starts out unverified
+    pub fn pin_key(&mut self, did: &str, key: &str) {
+        self.data.pinned_keys.insert(
+            did.to_string(),
+            PinnedKey {
+                key: key.to_string(),
+                first_seen: Utc::now(),
+                verified: false,
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Mark a contact's pinned key as verified or not
+    pub fn set_verified(&mut self, did: &str, verified: bool) {
+        if let Some(pin) = self.data.pinned_keys.get_mut(did) {
+            pin.verified = verified;
+            self.dirty = true;
+        }
+    }
+
+    /// Get how long Bmails in a Conversation last, if they disappear
+    pub fn disappearing_timer(&self, conversation_id: &Uuid) -> Option<u64> {
+        self.data
+            .disappearing_timers
+            .get(conversation_id)
+            .and_then(|t| t.seconds)
+    }
+
+    /// Set the disappearing messages timer for a Conversation, if this is newer than the last time it was set.
+    /// Every Bmail carries the timer it was sent with, so this is how changes from others are picked up.
+    /// Returns whether the timer changed
+    pub fn set_disappearing_timer(
+        &mut self,
+        conversation_id: Uuid,
+        seconds: Option<u64>,
+        set_at: DateTime<Utc>,
+    ) -> bool {
+        // A Bmail dated in the future would otherwise hold the timer until then
+        let set_at = set_at.min(Utc::now());
+        let current = self.data.disappearing_timers.get(&conversation_id);
+        if matches!(current, Some(t) if t.set_at >= set_at) {
+            return false;
+        }
+        let changed = current.and_then(|t| t.seconds) != seconds;
+        self.data
+            .disappearing_timers
+            .insert(conversation_id, DisappearingTimer { seconds, set_at });
+        self.dirty = true;
+        changed
+    }
+
+    /// Get a group, if the Conversation is one
+    pub fn group(&self, conversation_id: &Uuid) -> Option<&Group> {
+        self.data.groups.get(conversation_id)
+    }
+
+    /// Apply a change someone sent to a group to the members we have for it. Changes from people who
+    /// weren't in the group, ones older than the last one applied and ones from the future are ignored.
+    /// Returns whether it was applied
+    pub fn apply_group_update(
+        &mut self,
+        conversation_id: Uuid,
+        sender: &str,
+        update: &GroupUpdate,
+        sent_at: DateTime<Utc>,
+        user_did: Option<&str>,
+    ) -> bool {
+        if sent_at > Utc::now() + Duration::minutes(MAX_CLOCK_SKEW_MINUTES) {
+            return false;
+        }
+        let Some(group) = self.data.groups.get(&conversation_id) else {
+            // A group starts from the Bmail that made it, or the one that added us if we joined later, since
+            // we can't read the ones from before that
+            let starts = match &update.change {
+                GroupChange::Created => true,
+                GroupChange::Added(added) => {
+                    matches!(user_did, Some(us) if added.iter().any(|d| d == us))
+                }
+                _ => false,
+            };
+            if !starts || !update.members.iter().any(|m| m == sender) {
+                return false;
+            }
+            let mut members = update.members.clone();
+            members.sort();
+            members.dedup();
+            self.data.groups.insert(
+                conversation_id,
+                Group {
+                    name: update.name.clone(),
+                    members,
+                    former_members: Vec::new(),
+                    updated_at: sent_at,
+                },
+            );
+            self.dirty = true;
+            return true;
+        };
+        if group.updated_at >= sent_at || !group.members.iter().any(|m| m == sender) {
+            return false;
+        }
+        let mut name = group.name.clone();
+        let mut members = group.members.clone();
+        match &update.change {
+            GroupChange::Created => return false,
+            GroupChange::Renamed if update.name.trim().is_empty() => return false,
+            GroupChange::Renamed => name = update.name.clone(),
+            GroupChange::Added(added) => members.extend(added.iter().cloned()),
+            // Leaving is done with Left, so the members that are left always include whoever removed them
+            GroupChange::Removed(removed) if removed.iter().any(|d| d == sender) => return false,
+            GroupChange::Removed(removed) => members.retain(|m| !removed.contains(m)),
+            GroupChange::Left => members.retain(|m| m != sender),
+        }
+        members.sort();
+        members.dedup();
+        let mut former_members: Vec<String> = group
+            .former_members
+            .iter()
+            .chain(group.members.iter())
+            .filter(|m| !members.contains(m))
+            .cloned()
+            .collect();
+        former_members.sort();
+        former_members.dedup();
+        self.data.groups.insert(
+            conversation_id,
+            Group {
+                name,
+                members,
+                former_members,
+                updated_at: sent_at,
+            },
+        );
+        self.dirty = true;
+        true
+    }
+
+    /// Get what we've set for a Conversation, if anything
+    pub fn conversation_settings(&self, conversation_id: &Uuid) -> Option<&ConversationSettings> {
+        self.data.synced_settings.conversations.get(conversation_id)
+    }
+
+    /// Get the settings for every Conversation, to sync them
+    pub fn synced_settings(&self) -> &SyncedSettings {
+        &self.data.synced_settings
+    }
+
+    /// Change what we've set for a Conversation. It's marked as changed now, so it wins over other devices
+    pub fn update_conversation_settings(
+        &mut self,
+        conversation_id: Uuid,
+        change: impl FnOnce(&mut ConversationSettings),
+    ) {
+        let settings = self
+            .data
+            .synced_settings
+            .conversations
+            .entry(conversation_id)
+            .or_insert_with(|| ConversationSettings {
+                title: None,
+                muted: false,
+                archived: false,
+                updated_at: Utc::now(),
+            });
+        change(settings);
+        settings.updated_at = Utc::now();
+        self.dirty = true;
+    }
+
+    /// Take settings synced from our other devices
+    pub fn merge_synced_settings(&mut self, settings: &SyncedSettings) {
+        if self.data.synced_settings.merge(settings) {
+            self.dirty = true;
+        }
+    }
+
+    /// Remember a Bmail we sent that disappears, so its record can be deleted later
+    pub fn add_expiring_record(&mut self, uri: String, expires_at: DateTime<Utc>) {
+        self.data
+            .expiring_records
+            .push(ExpiringRecord { uri, expires_at });
+        self.dirty = true;
+    }
+
+    /// Get the records of our Bmails that have disappeared and still need deleting
+    pub fn expired_records(&self, now: &DateTime<Utc>) -> Vec<String> {
+        self.data
+            .expiring_records
+            .iter()
+            .filter(|r| r.expires_at <= *now)
+            .map(|r| r.uri.clone())
+            .collect()
+    }
+
+    /// Forget records once they've been deleted
+    pub fn remove_expiring_records(&mut self, uris: &[String]) {
+        if uris.is_empty() {
+            return;
+        }
+        self.data
+            .expiring_records
+            .retain(|r| !uris.contains(&r.uri));
+        self.dirty = true;
+    }
+
+    /// Write the store out if anything has changed. It's written to a temporary file first, so a crash
+    /// partway through doesn't lose the old one
+    pub async fn save(&mut self, identity: &BmailIdentity) -> Result<(), BmailError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        let encoded = encrypt_and_encode(vec![identity.recipient()?], &self.data).await?;
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, encoded).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(members: &[&str], change: GroupChange) -> GroupUpdate {
+        GroupUpdate {
+            name: "Friends".to_string(),
+            members: members.iter().map(|m| m.to_string()).collect(),
+            change,
+        }
+    }
+
+    fn dids(dids: &[&str]) -> Vec<String> {
+        dids.iter().map(|d| d.to_string()).collect()
+    }
+
+    fn minutes_ago(minutes: i64) -> DateTime<Utc> {
+        Utc::now() - Duration::minutes(minutes)
+    }
+
+    /// A store with a group of a, b and c that a made
+    fn group_store() -> (LocalStore, Uuid) {
+        let mut store = LocalStore::default();
+        let c_id = Uuid::new_v4();
+        let created = update(&["a", "b", "c"], GroupChange::Created);
+        assert!(store.apply_group_update(c_id, "a", &created, minutes_ago(60), Some("b")));
+        (store, c_id)
+    }
+
+    #[test]
+    fn group_changes_apply_to_current_members() {
+        let (mut store, c_id) = group_store();
+        // The member list sent with a change doesn't matter, only the change does
+        let added = update(&["d"], GroupChange::Added(dids(&["d"])));
+        assert!(store.apply_group_update(c_id, "b", &added, minutes_ago(50), Some("b")));
+        assert_eq!(
+            store.group(&c_id).unwrap().members,
+            dids(&["a", "b", "c", "d"])
+        );
+
+        let removed = update(&["a"], GroupChange::Removed(dids(&["d"])));
+        assert!(store.apply_group_update(c_id, "c", &removed, minutes_ago(40), Some("b")));
+        let group = store.group(&c_id).unwrap();
+        assert_eq!(group.members, dids(&["a", "b", "c"]));
+        assert_eq!(group.former_members, dids(&["d"]));
+
+        let left = update(&[], GroupChange::Left);
+        assert!(store.apply_group_update(c_id, "a", &left, minutes_ago(30), Some("b")));
+        assert_eq!(store.group(&c_id).unwrap().members, dids(&["b", "c"]));
+
+        let mut renamed = update(&[], GroupChange::Renamed);
+        renamed.name = "Pals".to_string();
+        assert!(store.apply_group_update(c_id, "c", &renamed, minutes_ago(20), Some("b")));
+        let group = store.group(&c_id).unwrap();
+        assert_eq!(group.name, "Pals");
+        assert_eq!(group.members, dids(&["b", "c"]));
+        assert_eq!(group.former_members, dids(&["a", "d"]));
+    }
+
+    #[test]
+    fn bad_group_changes_are_ignored() {
+        let (mut store, c_id) = group_store();
+        let added = update(&["a", "b", "c", "e"], GroupChange::Added(dids(&["e"])));
+        // Not in the group
+        assert!(!store.apply_group_update(c_id, "e", &added, minutes_ago(50), Some("b")));
+        // Older than the last change
+        assert!(!store.apply_group_update(c_id, "a", &added, minutes_ago(70), Some("b")));
+        // From the future
+        let later = Utc::now() + Duration::hours(1);
+        assert!(!store.apply_group_update(c_id, "a", &added, later, Some("b")));
+        // Removing yourself, or making the group again
+        let removed = update(&["b", "c"], GroupChange::Removed(dids(&["a"])));
+        assert!(!store.apply_group_update(c_id, "a", &removed, minutes_ago(50), Some("b")));
+        let created = update(&["a", "e"], GroupChange::Created);
+        assert!(!store.apply_group_update(c_id, "a", &created, minutes_ago(50), Some("b")));
+        assert_eq!(store.group(&c_id).unwrap().members, dids(&["a", "b", "c"]));
+    }
+
+    /// Settings with a title for each Conversation, changed some minutes ago
+    fn synced(conversations: &[(Uuid, &str, i64)]) -> SyncedSettings {
+        let conversations = conversations
+            .iter()
+            .map(|(c_id, title, minutes)| {
+                let settings = ConversationSettings {
+                    title: Some(title.to_string()),
+                    muted: false,
+                    archived: false,
+                    updated_at: minutes_ago(*minutes),
+                };
+                (*c_id, settings)
+            })
+            .collect();
+        SyncedSettings { conversations }
+    }
+
+    #[test]
+    fn newest_settings_win_when_merged() {
+        let ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let mut ours = synced(&[
+            (ids[0], "ours", 10),
+            (ids[1], "ours", 30),
+            (ids[2], "ours", 30),
+        ]);
+        let theirs = synced(&[
+            (ids[0], "theirs", 20),
+            (ids[1], "theirs", 5),
+            (ids[3], "theirs", 30),
+        ]);
+
+        assert!(ours.merge(&theirs));
+        let titles: Vec<&str> = ids
+            .iter()
+            .map(|c_id| ours.conversations[c_id].title.as_deref().unwrap())
+            .collect();
+        assert_eq!(titles, vec!["ours", "theirs", "ours", "theirs"]);
+        // Merging the same thing again changes nothing
+        assert!(!ours.merge(&theirs));
+    }
+
+    #[test]
+    fn newest_disappearing_timer_wins() {
+        let mut store = LocalStore::default();
+        let c_id = Uuid::new_v4();
+        assert!(store.set_disappearing_timer(c_id, Some(60), minutes_ago(30)));
+        assert!(!store.set_disappearing_timer(c_id, None, minutes_ago(40)));
+        assert!(!store.set_disappearing_timer(c_id, Some(60), minutes_ago(20)));
+        assert!(store.set_disappearing_timer(c_id, None, minutes_ago(10)));
+        assert_eq!(store.disappearing_timer(&c_id), None);
+    }
+
+    #[test]
+    fn future_disappearing_timer_doesnt_stick() {
+        let mut store = LocalStore::default();
+        let c_id = Uuid::new_v4();
+        let later = Utc::now() + Duration::days(365);
+        assert!(store.set_disappearing_timer(c_id, Some(60), later));
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(store.set_disappearing_timer(c_id, None, Utc::now()));
+    }
+
+    #[test]
+    fn groups_start_from_their_creation_or_being_added() {
+        let mut store = LocalStore::default();
+        let c_id = Uuid::new_v4();
+        let renamed = update(&["a", "b"], GroupChange::Renamed);
+        assert!(!store.apply_group_update(c_id, "a", &renamed, minutes_ago(10), Some("b")));
+        let added = update(&["a", "b", "c"], GroupChange::Added(dids(&["c"])));
+        assert!(!store.apply_group_update(c_id, "a", &added, minutes_ago(10), Some("b")));
+        let created = update(&["b", "c"], GroupChange::Created);
+        assert!(!store.apply_group_update(c_id, "a", &created, minutes_ago(10), Some("b")));
+        assert!(store.group(&c_id).is_none());
+
+        let added = update(&["a", "b", "c"], GroupChange::Added(dids(&["b"])));
+        assert!(store.apply_group_update(c_id, "a", &added, minutes_ago(10), Some("b")));
+        assert_eq!(store.group(&c_id).unwrap().members, dids(&["a", "b", "c"]));
+    }
+}
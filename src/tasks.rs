@@ -0,0 +1,156 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    time::Instant,
+};
+
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    autocomplete::Candidate,
+    errors::BmailError,
+    session::{FetchedConversation, LoadedConversation, ParticipantKey, SentBmail},
+    store::{LocalStore, SyncedSettings},
+};
+
+/// The result of a network operation run in the background, sent back to the UI to apply
+pub enum TaskOutcome {
+    ConversationLoaded(Result<LoadedConversation, BmailError>),
+    /// New messages for a Conversation we were notified about
+    ConversationSynced(Result<FetchedConversation, BmailError>),
+    /// Keeps the message that was being sent, so it can be put back in the input box if sending fails
+    BmailSent {
+        conversation_id: Uuid,
+        draft: Zeroizing<String>,
+        result: Result<SentBmail, BmailError>,
+    },
+    FollowsLoaded(Result<Vec<Candidate>, BmailError>),
+    /// Keeps what was searched for, so results for something that isn't being typed anymore can be dropped
+    ActorsSearched {
+        query: String,
+        result: Result<Vec<Candidate>, BmailError>,
+    },
+    BmailCapabilityChecked(HashMap<String, bool>),
+    /// Keys fetched again after one turned out to be different from the pinned one
+    ParticipantKeysChecked(HashMap<String, ParticipantKey>),
+    /// A group change, sent as a control message to everyone it affects
+    GroupUpdated(Result<SentBmail, BmailError>),
+    /// Conversation settings, merged with the ones from our other devices
+    SettingsSynced(Result<SyncedSettings, BmailError>),
+    /// Records of our disappearing Bmails that were deleted. Any that weren't are tried again later
+    ExpiredRecordsDeleted {
+        deleted: Vec<String>,
+        result: Result<(), BmailError>,
+    },
+    /// The store and ratchet state have been saved and dropped from memory
+    Locked(Result<(), BmailError>),
+    /// The store, opened again once the lock passphrase was right
+    Unlocked(Result<LocalStore, BmailError>),
+}
+
+struct RunningTask {
+    label: String,
+    started: Instant,
+    handle: JoinHandle<()>,
+}
+
+/// Runs network operations in the background so the UI stays responsive, and keeps track of them
+/// so they can be shown in the status line and cancelled
+pub struct TaskManager {
+    next_id: u64,
+    running: BTreeMap<u64, RunningTask>,
+    tx: UnboundedSender<(u64, TaskOutcome)>,
+    rx: UnboundedReceiver<(u64, TaskOutcome)>,
+}
+
+impl Default for TaskManager {
+    fn default() -> Self {
+        let (tx, rx) = unbounded_channel();
+        Self {
+            next_id: 0,
+            running: BTreeMap::new(),
+            tx,
+            rx,
+        }
+    }
+}
+
+impl TaskManager {
+    /// Run a task in the background. Its outcome comes back through [TaskManager::recv]
+    pub fn spawn<F>(&mut self, label: &str, task: F)
+    where
+        F: Future<Output = TaskOutcome> + Send + 'static,
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let tx = self.tx.clone();
+        let handle = tokio::spawn(async move {
+            let outcome = task.await;
+            // If this fails the UI is gone, and nobody cares anymore
+            let _ = tx.send((id, outcome));
+        });
+        self.running.insert(
+            id,
+            RunningTask {
+                label: label.to_string(),
+                started: Instant::now(),
+                handle,
+            },
+        );
+    }
+
+    /// Wait for the next background task to finish
+    pub async fn recv(&mut self) -> TaskOutcome {
+        loop {
+            // We hold a Sender, so the channel never closes
+            let Some((id, outcome)) = self.rx.recv().await else {
+                return std::future::pending().await;
+            };
+            // A cancelled task might have finished before it was aborted, so we drop its outcome
+            if self.running.remove(&id).is_some() {
+                return outcome;
+            }
+        }
+    }
+
+    /// Cancel everything that's running. Returns how many tasks were cancelled
+    pub fn cancel_all(&mut self) -> usize {
+        let running = std::mem::take(&mut self.running);
+        for task in running.values() {
+            task.handle.abort();
+        }
+        running.len()
+    }
+
+    /// Whether a task with a label is running
+    pub fn is_running(&self, label: &str) -> bool {
+        self.running.values().any(|t| t.label == label)
+    }
+
+    /// Whether nothing is running
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Describe what's running for the status line, with the key that cancels it if there is one
+    pub fn progress(&self, cancel_key: Option<&str>) -> Option<String> {
+        if self.running.is_empty() {
+            return None;
+        }
+        let tasks: Vec<String> = self
+            .running
+            .values()
+            .map(|t| format!("{} ({}s)", t.label, t.started.elapsed().as_secs()))
+            .collect();
+        Some(match cancel_key {
+            Some(key) => format!("{}... {} to cancel", tasks.join(", "), key),
+            None => format!("{}...", tasks.join(", ")),
+        })
+    }
+}
@@ -0,0 +1,223 @@
+use ratatui::style::{Color, Modifier, Style};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conf::{StyleSettings, ThemeSettings},
+    errors::BmailError,
+};
+
+/// Built in colour schemes
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemePreset {
+    #[default]
+    Dark,
+    Light,
+    HighContrast,
+}
+
+/// The parts of the UI that can be styled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThemeElement {
+    /// The app name at the top
+    Title,
+    /// Keys in the help line
+    Key,
+    /// The border and text of whichever pane has focus
+    Focused,
+    /// The selected Conversation in the sidebar
+    Selected,
+    /// Unread counts
+    Unread,
+    /// Less important text, like previews and day separators
+    Dim,
+    /// Our handle on messages we sent
+    OwnMessage,
+    /// Other people's handles on messages they sent
+    OtherMessage,
+    /// People whose keys haven't been verified
+    Unverified,
+    Status,
+    StatusError,
+}
+
+/// Named styles for everything in the UI
+#[derive(Clone)]
+pub struct Theme {
+    pub title: Style,
+    pub key: Style,
+    pub focused: Style,
+    pub selected: Style,
+    pub unread: Style,
+    pub dim: Style,
+    pub own_message: Style,
+    pub other_message: Style,
+    pub unverified: Style,
+    pub status: Style,
+    pub status_error: Style,
+}
+
+impl Theme {
+    /// Build the theme from a preset and any overrides. Colours are dropped if `NO_COLOR` is set
+    pub fn new(settings: &ThemeSettings) -> Result<Self, BmailError> {
+        let mut theme = Self::preset(settings.preset);
+        for (element, style) in settings.overrides.iter() {
+            *theme.style_mut(*element) = parse_style(style)?;
+        }
+        if matches!(std::env::var_os("NO_COLOR"), Some(v) if !v.is_empty()) {
+            theme.strip_colors();
+        }
+        Ok(theme)
+    }
+
+    pub fn preset(preset: ThemePreset) -> Self {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        match preset {
+            ThemePreset::Dark => Self {
+                title: bold,
+                key: bold,
+                focused: Style::default().fg(Color::Yellow),
+                selected: Style::default().add_modifier(Modifier::REVERSED),
+                unread: Style::default().fg(Color::Yellow),
+                dim: Style::default().add_modifier(Modifier::DIM),
+                own_message: bold.fg(Color::LightCyan),
+                other_message: bold.fg(Color::LightGreen),
+                unverified: Style::default().fg(Color::LightRed),
+                status: Style::default(),
+                status_error: bold.fg(Color::LightRed),
+            },
+            ThemePreset::Light => Self {
+                title: bold,
+                key: bold,
+                focused: Style::default().fg(Color::Blue),
+                selected: Style::default().add_modifier(Modifier::REVERSED),
+                unread: Style::default().fg(Color::Magenta),
+                dim: Style::default().fg(Color::DarkGray),
+                own_message: bold.fg(Color::Blue),
+                other_message: bold.fg(Color::Green),
+                unverified: Style::default().fg(Color::Red),
+                status: Style::default(),
+                status_error: bold.fg(Color::Red),
+            },
+            // No dimmed text, and nothing that relies on telling similar colours apart
+            ThemePreset::HighContrast => Self {
+                title: bold.fg(Color::White),
+                key: bold.fg(Color::Yellow),
+                focused: bold.fg(Color::Yellow),
+                selected: bold.fg(Color::Black).bg(Color::Yellow),
+                unread: bold.fg(Color::Yellow),
+                dim: Style::default().fg(Color::White),
+                own_message: bold.fg(Color::White),
+                other_message: bold.fg(Color::LightCyan),
+                unverified: bold
+                    .fg(Color::LightRed)
+                    .add_modifier(Modifier::UNDERLINED),
+                status: Style::default().fg(Color::White),
+                status_error: bold.fg(Color::White).bg(Color::Red),
+            },
+        }
+    }
+
+    fn style_mut(&mut self, element: ThemeElement) -> &mut Style {
+        match element {
+            ThemeElement::Title => &mut self.title,
+            ThemeElement::Key => &mut self.key,
+            ThemeElement::Focused => &mut self.focused,
+            ThemeElement::Selected => &mut self.selected,
+            ThemeElement::Unread => &mut self.unread,
+            ThemeElement::Dim => &mut self.dim,
+            ThemeElement::OwnMessage => &mut self.own_message,
+            ThemeElement::OtherMessage => &mut self.other_message,
+            ThemeElement::Unverified => &mut self.unverified,
+            ThemeElement::Status => &mut self.status,
+            ThemeElement::StatusError => &mut self.status_error,
+        }
+    }
+
+    /// Keep the bold, reversed and so on, but lose the colours. See https://no-color.org
+    fn strip_colors(&mut self) {
+        for element in [
+            ThemeElement::Title,
+            ThemeElement::Key,
+            ThemeElement::Focused,
+            ThemeElement::Selected,
+            ThemeElement::Unread,
+            ThemeElement::Dim,
+            ThemeElement::OwnMessage,
+            ThemeElement::OtherMessage,
+            ThemeElement::Unverified,
+            ThemeElement::Status,
+            ThemeElement::StatusError,
+        ] {
+            let style = self.style_mut(element);
+            style.fg = None;
+            style.bg = None;
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::preset(ThemePreset::default())
+    }
+}
+
+fn parse_style(settings: &StyleSettings) -> Result<Style, BmailError> {
+    let mut style = Style::default();
+    if let Some(fg) = &settings.fg {
+        style = style.fg(parse_color(fg)?);
+    }
+    if let Some(bg) = &settings.bg {
+        style = style.bg(parse_color(bg)?);
+    }
+    for modifier in settings.modifiers.iter() {
+        style = style.add_modifier(parse_modifier(modifier)?);
+    }
+    Ok(style)
+}
+
+/// Parse a colour name like `light_red`, a hex colour like `#ff8800`, or a 256 colour palette index
+fn parse_color(s: &str) -> Result<Color, BmailError> {
+    let invalid = || BmailError::InvalidTheme(format!("Unknown colour {}", s));
+    let name = s.trim().to_lowercase().replace(['-', ' '], "_");
+    Ok(match name.as_str() {
+        "reset" | "default" => Color::Reset,
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "dark_gray" | "dark_grey" => Color::DarkGray,
+        "light_red" => Color::LightRed,
+        "light_green" => Color::LightGreen,
+        "light_yellow" => Color::LightYellow,
+        "light_blue" => Color::LightBlue,
+        "light_magenta" => Color::LightMagenta,
+        "light_cyan" => Color::LightCyan,
+        "white" => Color::White,
+        hex if hex.starts_with('#') && hex.len() == 7 && hex.is_ascii() => {
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+            Color::Rgb(channel(1)?, channel(3)?, channel(5)?)
+        }
+        index => Color::Indexed(index.parse().map_err(|_| invalid())?),
+    })
+}
+
+fn parse_modifier(s: &str) -> Result<Modifier, BmailError> {
+    Ok(match s.trim().to_lowercase().as_str() {
+        "bold" => Modifier::BOLD,
+        "dim" => Modifier::DIM,
+        "italic" => Modifier::ITALIC,
+        "underlined" | "underline" => Modifier::UNDERLINED,
+        "slow_blink" => Modifier::SLOW_BLINK,
+        "rapid_blink" => Modifier::RAPID_BLINK,
+        "reversed" => Modifier::REVERSED,
+        "hidden" => Modifier::HIDDEN,
+        "crossed_out" => Modifier::CROSSED_OUT,
+        _ => return Err(BmailError::InvalidTheme(format!("Unknown modifier {}", s))),
+    })
+}
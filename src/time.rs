@@ -0,0 +1,178 @@
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, FixedOffset, Local, NaiveDate, Offset, Utc,
+};
+use chrono_tz::Tz;
+
+use crate::{conf::TimeSettings, errors::BmailError};
+
+/// Which timezone times are shown in
+#[derive(Clone, Default)]
+pub enum Zone {
+    /// Whatever the system is set to
+    #[default]
+    Local,
+    Named(Tz),
+}
+
+/// Shows times in the configured timezone and formats
+#[derive(Clone, Default)]
+pub struct TimeFormatter {
+    zone: Zone,
+    settings: TimeSettings,
+}
+
+impl TimeFormatter {
+    /// Check the timezone and formats from the settings up front, since chrono panics on a bad format
+    /// string when it's displayed
+    pub fn new(settings: TimeSettings) -> Result<Self, BmailError> {
+        let zone = match settings.timezone.trim() {
+            "" => Zone::Local,
+            name => Zone::Named(name.parse::<Tz>().map_err(|_| {
+                BmailError::InvalidTimeSettings(format!("Unknown timezone {}", name))
+            })?),
+        };
+        for format in [
+            &settings.message_format,
+            &settings.day_format,
+            &settings.sidebar_format,
+        ] {
+            if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+                return Err(BmailError::InvalidTimeSettings(format!(
+                    "Invalid time format {}",
+                    format
+                )));
+            }
+        }
+        Ok(Self { zone, settings })
+    }
+
+    /// Convert a UTC time into the configured timezone
+    pub fn localize(&self, time: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        match &self.zone {
+            Zone::Local => time.with_timezone(&Local).into(),
+            Zone::Named(tz) => {
+                let time = time.with_timezone(tz);
+                time.with_timezone(&time.offset().fix())
+            }
+        }
+    }
+
+    /// Get the day a time falls on in the configured timezone
+    pub fn local_date(&self, time: &DateTime<Utc>) -> NaiveDate {
+        self.localize(time).date_naive()
+    }
+
+    /// Format the time a message was sent, to go next to it
+    pub fn message_time(&self, time: &DateTime<Utc>) -> String {
+        self.localize(time)
+            .format(&self.settings.message_format)
+            .to_string()
+    }
+
+    /// Format the time of the last message in a Conversation for the sidebar. Messages from today
+    /// just get the time
+    pub fn sidebar_time(&self, time: &DateTime<Utc>) -> String {
+        if self.local_date(time) == self.local_date(&Utc::now()) {
+            self.message_time(time)
+        } else {
+            self.localize(time)
+                .format(&self.settings.sidebar_format)
+                .to_string()
+        }
+    }
+
+    /// Get the header for the day a time falls on. Today and Yesterday get called that
+    pub fn day_label(&self, time: &DateTime<Utc>) -> String {
+        let date = self.local_date(time);
+        let today = self.local_date(&Utc::now());
+        if date == today {
+            "Today".to_string()
+        } else if Some(date) == today.pred_opt() {
+            "Yesterday".to_string()
+        } else {
+            self.localize(time)
+                .format(&self.settings.day_format)
+                .to_string()
+        }
+    }
+}
+
+/// Describe a number of seconds in the biggest unit that fits it exactly, like "5 minutes" or "1 week"
+pub fn duration_label(seconds: u64) -> String {
+    let units = [
+        (7 * 24 * 60 * 60, "week"),
+        (24 * 60 * 60, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+    ];
+    let (count, unit) = units
+        .into_iter()
+        .find(|(size, _)| seconds >= *size && seconds % size == 0)
+        .map(|(size, unit)| (seconds / size, unit))
+        .unwrap_or((seconds, "second"));
+    match count {
+        1 => format!("1 {}", unit),
+        n => format!("{} {}s", n, unit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn formatter(timezone: &str) -> TimeFormatter {
+        TimeFormatter::new(TimeSettings {
+            timezone: timezone.to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn shows_times_in_the_timezone() {
+        let time = Utc.with_ymd_and_hms(2024, 1, 15, 3, 30, 0).unwrap();
+        let la = formatter("America/Los_Angeles");
+        assert_eq!(la.message_time(&time), "19:30");
+        assert_eq!(
+            la.local_date(&time),
+            NaiveDate::from_ymd_opt(2024, 1, 14).unwrap()
+        );
+        assert_eq!(la.day_label(&time), "Sunday, January 14, 2024");
+        assert_eq!(la.sidebar_time(&time), "01/14");
+        assert_eq!(formatter("UTC").message_time(&time), "03:30");
+    }
+
+    #[test]
+    fn recent_days_get_names() {
+        let utc = formatter("UTC");
+        let now = Utc::now();
+        assert_eq!(utc.day_label(&now), "Today");
+        assert_eq!(utc.day_label(&(now - Duration::days(1))), "Yesterday");
+        assert_eq!(utc.sidebar_time(&now), utc.message_time(&now));
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let bad_zone = TimeSettings {
+            timezone: "Mars/Olympus_Mons".to_string(),
+            ..Default::default()
+        };
+        assert!(TimeFormatter::new(bad_zone).is_err());
+        let bad_format = TimeSettings {
+            message_format: "%Q".to_string(),
+            ..Default::default()
+        };
+        assert!(TimeFormatter::new(bad_format).is_err());
+    }
+
+    #[test]
+    fn labels_durations() {
+        assert_eq!(duration_label(300), "5 minutes");
+        assert_eq!(duration_label(3600), "1 hour");
+        assert_eq!(duration_label(90), "90 seconds");
+        assert_eq!(duration_label(7 * 24 * 60 * 60), "1 week");
+        assert_eq!(duration_label(2 * 24 * 60 * 60), "2 days");
+    }
+}
@@ -1,58 +1,112 @@
-use age::x25519::{Identity, Recipient};
-use bisky::lexicon::{
-    app::bsky::feed::Post,
-    com::atproto::repo::{Record, StrongRef},
-};
-use chrono::{TimeZone, Utc};
-use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use age::secrecy::Secret;
+use age::x25519::Identity;
+use chrono::{DateTime, Utc};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
 use ratatui::{
     backend::Backend,
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Modifier, Style},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
     text::{Span, Spans, Text},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph},
     Frame, Terminal,
 };
 use std::{
-    collections::{BTreeMap, HashMap},
-    str::FromStr,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    time::Duration,
 };
-use tokio::sync::mpsc::{error::TryRecvError, Receiver};
+use tokio::sync::mpsc::{Receiver, Sender};
 use unicode_width::UnicodeWidthStr;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 use crate::{
     conf::Settings,
     errors::BmailError,
-    key::{get_recipient_for_bskyer, decode, encode},
     message::{
-        insert_with_collisions, BmailEnabledProfile, BmailLike, Conversation, DecryptedMessage,
-        FirehoseMessages,
+        get_bmail_records_for_participant, insert_with_collisions, BmailEnabledProfile,
+        BmailMessageRecord, Conversation, DecryptedMessage, FirehoseMessages, GroupChange,
+        PAYLOAD_VERSION,
     },
+    autocomplete::{Autocomplete, Candidate},
+    editor::Editor,
+    keybindings::{Action, Keymap},
+    notify::Notifier,
+    keylog::{KeyLog, KeySource},
+    lock::unlock,
+    ratchet::{decrypt_bmail, RatchetStore, SharedRatchet},
+    store::{ConversationSettings, Group, KeyCheck, LocalStore},
+    time::{duration_label, TimeFormatter},
+    key::{fingerprint, safety_number, BmailIdentity},
+    session::{AppView, GroupRequest, LoadedConversation, ParticipantKey, SentBmail, Session},
+    tasks::{TaskManager, TaskOutcome},
+    theme::Theme,
+    wrap::wrap_text,
     SharableBluesky,
 };
 
+/// Marks the selected message in the message list
+const MESSAGE_HIGHLIGHT: &str = "> ";
+/// How tall the input box can grow before it scrolls
+const MAX_INPUT_LINES: usize = 6;
+/// The disappearing messages timers to cycle through, in seconds
+const DISAPPEARING_TIMERS: [Option<u64>; 5] = [
+    None,
+    Some(5 * 60),
+    Some(60 * 60),
+    Some(24 * 60 * 60),
+    Some(7 * 24 * 60 * 60),
+];
+/// How long to wait before trying to delete expired records again after it failed
+const DELETE_RETRY_MINUTES: i64 = 5;
+
 pub enum InputMode {
     Normal,
     Editing,
     EditingRecipient,
     ScrollingMessages,
+    SelectingConversation,
+    VerifyingKeys,
+    /// Typing a group command, like `add alice.bsky.social`
+    EditingGroup,
+    /// Typing a title for the Conversation picked in the sidebar
+    EditingTitle,
+    /// Decrypted Bmails have been wiped until the lock passphrase is typed in
+    Locked,
+}
+
+/// A single entry in the Conversation sidebar
+pub struct ConversationSummary {
+    pub conversation_id: Uuid,
+    /// DIDs of everyone in the Conversation except us
+    pub others: Vec<String>,
+    /// Handles of everyone in the Conversation except us, or their DID if we don't know it yet
+    pub label: String,
+    pub last_message_at: Option<DateTime<Utc>>,
+    /// The start of the last message, for the sidebar. It's plaintext, so it's wiped once it's drawn
+    pub preview: Zeroizing<String>,
+    pub unread_count: usize,
+    pub muted: bool,
+    pub archived: bool,
 }
 
 /// App holds the state of the application
 pub struct App {
     /// Current value of the recipient box
     pub recipient: String,
-    /// Current value of the input box
-    pub input: String,
+    /// Message being composed in the input box
+    pub input: Editor,
     /// Current value of the status field
     pub status: String,
+    /// Whether the status is an error, so it can stand out
+    pub status_is_error: bool,
     /// Current input mode
     pub input_mode: InputMode,
     /// Bluesky object for API Calls
     pub bluesky: SharableBluesky,
     /// Identity for Decrypting DMs
-    pub identity: Identity,
+    pub identity: BmailIdentity,
     /// The currently active Conversation Id
     pub current_conversation_id: Option<Uuid>,
     /// Storage Medium for Conversations.
@@ -67,9 +121,68 @@ pub struct App {
     pub conf: Settings,
     /// The DID of the current user
     pub user_did: Option<String>,
+    /// Desktop and terminal bell notifications for new Bmails
+    pub notifier: Notifier,
+    /// Handles we've seen for DIDs, so we can show people instead of DIDs
+    pub known_handles: HashMap<String, String>,
+    /// Current state of the Conversation sidebar
+    pub conversation_list_state: ListState,
+    /// Network operations running in the background
+    pub tasks: TaskManager,
+    /// How many messages fit in the message list, for paging through them
+    pub message_page_size: usize,
+    /// Data kept on this computer between runs
+    pub store: LocalStore,
+    /// Every key we've seen for each DID
+    pub key_log: KeyLog,
+    /// The keys of all our devices, as other people see them. Used for safety numbers
+    pub own_key_set: Option<String>,
+    /// Forward secret sessions with other devices
+    pub ratchet: SharedRatchet,
+    /// Shows times in the configured timezone and formats
+    pub time: TimeFormatter,
+    /// What each key does
+    pub keymap: Keymap,
+    /// Colours and styles for the UI
+    pub theme: Theme,
+    /// Handle suggestions for the recipient box
+    pub autocomplete: Autocomplete,
+    /// The Bmail key each participant of a loaded Conversation has published, keyed by DID
+    pub participant_keys: HashMap<String, ParticipantKey>,
+    /// Current state of the key verification list
+    pub verify_list_state: ListState,
+    /// The lock passphrase as it's typed in
+    pub unlock_input: Zeroizing<String>,
+    /// When to try deleting expired records again, if it failed last time
+    pub delete_retry_at: Option<DateTime<Utc>>,
+    /// Current value of the group command box
+    pub group_command: String,
+    /// Current value of the title box, and the Conversation it's for
+    pub title_input: String,
+    pub renaming: Option<Uuid>,
+    /// Whether archived Conversations are in the sidebar
+    pub show_archived: bool,
+    /// Whether settings changed while they were being synced, so they need syncing again
+    pub settings_sync_pending: bool,
+    /// For lookups that don't need to be logged in
+    pub appview: AppView,
+    /// Notification Likes that couldn't be checked while locked, as who made them and for which Conversation
+    pub locked_likes: Vec<(String, Uuid)>,
 }
 
 impl App {
+    /// Get a Session to do network operations with, which can be moved into a background task
+    pub fn session(&self) -> Session {
+        Session {
+            bluesky: self.bluesky.clone(),
+            identity: self.identity.clone(),
+            conf: self.conf.clone(),
+            user_did: self.user_did.clone(),
+            ratchet: self.ratchet.clone(),
+            appview: self.appview.clone(),
+        }
+    }
+
     /// Initialize profile
     pub async fn initialize(&mut self) -> Result<(), BmailError> {
         //Get Profile and check for existence of key
@@ -84,695 +197,1875 @@ impl App {
             .await?
         };
 
-        if profile_record.value.bmail_pub_key.is_none() {
-            self.upload_bmail_recipient().await?;
+        let session = self.session();
+        if let (Some(did), Some(key_set)) = (self.user_did.clone(), profile_record.value.key_set()) {
+            self.log_key(&did, &key_set, KeySource::ProfileFetch);
         }
-        if profile_record.value.bmail_notification_uri.is_none() {
-            self.create_notification_post().await?;
+        // Each device publishes its own key, so this one needs adding if it's new. Its prekey goes with it
+        let prekey = match (self.conf.forward_secrecy.enabled, self.identity.x25519()) {
+            (true, Some(x25519)) => {
+                let mut ratchet = self.ratchet.lock().await;
+                let prekey = ratchet.current_prekey(x25519)?;
+                // The secret half has to be kept before the prekey is published
+                ratchet.save(&self.identity).await?;
+                Some(prekey)
+            }
+            (true, None) => {
+                self.set_error(
+                    "Forward secrecy needs an age key, so it's off with an SSH or plugin key"
+                        .to_string(),
+                );
+                None
+            }
+            (false, _) => None,
+        };
+        let own_key_set = session
+            .register_device(&self.conf.key.device_label(), prekey)
+            .await?;
+        self.own_key_set = Some(own_key_set);
+        // Recreate the notification post if it was never made, or if it has been deleted since
+        match session.notification_post_exists(&profile_record.value).await {
+            Ok(true) => (),
+            Ok(false) => session.create_notification_post().await?,
+            Err(e) => self.set_error(format!("Couldn't check your notification post: {}", e)),
         }
-        //TODO Get Conversation IDs and Recipient Lists from Profile
 
-        Ok(())
-    }
-    /// Load a conversation. If there is a conversation with the recipients in memory, display messages. If there isn't one,
-    /// check the profile self storage for a conversation that matches. If that fails, create a new conversation and upload it
-    /// to the profile storage. This takes handles from the UI, so they'll be parsed into DIDs
-    pub async fn load_conversation(
-        &mut self,
-        mut recipients: Vec<String>,
-    ) -> Result<Uuid, BmailError> {
-        // 0. Add myself to the participants
-        recipients.push(self.conf.user.handle.clone());
-        //println!("RECIPIENTS: {:?}", recipients);
-
-        // 1. Get DIDs for recipients
-        let participant_dids = {
-            let mut dids: Vec<String> = Vec::with_capacity(recipients.len());
-            let mut bsky = self.bluesky.0.write().await;
-            let mut user = bsky.user(&self.conf.user.handle)?;
-            for recipient in &recipients {
-                let recipient_did = user.resolve_handle(recipient).await?;
-                dids.push(recipient_did);
+        // Get Conversation IDs and Recipient Lists from Profile, so the sidebar is filled in from the start
+        let rc_map = session
+            .get_rc_map_from_profile(&self.conf.user.handle)
+            .await?;
+        if let Some(rc_map) = rc_map {
+            let mut dids: Vec<String> = Vec::new();
+            for (participants, c_id) in rc_map.into_iter() {
+                dids.extend(participants.iter().cloned());
+                self.recipients_conversation_map
+                    .insert(participants.clone(), c_id);
+                self.conversations
+                    .entry(c_id)
+                    .or_insert_with(|| Conversation {
+                        conversation_id: c_id,
+                        participants,
+                        ..Default::default()
+                    });
             }
             dids.sort();
-            dids
-        };
-        //println!("DIDS: {:?}", participant_dids);
-
-        // 2. Check if all DIDs are present in Conversation Storage as a key
-        let mut conversation_id = None;
-        for conversation in self.recipients_conversation_map.iter() {
-            // We need the Vec as the HashMap Key to be sorted
-            match conversation.0 == &participant_dids
-            {
-                true => {
-                    conversation_id = Some(*conversation.1);
-                    break;
-                }
-                false => continue,
-            };
+            dids.dedup();
+            self.resolve_handles(&dids).await;
         }
-        // 3. If conversation_id exists, set currently active conversation ID.
-        if let Some(active_conversation_id) = conversation_id {
-            self.current_conversation_id = Some(active_conversation_id);
+        // Groups aren't in the map, since they're found by their ID rather than who's in them
+        let groups: Vec<(Uuid, Group)> = self
+            .store
+            .data()
+            .groups
+            .iter()
+            .map(|(c_id, group)| (*c_id, group.clone()))
+            .collect();
+        let mut dids: Vec<String> = Vec::new();
+        for (c_id, group) in groups {
+            dids.extend(group.members.iter().cloned());
+            self.conversations.insert(
+                c_id,
+                Conversation {
+                    conversation_id: c_id,
+                    participants: group.members,
+                    ..Default::default()
+                },
+            );
         }
-        // Else if, check if present in profile storage.
-        else if let Ok(Some(c_id)) = self
-            .get_cid_from_rc_map_from_profile(participant_dids.clone())
-            .await
-        {
-            //println!("MY PROFILE");
-            self.current_conversation_id = Some(c_id);
+        dids.sort();
+        dids.dedup();
+        self.resolve_handles(&dids).await;
+        // Pick up titles, mutes and archives from our other devices, and share ours with them
+        self.sync_settings();
 
-            self.recipients_conversation_map
-            .insert(participant_dids.clone(), c_id);
-        // Create a new conversation
-        let conversation = Conversation {
-            conversation_id: c_id,
-            messages: BTreeMap::default(),
-            recipient_active_time: HashMap::default(),
-            participants: participant_dids.clone(),
-        };
-        self.conversations
-            .insert(c_id, conversation);
+        Ok(())
+    }
 
-        self.upload_rc_map_to_profile(participant_dids.clone(), c_id)
-            .await?;
+    /// Look up the handles for DIDs we don't know yet. If we can't find one, we just show the DID
+    pub async fn resolve_handles(&mut self, dids: &[String]) {
+        let unknown: Vec<String> = dids
+            .iter()
+            .filter(|did| !self.known_handles.contains_key(*did))
+            .cloned()
+            .collect();
+        let handles = self.session().resolve_handles(&unknown).await;
+        self.known_handles.extend(handles);
+    }
 
-        // Check in participant profiles if a conversation exists. If it does, add it to our local storage
-        } else if let Ok(Some(c_id)) = self.get_cid_from_rc_map_from_participants_profiles(participant_dids.clone()).await{
-            //println!("THEIR PROFILE: {c_id}");
-            self.current_conversation_id = Some(c_id);
+    /// Fetch the most recent messages for every Conversation we know about in the background. They come back
+    /// through the same channel as the Firehose, so the UI can pick them up as they arrive
+    pub fn spawn_startup_sync(&self, tx: Sender<FirehoseMessages>) {
+        let bsky = self.bluesky.clone();
+        let handle = self.conf.user.handle.clone();
+        let recent_messages = self.conf.sync.recent_messages;
+        let conversations: Vec<(Uuid, Vec<String>)> = self
+            .conversations
+            .values()
+            .map(|c| match self.store.group(&c.conversation_id) {
+                Some(group) => (c.conversation_id, group.everyone()),
+                None => (c.conversation_id, c.participants.clone()),
+            })
+            .collect();
+
+        tokio::spawn(async move {
+            for (c_id, participants) in conversations {
+                let mut records = Vec::new();
+                for participant in participants.iter() {
+                    // One participant being unreachable shouldn't stop the rest of the sync
+                    if let Ok(r) =
+                        get_bmail_records_for_participant(bsky.clone(), &handle, participant, &c_id)
+                            .await
+                    {
+                        records.extend(r);
+                    }
+                }
+                records.sort_by(|a, b| b.bmail_created_at.cmp(&a.bmail_created_at));
+                for record in records.into_iter().take(recent_messages) {
+                    if tx.send(FirehoseMessages::SyncedBmail(record)).await.is_err() {
+                        // The UI has gone away
+                        return;
+                    }
+                }
+            }
+        });
+    }
 
-            self.recipients_conversation_map
-                .insert(participant_dids.clone(), c_id);
-            // Create a new conversation
-            let new_conversation = Conversation {
-                conversation_id: c_id,
-                messages: BTreeMap::default(),
-                recipient_active_time: HashMap::default(),
-                participants: participant_dids.clone(),
-            };
-            self.conversations
-                .insert(c_id, new_conversation);
+    /// Load a conversation in the background. See [Session::load_conversation]
+    pub fn load_conversation(&mut self, recipients: Vec<String>) {
+        let session = self.session();
+        let known_conversations = self.recipients_conversation_map.clone();
+        let sender_keys = self.all_sender_keys();
+        self.tasks.spawn("Loading conversation", async move {
+            TaskOutcome::ConversationLoaded(
+                session
+                    .load_conversation(recipients, known_conversations, sender_keys)
+                    .await,
+            )
+        });
+    }
 
-            self.upload_rc_map_to_profile(participant_dids.clone(), c_id)
-                .await?;
+    /// Load a group in the background. See [Session::load_group]
+    pub fn load_group(&mut self, conversation_id: Uuid) {
+        let Some(group) = self.store.group(&conversation_id).cloned() else {
+            return;
+        };
+        let session = self.session();
+        let sender_keys = self.all_sender_keys();
+        self.tasks.spawn("Loading group", async move {
+            TaskOutcome::ConversationLoaded(
+                session
+                    .load_group(conversation_id, group, sender_keys)
+                    .await,
+            )
+        });
+    }
 
+    /// Load a Conversation from the sidebar, whether it's a group or not
+    pub fn open_conversation(&mut self, conversation_id: Uuid, others: Vec<String>) {
+        match self.store.group(&conversation_id) {
+            Some(_) => self.load_group(conversation_id),
+            None => self.load_conversation(others),
         }
-        // Else create a new one
-        else {
-            //println!("NEW");
-            let new_conversation_id = Uuid::new_v4();
+    }
 
-            self.current_conversation_id = Some(new_conversation_id);
+    /// Make a Conversation loaded in the background the active one
+    pub fn apply_loaded_conversation(&mut self, loaded: LoadedConversation) -> Uuid {
+        let LoadedConversation {
+            conversation: loaded,
+            handles,
+            keys,
+            ..
+        } = loaded;
+        let c_id = loaded.conversation_id;
+        self.known_handles.extend(handles);
+        self.apply_participant_keys(keys);
+        if self.store.group(&c_id).is_none() {
             self.recipients_conversation_map
-                .insert(participant_dids.clone(), new_conversation_id);
-            // Create a new conversation
-            let new_conversation = Conversation {
-                conversation_id: new_conversation_id,
-                messages: BTreeMap::default(),
-                recipient_active_time: HashMap::default(),
-                participants: participant_dids.clone(),
-            };
-            self.conversations
-                .insert(new_conversation_id, new_conversation);
-
-            self.upload_rc_map_to_profile(participant_dids.clone(), new_conversation_id)
-                .await?;
+                .insert(loaded.participants.clone(), c_id);
         }
 
-        // 4. Get all Conversation Records with that Conversation ID from each participant
-        // 4.1 Check latest on server vs latest in memory(last entry)
-        // 4.2 If server has newer messages, add missing to memory conversation
-        if let Some(c_id) = self.current_conversation_id {
-            // Create the conversation entry to update if it does not exist
-            self.conversations
-                .entry(c_id)
-                .or_insert_with(|| Conversation {
-                    conversation_id: c_id,
-                    participants: participant_dids.clone(),
-                    ..Default::default()
-                });
-            // This should always be true, because we create it above
-            if let Some(conversation) = self.conversations.get_mut(&c_id) {
-                conversation
-                    .update_with_messages_from_participants(
-                        self.bluesky.clone(),
-                        &self.conf.user.handle,
-                        &self.identity,
-                        participant_dids,
-                    )
-                    .await?;
+        let now = Utc::now();
+        for msg in loaded.messages.values() {
+            self.log_gossip(msg);
+            self.note_disappearing_timer(msg);
+            self.apply_group_update(msg);
+        }
+        let everyone = self.store.group(&c_id).map(|g| g.everyone());
+        let conversation = self
+            .conversations
+            .entry(c_id)
+            .or_insert_with(|| Conversation {
+                conversation_id: c_id,
+                participants: loaded.participants.clone(),
+                ..Default::default()
+            });
+        // The messages are moved in rather than copied, so there's one less copy of the plaintext around
+        for msg in loaded.messages.into_values() {
+            self.known_handles
+                .insert(msg.creator.clone(), msg.creator_handle.clone());
+            let outsider = matches!(&everyone, Some(everyone) if !everyone.contains(&msg.creator));
+            if !msg.is_expired(&now) && !outsider {
+                insert_with_collisions(&mut conversation.messages, msg);
+            }
+        }
+        // We're looking at it now, so nothing is unread
+        conversation.unread_count = 0;
+
+        self.switch_conversation(c_id);
+        // Set Conversation state of conversation
+        self.conversation_state = ListState::default();
+        // Loading may have reordered the sidebar, so keep it on this Conversation
+        let i = self
+            .conversation_list()
+            .iter()
+            .position(|c| c.conversation_id == c_id);
+        self.conversation_list_state.select(i);
+        c_id
+    }
 
-                // Set Conversation state of conversation
-                self.conversation_state = ListState::default();
+    /// Make a Conversation the active one, putting the draft for the old one aside and bringing back the
+    /// draft for the new one
+    fn switch_conversation(&mut self, conversation_id: Uuid) {
+        if self.current_conversation_id == Some(conversation_id) {
+            return;
+        }
+        let draft = self.store.draft(&conversation_id).map(str::to_string);
+        match self.current_conversation_id {
+            Some(old) => {
+                self.store.set_draft(old, self.input.text());
+                self.input.set_text(draft.unwrap_or_default());
+            }
+            // Anything typed before there was a Conversation belongs to this one, unless it has its own draft
+            None => {
+                if let Some(draft) = draft {
+                    self.input.set_text(draft);
+                }
             }
         }
+        self.current_conversation_id = Some(conversation_id);
+    }
 
-        Ok(self.current_conversation_id.unwrap())
+    /// Save the local store if anything in it has changed, including the draft being written now
+    pub async fn save_store(&mut self) -> Result<(), BmailError> {
+        if let Some(c_id) = self.current_conversation_id {
+            self.store.set_draft(c_id, self.input.text());
+        }
+        self.store.save(&self.identity).await?;
+        self.ratchet.lock().await.save(&self.identity).await
     }
 
-    /// We're storing a HashMap of recipients in a Conversation to conversation IDs in the profile(encrypted)
-    /// so that multiple clients can fetch them, and so that we can recover them when the app is restarted.
-    /// Get that Hashmap
-    pub async fn get_rc_map_from_profile(
-        &mut self,
-        handle: &str,
-    ) -> Result<Option<HashMap<Vec<String>, Uuid>>, BmailError> {
-        let mut bsky = self.bluesky.0.write().await;
-        let mut user = bsky.user(&self.conf.user.handle)?;
-        let profile_record = user
-            .get_record::<BmailEnabledProfile>(
-                handle,
-                "app.bsky.actor.profile",
-                "self",
-            )
-            .await?;
+    /// Wipe decrypted Bmails and drafts from memory until the lock passphrase is typed in. The store and
+    /// ratchet state are saved and dropped in the background, and anything else running is cancelled
+    pub fn lock(&mut self) {
+        if !self.conf.lock.passphrase_path.exists() {
+            self.set_error("Set a lock passphrase first, with bmail lock set-passphrase".to_string());
+            return;
+        }
+        self.tasks.cancel_all();
+        if let Some(c_id) = self.current_conversation_id {
+            self.store.set_draft(c_id, self.input.text());
+        }
+        self.input.wipe();
+        for conversation in self.conversations.values_mut() {
+            conversation.messages.clear();
+            // So everything is fetched again when it's loaded
+            conversation.recipient_active_time.clear();
+        }
+        self.conversation_state = ListState::default();
+
+        let mut store = std::mem::take(&mut self.store);
+        let identity = self.identity.clone();
+        let ratchet = self.ratchet.clone();
+        self.tasks.spawn("Locking", async move {
+            let saved = store.save(&identity).await;
+            let mut ratchet = ratchet.lock().await;
+            let saved = saved.and(ratchet.save(&identity).await);
+            *ratchet = RatchetStore::default();
+            TaskOutcome::Locked(saved)
+        });
+        // Room up front, so typing doesn't leave copies of the passphrase behind in reallocated memory
+        self.unlock_input = Zeroizing::new(String::with_capacity(256));
+        self.input_mode = InputMode::Locked;
+        self.set_status("Locked".to_string());
+    }
 
-        if let Some(r_map) = profile_record.value.bmail_rc_map {
-             let decoded = decode(&r_map).await?;
-            Ok(Some(decoded))
-        } else {
-            Ok(None)
+    /// Check the passphrase typed in on the lock screen in the background
+    fn unlock(&mut self) {
+        // Unlocking before the lock has finished saving would open the store without the latest drafts
+        if !self.tasks.is_empty() {
+            self.set_status("Still locking, try again in a moment".to_string());
+            return;
         }
+        let passphrase = Secret::new(std::mem::take(&mut *self.unlock_input));
+        let conf = self.conf.clone();
+        let identity = self.identity.clone();
+        let ratchet = self.ratchet.clone();
+        self.tasks.spawn("Unlocking", async move {
+            TaskOutcome::Unlocked(unlock(&conf, &identity, &ratchet, passphrase).await)
+        });
     }
 
-    /// We're storing a HashMap of recipients in a Conversation to conversation IDs in the profile(encrypted)
-    /// so that multiple clients can fetch them, and so that we can recover them when the app is restarted.
-    /// Get that Hashmap
-    pub async fn get_cid_from_rc_map_from_profile(
-        &mut self,
-        participants: Vec<String>,
-    ) -> Result<Option<Uuid>, BmailError> {
-        let handle = self.conf.user.handle.clone();
-        let profile_rc_map = self.get_rc_map_from_profile(&handle).await?;
-        if let Some(rc_map) = &profile_rc_map {
-            let mut conversation_id = None;
-            for key in rc_map.keys() {
-                match key == &participants{
-                // match key.iter().all(|item| participants.contains(item)) && participants.len() == keys.len() {
-                    true => {
-                        let c_id = rc_map.get(key).unwrap();
-                        //println!("K P: {:?}||{:?}||{:?}", key, participants, c_id);
-                        conversation_id = Some(*c_id);
-                        break;
-                    }
-                    false => continue,
-                };
+    /// Pick up the disappearing messages timer a Bmail was sent with, if it's newer than the one we have
+    fn note_disappearing_timer(&mut self, msg: &DecryptedMessage) {
+        // Older versions don't know about timers, so their Bmails don't turn one off
+        if msg.version < PAYLOAD_VERSION {
+            return;
+        }
+        if !self
+            .store
+            .set_disappearing_timer(msg.conversation_id, msg.disappear_after, msg.created_at)
+        {
+            return;
+        }
+        let who = self.who(&msg.creator);
+        let label = self.conversation_label(&msg.conversation_id);
+        match msg.disappear_after {
+            Some(seconds) => self.set_status(format!(
+                "{} set Bmails with {} to disappear after {}",
+                who,
+                label,
+                duration_label(seconds)
+            )),
+            None => {
+                self.set_status(format!("{} turned off disappearing Bmails with {}", who, label))
             }
-            Ok(conversation_id)
-        } else {
-            Ok(None)
         }
     }
 
-    /// Check for a conversation ID in each participants profiles, so that if they make a conversation with me, and I try to make one later,
-    /// it will find their ID and use it for my local conversation
-    pub async fn get_cid_from_rc_map_from_participants_profiles(
-        &mut self,
-        participants: Vec<String>,
-    ) -> Result<Option<Uuid>, BmailError> {
-
-
-        for participant in participants.iter(){
-            if let Some(user_did) = &self.user_did{
-                // Skip if it's me
-                if participant == user_did{
-                    continue
-                }
-            }
-            
-            let profile_rc_map = self.get_rc_map_from_profile(participant).await?;
-            //println!("profile_rc_map: {:?}", profile_rc_map);
-            if let Some(rc_map) = &profile_rc_map {
-                for key in rc_map.keys() {
-                    // Need to check length because we might have one vector contain all of another
-                    match key == &participants {
-                        true => {
-                            let c_id = rc_map.get(key).unwrap();
-                            //println!("FOUND KEYS: {:#?}", keys);
-                            return Ok(Some(*c_id));
-                        }
-                        false => continue,
-                    };
-                }
-            }
+    /// Apply a group change sent in a Bmail, if it's newer than the one we have and came from someone in the
+    /// group. The Conversation's participants follow the group's members
+    fn apply_group_update(&mut self, msg: &DecryptedMessage) {
+        let Some(update) = &msg.group else {
+            return;
+        };
+        let c_id = msg.conversation_id;
+        let user_did = self.user_did.as_deref();
+        if !self
+            .store
+            .apply_group_update(c_id, &msg.creator, update, msg.created_at, user_did)
+        {
+            return;
+        }
+        let members = self
+            .store
+            .group(&c_id)
+            .map(|g| g.members.clone())
+            .unwrap_or_default();
+        self.conversations
+            .entry(c_id)
+            .or_insert_with(|| Conversation {
+                conversation_id: c_id,
+                ..Default::default()
+            })
+            .participants = members;
+        // Groups are found by their ID, not who's in them, so a Like may have put it in the map
+        self.recipients_conversation_map.retain(|_, id| *id != c_id);
+        if let Some(description) = self.describe_group_update(msg) {
+            self.set_status(description);
+        }
     }
-    Ok(None)
-}
 
-    /// Get the current rc_map from profile and add a new value to it
-    pub async fn upload_rc_map_to_profile(
-        &mut self,
-        key: Vec<String>,
-        value: Uuid,
-    ) -> Result<(), BmailError> {
-        let handle = self.conf.user.handle.clone();
+    /// Get "You" for us, or the handle of someone else
+    fn who(&self, did: &str) -> String {
+        match self.user_did.as_deref() == Some(did) {
+            true => "You".to_string(),
+            false => self.handle_for(did),
+        }
+    }
 
-        let mut profile_record = {
-            let mut bsky = self.bluesky.0.write().await;
-            let mut user = bsky.user(&handle)?;
-            user.get_record::<BmailEnabledProfile>(&handle, "app.bsky.actor.profile", "self").await?
+    /// Describe a group change, like "alice.bsky.social added bob.bsky.social to Friends"
+    pub fn describe_group_update(&self, msg: &DecryptedMessage) -> Option<String> {
+        let update = msg.group.as_ref()?;
+        let who = self.who(&msg.creator);
+        let names = |dids: &[String]| {
+            dids.iter()
+                .map(|did| self.handle_for(did))
+                .collect::<Vec<String>>()
+                .join(", ")
         };
-
-        match &mut profile_record.value.bmail_rc_map {
-            Some(m) => {      
-                let mut rc_map: HashMap<Vec<String>, Uuid> = decode(m).await?; 
-                rc_map.insert(key, value);
-                let encoded = encode(rc_map).await?;
-                profile_record.value.bmail_rc_map = Some(encoded);     
-            }
-            None => {
-                let mut new_rc_map = HashMap::new();
-                new_rc_map.insert(key, value);
-                let encoded_map = encode(new_rc_map).await?;
-                profile_record.value.bmail_rc_map = Some(encoded_map);
+        Some(match &update.change {
+            GroupChange::Created => format!("{} made the group {}", who, update.name),
+            GroupChange::Renamed => format!("{} renamed the group to {}", who, update.name),
+            GroupChange::Added(dids) => format!("{} added {} to {}", who, names(dids), update.name),
+            GroupChange::Removed(dids) => {
+                format!("{} removed {} from {}", who, names(dids), update.name)
             }
-        };
+            GroupChange::Left => format!("{} left {}", who, update.name),
+        })
+    }
 
-        let mut bsky = self.bluesky.0.write().await;
-        let mut me = bsky.me()?;
-        me.put_record(
-            "app.bsky.actor.profile",
-            "self",
-            None,
-            None,
-            Some(&profile_record.cid),
-            &profile_record.value,
-        )
-        .await?;
+    /// Get the text to show for a Bmail, which for a group change is a description of it
+    pub fn message_text<'a>(&self, msg: &'a DecryptedMessage) -> Cow<'a, str> {
+        match self.describe_group_update(msg) {
+            Some(description) => Cow::Owned(description),
+            None => Cow::Borrowed(msg.message.as_str()),
+        }
+    }
 
-        Ok(())
+    /// Only the start of a message's first line, since sidebar space is precious. It's made at its full size
+    /// up front, so growing it doesn't leave copies of the plaintext behind
+    fn message_preview(&self, msg: &DecryptedMessage) -> Zeroizing<String> {
+        let text = self.message_text(msg);
+        let first_line = text.lines().next().unwrap_or_default();
+        let mut preview = Zeroizing::new(String::with_capacity(first_line.len() + '…'.len_utf8()));
+        preview.extend(first_line.chars().take(24));
+        if preview.len() < text.len() {
+            preview.push('…');
+        }
+        preview
     }
 
-   /// Get the current rc_map from profile and add a new value to it
-   pub async fn delete_rc_map_from_profile(
-    &mut self,
-) -> Result<(), BmailError> {
-    let handle = self.conf.user.handle.clone();
+    /// Run what's typed in the group box. `create <name>` makes a group with whoever is in the recipient box,
+    /// and `rename <name>`, `add <handles>`, `remove <handles>` and `leave` change the current one
+    pub fn run_group_command(&mut self) {
+        let command = self.group_command.trim();
+        let (verb, rest) = command.split_once(' ').unwrap_or((command, ""));
+        let handles = |s: &str| -> Vec<String> {
+            s.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|h| !h.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let request = match verb {
+            "create" => GroupRequest::Create {
+                name: rest.trim().to_string(),
+                members: handles(&self.recipient),
+            },
+            "rename" => GroupRequest::Rename(rest.trim().to_string()),
+            "add" => GroupRequest::Add(handles(rest)),
+            "remove" => GroupRequest::Remove(handles(rest)),
+            "leave" => GroupRequest::Leave,
+            _ => {
+                self.set_error(
+                    "Group commands are create <name>, rename <name>, add <handles>, \
+                     remove <handles> and leave"
+                        .to_string(),
+                );
+                return;
+            }
+        };
+        let current = match (&request, self.current_conversation_id) {
+            (GroupRequest::Create { .. }, _) => None,
+            (_, Some(c_id)) => self.store.group(&c_id).cloned().map(|group| (c_id, group)),
+            (_, None) => None,
+        };
+        let session = self.session();
+        let pinned_keys = self.store.pinned_keys();
+        let disappear_after = current
+            .as_ref()
+            .and_then(|(c_id, _)| self.store.disappearing_timer(c_id));
+        self.tasks.spawn("Updating group", async move {
+            TaskOutcome::GroupUpdated(
+                session
+                    .update_group(current, request, pinned_keys, disappear_after)
+                    .await,
+            )
+        });
+        self.group_command.clear();
+        self.input_mode = InputMode::Normal;
+    }
 
-    let mut profile_record = {
-        let mut bsky = self.bluesky.0.write().await;
-        let mut user = bsky.user(&handle)?;
-        user.get_record::<BmailEnabledProfile>(&handle, "app.bsky.actor.profile", "self").await?
-    };
+    /// Get the Conversation picked in the sidebar
+    pub fn selected_conversation(&self) -> Option<ConversationSummary> {
+        self.conversation_list_state
+            .selected()
+            .and_then(|i| self.conversation_list().into_iter().nth(i))
+    }
 
+    /// Whether new Bmails in a Conversation shouldn't notify
+    pub fn is_muted(&self, conversation_id: &Uuid) -> bool {
+        matches!(self.store.conversation_settings(conversation_id), Some(s) if s.muted)
+    }
 
-    profile_record.value.bmail_rc_map = None;     
-    
+    /// Change what we've set for a Conversation, and sync it to our other devices
+    pub fn change_conversation_settings(
+        &mut self,
+        conversation_id: Uuid,
+        change: impl FnOnce(&mut ConversationSettings),
+    ) {
+        self.store
+            .update_conversation_settings(conversation_id, change);
+        self.sync_settings();
+    }
 
-    let mut bsky = self.bluesky.0.write().await;
-    let mut me = bsky.me()?;
-    me.put_record(
-        "app.bsky.actor.profile",
-        "self",
-        None,
-        None,
-        Some(&profile_record.cid),
-        &profile_record.value,
-    )
-    .await?;
+    /// Sync Conversation settings with our other devices in the background. If a sync is already running,
+    /// another one runs after it, so nothing set in the meantime is missed
+    pub fn sync_settings(&mut self) {
+        if self.tasks.is_running("Syncing settings") {
+            self.settings_sync_pending = true;
+            return;
+        }
+        let session = self.session();
+        let local = self.store.synced_settings().clone();
+        self.tasks.spawn("Syncing settings", async move {
+            TaskOutcome::SettingsSynced(session.sync_settings(local).await)
+        });
+    }
 
-    Ok(())
-}
-    
+    /// Save the title typed in for the Conversation being renamed. An empty title goes back to showing
+    /// who's in it
+    pub fn save_title(&mut self) {
+        let title = std::mem::take(&mut self.title_input);
+        self.input_mode = InputMode::SelectingConversation;
+        let Some(c_id) = self.renaming.take() else {
+            return;
+        };
+        let title = title.trim().to_string();
+        let status = match title.is_empty() {
+            true => "Cleared the title".to_string(),
+            false => format!("Renamed to {}", title),
+        };
+        self.change_conversation_settings(c_id, |s| {
+            s.title = (!title.is_empty()).then_some(title)
+        });
+        self.set_status(status);
+    }
 
-    /// Scrape the recipient's Profile for their Public Key so we can encrypt this thing
-    pub async fn get_recipient_for_bskyer(
-        &mut self,
-        handle: &str,
-    ) -> Result<(Option<Recipient>, Record<BmailEnabledProfile>), BmailError> {
-        let mut bsky = self.bluesky.0.write().await;
-        let mut user = bsky.user(&self.conf.user.handle)?;
+    /// Mute or archive the Conversation picked in the sidebar, or bring it back
+    pub fn toggle_selected(&mut self, archive: bool) {
+        let Some(summary) = self.selected_conversation() else {
+            return;
+        };
+        let on = match archive {
+            true => !summary.archived,
+            false => !summary.muted,
+        };
+        self.change_conversation_settings(summary.conversation_id, |s| match archive {
+            true => s.archived = on,
+            false => s.muted = on,
+        });
+        let status = match (archive, on) {
+            (true, true) => "Archived",
+            (true, false) => "Unarchived",
+            (false, true) => "Muted",
+            (false, false) => "Unmuted",
+        };
+        self.set_status(format!("{} {}", status, summary.label));
+        // Archiving takes it out of the sidebar, so keep the selection in range
+        let count = self.conversation_list().len();
+        if let Some(i) = self.conversation_list_state.selected() {
+            self.conversation_list_state.select(match count {
+                0 => None,
+                n => Some(i.min(n - 1)),
+            });
+        }
+    }
 
-        let profile_record = user
-            .get_record::<BmailEnabledProfile>(handle, "app.bsky.actor.profile", "self")
-            .await?;
-        let recipient = match &profile_record.value.bmail_pub_key {
-            Some(k) => Some(Recipient::from_str(k).map_err(|_| BmailError::ParseRecipientError)?),
-            None => None,
+    /// Move the current Conversation on to the next disappearing messages timer. It's sent along with every
+    /// Bmail, so everyone else picks it up with the next one
+    pub fn cycle_disappearing_timer(&mut self) {
+        let Some(c_id) = self.current_conversation_id else {
+            self.set_error("Load a conversation to set its disappearing messages timer".to_string());
+            return;
         };
-        Ok((recipient, profile_record))
+        let current = self.store.disappearing_timer(&c_id);
+        let i = DISAPPEARING_TIMERS
+            .iter()
+            .position(|t| *t == current)
+            .unwrap_or(0);
+        let next = DISAPPEARING_TIMERS[(i + 1) % DISAPPEARING_TIMERS.len()];
+        self.store.set_disappearing_timer(c_id, next, Utc::now());
+        match next {
+            // Turning it on is when people need to know what it can't do
+            Some(seconds) if current.is_none() => self.set_error(format!(
+                "New Bmails disappear after {}. Deleting isn't guaranteed: the record can linger on \
+                 servers, and anyone who fetched it can keep a copy",
+                duration_label(seconds)
+            )),
+            Some(seconds) => {
+                self.set_status(format!("New Bmails disappear after {}", duration_label(seconds)))
+            }
+            None => self.set_status("Disappearing Bmails are off".to_string()),
+        }
     }
 
-    /// Create an extremely old post that can be liked to indicate you have a new Bmail
-    pub async fn create_notification_post(&mut self) -> Result<(), BmailError> {
-        let handle = &self.conf.user.handle.clone();
+    /// Drop Bmails that have disappeared from memory and from the ratchet state, and start deleting our own
+    /// expired records in the background
+    pub async fn expire_messages(&mut self) {
+        let now = Utc::now();
+        for conversation in self.conversations.values_mut() {
+            conversation.messages.retain(|_, m| !m.is_expired(&now));
+        }
+        self.ratchet.lock().await.forget_expired(&now);
 
-        let notif_post = {
-            let mut bsky = self.bluesky.0.write().await;
-            let mut me = bsky.me()?;
-            me.create_record(
-                "app.bsky.feed.post",
-                None,
-                None,
-                None,
-                Post {
-                    rust_type: Some("app.bsky.feed.post".to_string()),
-                    text: "You've got Bmail".to_string(),
-                    created_at: Utc.with_ymd_and_hms(1970, 1, 1, 0, 0, 0).unwrap(),
-                    embed: None,
-                    reply: None,
-                },
-            )
-            .await
-            .unwrap()
-        }; //Get existing Record so we can only change one thing
+        if self.tasks.is_running("Deleting expired Bmails")
+            || matches!(self.delete_retry_at, Some(retry_at) if retry_at > now)
+        {
+            return;
+        }
+        let expired = self.store.expired_records(&now);
+        if expired.is_empty() {
+            return;
+        }
+        let session = self.session();
+        self.tasks.spawn("Deleting expired Bmails", async move {
+            let mut deleted = Vec::new();
+            for uri in expired {
+                if let Err(e) = session.delete_bmail_record(&uri).await {
+                    return TaskOutcome::ExpiredRecordsDeleted {
+                        deleted,
+                        result: Err(e),
+                    };
+                }
+                deleted.push(uri);
+            }
+            TaskOutcome::ExpiredRecordsDeleted {
+                deleted,
+                result: Ok(()),
+            }
+        });
+    }
 
-        let (_recipient, mut profile_record) = match self.get_recipient_for_bskyer(handle).await {
-            Ok(r) => r,
-            Err(_) => return Err(BmailError::InternalServerError),
-        };
+    /// Decrypt a Bmail that came in. One that can't be decrypted is reported, and the rest carry on
+    async fn decrypt_firehose_bmail(
+        &mut self,
+        record: &BmailMessageRecord,
+    ) -> Option<DecryptedMessage> {
+        let sender_keys = self.sender_keys(&record.bmail_creator);
+        let result =
+            decrypt_bmail(record, &self.identity, &self.ratchet, sender_keys.as_deref()).await;
+        match result {
+            Ok(msg) => Some(msg),
+            Err(BmailError::KeyChanged(did)) => {
+                self.warn_about_held_back_bmail(vec![did]);
+                None
+            }
+            Err(e) => {
+                self.set_error(format!(
+                    "Couldn't decrypt a Bmail from {}: {}",
+                    self.handle_for(&record.bmail_creator),
+                    e
+                ));
+                None
+            }
+        }
+    }
 
-        profile_record.value.bmail_notification_uri = Some(notif_post.uri);
-        profile_record.value.bmail_notification_cid = Some(notif_post.cid);
+    /// Get the keys a Bmail from someone should have come from: theirs if they're pinned, or ours for our own
+    fn sender_keys(&self, did: &str) -> Option<String> {
+        match self.user_did.as_deref() == Some(did) {
+            true => self.own_key_set.clone(),
+            false => self.store.pinned_keys().get(did).cloned(),
+        }
+    }
 
-        let mut bsky = self.bluesky.0.write().await;
-        bsky.me()?
-            .put_record(
-                "app.bsky.actor.profile",
-                "self",
-                None,
-                None,
-                Some(&profile_record.cid),
-                &profile_record.value,
-            )
-            .await?;
+    /// [App::sender_keys] for everyone we know the keys for, to check Bmails fetched in the background
+    fn all_sender_keys(&self) -> HashMap<String, String> {
+        let mut keys = self.store.pinned_keys();
+        if let (Some(did), Some(key_set)) = (&self.user_did, &self.own_key_set) {
+            keys.insert(did.clone(), key_set.clone());
+        }
+        keys
+    }
 
-        Ok(())
+    /// Say that Bmails were left out because they came from a key that isn't pinned. Returns whether there
+    /// were any
+    fn warn_about_held_back_bmail(&mut self, dids: Vec<String>) -> bool {
+        if dids.is_empty() {
+            return false;
+        }
+        let handles: Vec<String> = dids.iter().map(|did| self.handle_for(did)).collect();
+        // Find out what the new keys are, so they can be compared
+        self.refresh_participant_keys(dids);
+        self.set_error(format!(
+            "Not shown: the Bmail key for {} has changed since you first saw it!",
+            handles.join(", ")
+        ));
+        true
     }
 
-    /// Create a BmailEnabledProfile Profile Record to store your Recipient.
-    pub async fn upload_bmail_recipient(&mut self) -> Result<(), BmailError> {
-        let handle = &self.conf.user.handle.clone();
+    /// Take the keys participants have published. New keys are pinned, and keys that don't match the pinned
+    /// one are left alone so they can be checked
+    pub fn apply_participant_keys(&mut self, keys: HashMap<String, ParticipantKey>) {
+        self.autocomplete.set_bmail_capable(
+            keys.iter()
+                .filter(|(_, key)| !matches!(key, ParticipantKey::Unavailable(_)))
+                .map(|(did, key)| (did.clone(), !key.is_missing()))
+                .collect(),
+        );
+        for (did, key) in keys.iter() {
+            if let ParticipantKey::Published(key) = key {
+                self.log_key(did, key, KeySource::ProfileFetch);
+                if Some(did) != self.user_did.as_ref() {
+                    self.store.check_key(did, key);
+                }
+            }
+        }
+        self.participant_keys.extend(keys);
+    }
 
-        //Get existing Record so we can only change one thing
-        let (_recipient, mut profile_record) = match self.get_recipient_for_bskyer(handle).await {
-            Ok(r) => r,
-            Err(_) => return Err(BmailError::InternalServerError),
-        };
+    /// Add a key to the key log. Failing to write it shouldn't stop anything else, so it's only reported
+    pub fn log_key(&mut self, did: &str, key: &str, source: KeySource) {
+        if let Err(e) = self.key_log.record(did, key, source) {
+            self.set_error(format!("Failed to write to the key log: {}", e));
+        }
+    }
 
-        //Update pub key
-        profile_record.value.bmail_pub_key = Some(self.identity.to_public().to_string());
-
-        let mut bsky = self.bluesky.0.write().await;
-        let mut me = bsky.me()?;
-        me.put_record(
-            "app.bsky.actor.profile",
-            "self",
-            None,
-            None,
-            Some(&profile_record.cid),
-            &profile_record.value,
-        )
-        .await?;
+    /// Log the keys the sender of a Bmail encrypted it to, so the audit can compare them with what we were shown
+    pub fn log_gossip(&mut self, msg: &DecryptedMessage) {
+        if self.user_did.as_ref() == Some(&msg.creator) {
+            return;
+        }
+        for (did, key) in msg.recipient_keys.iter() {
+            self.log_key(did, key, KeySource::Gossip { from: msg.creator.clone() });
+        }
+    }
 
-        Ok(())
+    /// Take a key from a profile update on the Firehose. Only people we know about are logged, since every
+    /// Bmail user's profile updates go by
+    pub fn handle_published_key(&mut self, did: String, key: String) {
+        let known = self.user_did.as_ref() == Some(&did)
+            || self.store.pinned_key(&did).is_some()
+            || self.participant_keys.contains_key(&did);
+        if !known {
+            return;
+        }
+        self.log_key(&did, &key, KeySource::Firehose);
+        // Another of our devices may have changed settings, or been added and need them shared
+        if self.user_did.as_ref() == Some(&did) && !matches!(self.input_mode, InputMode::Locked) {
+            self.sync_settings();
+        }
+        if self.participant_keys.contains_key(&did) {
+            self.participant_keys
+                .insert(did.clone(), ParticipantKey::Published(key));
+            self.warn_about_changed_keys(&[did]);
+        }
     }
 
-    /// Notify Recipients they have a Bmail by liking their bmail notification post
-    pub async fn notify_recipients(
-        &mut self,
-        conversation_id: Uuid,
-        recipients: Vec<String>,
-    ) -> Result<(), BmailError> {
-        for recipient in recipients.iter() {
-            let (_recipient_key, profile_record) =
-                get_recipient_for_bskyer(self.bluesky.clone(), recipient).await?;
-            let mut bsky = self.bluesky.0.write().await;
-            let mut me = bsky.me().map_err::<BmailError, _>(Into::into)?;
-            me.create_record(
-                "app.bsky.feed.like",
-                None,
-                None,
-                None,
-                BmailLike {
-                    subject: StrongRef {
-                        cid: profile_record
-                            .value
-                            .bmail_notification_cid
-                            .ok_or_else(|| BmailError::MalformedBmail)?,
-                        uri: profile_record
-                            .value
-                            .bmail_notification_uri
-                            .ok_or_else(|| BmailError::MalformedBmail)?,
-                    },
-                    created_at: Utc::now(),
-                    bmail_recipients: recipients.clone(),
-                    bmail_conversation_id: conversation_id,
-                    bmail_type: "notification".to_string(),
-                },
+    /// Check participants' keys again in the background
+    pub fn refresh_participant_keys(&mut self, participant_dids: Vec<String>) {
+        let session = self.session();
+        self.tasks.spawn("Checking keys", async move {
+            TaskOutcome::ParticipantKeysChecked(
+                session.check_participant_keys(&participant_dids).await,
             )
-            .await?;
+        });
+    }
+
+    /// Get the handles of participants whose published key doesn't match the one we pinned for them
+    pub fn changed_keys(&self, participants: &[String]) -> Vec<String> {
+        participants
+            .iter()
+            .filter(|did| self.key_check(did) == Some(KeyCheck::Changed))
+            .map(|did| self.handle_for(did))
+            .collect()
+    }
+
+    /// Compare the key a participant has published with the one we pinned for them
+    pub fn key_check(&self, did: &str) -> Option<KeyCheck> {
+        let Some(ParticipantKey::Published(key)) = self.participant_keys.get(did) else {
+            return None;
+        };
+        match self.store.pinned_key(did) {
+            Some(pin) if pin.key == *key => Some(KeyCheck::Matches),
+            Some(_) => Some(KeyCheck::Changed),
+            None => Some(KeyCheck::FirstUse),
         }
-        Ok(())
     }
 
-    /// Add a single Bmail message to the Conversation
-    pub fn add_bmail_to_conversation(
-        &mut self,
-        conv_id: Uuid,
-        msg: &DecryptedMessage,
-    ) -> Result<(), BmailError> {
-        match self.conversations.get_mut(&conv_id) {
-            Some(c) => {
-                insert_with_collisions(&mut c.messages, msg);
-                // Set current state to newest message
-                self.conversation_state.select(Some(c.messages.keys().count()-1));
-                Ok(())
-            }
-            None => Err(BmailError::ConversationNotFound),
+    /// Whether we've verified the key a participant is using now
+    pub fn is_verified(&self, did: &str) -> bool {
+        self.key_check(did) == Some(KeyCheck::Matches)
+            && self.store.pinned_key(did).map(|pin| pin.verified) == Some(true)
+    }
+
+    /// Warn about participants whose keys have changed. Returns whether there were any
+    fn warn_about_changed_keys(&mut self, participants: &[String]) -> bool {
+        let changed = self.changed_keys(participants);
+        if changed.is_empty() {
+            return false;
         }
+        let verify_key = self.keymap.key_label(Action::VerifyKeys).unwrap_or_default();
+        self.set_error(format!(
+            "WARNING: the Bmail key for {} has changed since you first saw it! Someone may be pretending to be them. Press {} to compare keys",
+            changed.join(", "),
+            verify_key
+        ));
+        true
+    }
+
+    /// Get the participants of the current Conversation, other than us
+    pub fn current_others(&self) -> Vec<String> {
+        self.current_conversation_id
+            .and_then(|c_id| self.conversations.get(&c_id))
+            .map(|c| {
+                c.participants
+                    .iter()
+                    .filter(|did| self.user_did.as_ref() != Some(*did))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 
-    /// Send a Bmail by adding your message to your ConversationPortion in your profile Record
-    pub async fn send_bmail(
+    /// Send a Bmail in the background. See [Session::send_bmail]
+    pub fn send_bmail(
         &mut self,
         conversation_id: Uuid,
         recipients: Vec<String>,
-        msg: &str,
-    ) -> Result<(), BmailError> {
-        // println!("Message Size: {}", msg.len());
-        // println!("Message Char Count: {}", msg.chars().count());
-
-        let Some(user_did) = &self.user_did else {
-            return Err(BmailError::InternalServerError)
-        };
-
-        // 0. Get DIDS for participants
-        let participant_dids = {
-            let mut dids: Vec<String> = Vec::with_capacity(recipients.len());
-            let mut bsky = self.bluesky.0.write().await;
-            let mut user = bsky.user(&self.conf.user.handle)?;
-            for recipient in &recipients {
-                let recipient_did = user.resolve_handle(recipient).await?;
-                dids.push(recipient_did);
-            }
-            dids.sort();
-            dids
-        };
-        // Create Message
-        let msg = DecryptedMessage {
-            created_at: Utc::now(),
-            creator: user_did.clone(),
-            message: msg.to_string(),
-            conversation_id,
-            recipients: participant_dids.clone(),
-            version: 0,
-            creator_handle: self.conf.user.handle.clone(),
-        };
-        let record = msg.into_bmail_record(self.bluesky.clone()).await?;
-        // Send Bmail by creating a profile post with the contents
-        {
-            let mut bsky = self.bluesky.0.write().await;
-            let mut me = bsky.me().map_err::<BmailError, _>(Into::into)?;
-            me.create_record("app.bsky.actor.profile", None, None, None, record)
-                .await?;
-        };
-        // Add the decrypted message to the Conversation
-        match self.add_bmail_to_conversation(conversation_id, &msg) {
-            Ok(_) => (),
-            Err(BmailError::ConversationNotFound) => {
-                self.status = "Failed to find conversation".to_string()
+        msg: Zeroizing<String>,
+    ) {
+        let session = self.session();
+        let pinned_keys = self.store.pinned_keys();
+        let disappear_after = self.store.disappearing_timer(&conversation_id);
+        self.tasks.spawn("Sending Bmail", async move {
+            let result = session
+                .send_bmail(
+                    conversation_id,
+                    recipients,
+                    &msg,
+                    pinned_keys,
+                    disappear_after,
+                    None,
+                )
+                .await;
+            TaskOutcome::BmailSent {
+                conversation_id,
+                draft: msg,
+                result,
             }
-            Err(e) => self.status = format!("Unexpected_error: {}", e.to_string()),
-        };
-        // Notify recipients that we have sent them a Bmail
-        self.notify_recipients(conversation_id, recipients).await?;
-
-        Ok(())
+        });
     }
-}
 
-impl Default for App {
-    fn default() -> App {
-        App {
-            input: String::new(),
-            recipient: String::new(),
-            input_mode: InputMode::Normal,
-            bluesky: SharableBluesky::default(),
-            identity: Identity::generate(),
-            message_rx: None,
-            status: "ALL GOOD".to_string(),
-            conversations: HashMap::new(),
-            conf: Settings::default(),
-            user_did: None,
-            current_conversation_id: None,
-            recipients_conversation_map: HashMap::new(),
-            conversation_state: ListState::default(),
+    /// Explain why the current Conversation can't be sent to, if it can't. Everyone has to have published a
+    /// Bmail key, or they'd be sent something they can't read, and any key that changed has to be checked first
+    pub fn send_blocker(&self) -> Option<String> {
+        let c_id = self.current_conversation_id?;
+        let conversation = self.conversations.get(&c_id)?;
+        let left_group = matches!(
+            self.store.group(&c_id),
+            Some(group) if !group.members.iter().any(|m| self.user_did.as_ref() == Some(m))
+        );
+        if left_group {
+            return Some("you're not in this group anymore".to_string());
+        }
+        let missing: Vec<String> = conversation
+            .participants
+            .iter()
+            .filter(|did| matches!(self.participant_keys.get(*did), Some(ParticipantKey::Missing)))
+            .map(|did| self.handle_for(did))
+            .collect();
+        let changed = self.changed_keys(&conversation.participants);
+        match (missing.len(), changed.len()) {
+            (0, 0) => None,
+            (0, _) => Some(format!(
+                "the key for {} has changed, press {} to check it",
+                changed.join(", "),
+                self.keymap.key_label(Action::VerifyKeys).unwrap_or_default()
+            )),
+            (1, _) => Some(format!("{} hasn't set up Bmail", missing[0])),
+            _ => Some(format!("{} haven't set up Bmail", missing.join(", "))),
         }
     }
-}
 
-pub async fn run_app<B: Backend>(
-    terminal: &mut Terminal<B>,
-    mut app: App,
-) -> Result<(), BmailError> {
-    loop {
-        // Read messages on the channel and process them. Should work ok unless you're under
-        // an absolute deluge. TODO: Be smarter about reading these messages
-        if let Some(rx) = &mut app.message_rx {
-            match &rx.try_recv() {
-                Ok(m) => match m {
-                    FirehoseMessages::Bmail(m) => {
-                        // println!("FOUND BMAIL");
-                        // println!("\x07");
-                        let msg = m.into_decrypted_message(&app.identity).await?;
-                        app.add_bmail_to_conversation(msg.conversation_id, &msg)?;
-                    }
-                    FirehoseMessages::BmailLike(_l) => (),
-                },
-                Err(TryRecvError::Empty) => (),
-                Err(_) => return Err(BmailError::FirehoseProcessCrashed),
-            }
+    /// Show a message in the status line
+    pub fn set_status(&mut self, status: String) {
+        self.status = status;
+        self.status_is_error = false;
+    }
+
+    /// Show what the key's plugin had to say since the last time
+    pub fn show_key_messages(&mut self) {
+        let messages = match self.identity.callbacks() {
+            Some(callbacks) => callbacks.take_messages(),
+            None => return,
+        };
+        if let Some(message) = messages.last() {
+            self.set_status(message.clone());
         }
+    }
 
-        terminal.draw(|f| ui(f, &mut app))?;
+    /// Show an error in the status line
+    pub fn set_error(&mut self, error: String) {
+        self.status = error;
+        self.status_is_error = true;
+    }
 
-        if let Event::Key(key) = event::read()? {
-            match app.input_mode {
-                InputMode::Normal => match key.code {
-                    KeyCode::Char('e') => {
-                        app.input_mode = InputMode::Editing;
-                    }
-                    KeyCode::Char('m') => {
-                        app.input_mode = InputMode::ScrollingMessages;
-                    }
-                    KeyCode::Tab => {
-                        app.input_mode = InputMode::EditingRecipient;
-                    }
-                    KeyCode::Char('q') => {
-                        return Ok(());
-                    }
-                    _ => {}
-                },
-                InputMode::Editing if key.kind == KeyEventKind::Press => match key.code {
-                    KeyCode::Enter => {
-                        let Some(c_id) = &app.current_conversation_id else{
-                            app.status = "No conversation is active".to_string();
-                            continue
-                        };
-                        let recipients_input = &app.recipient;
-                        let recipients =
-                            recipients_input.split(',').map(|s| s.to_string()).collect();
-                        let input = app.input.clone();
-                        match app.send_bmail(*c_id, recipients, &input).await {
-                            Ok(_) => app.input= "".to_string(),
-                            Err(BmailError::MissingRecipient(r)) => {
-                                app.status = format!("Recipient {} is not using Bmail", r)
+    /// Apply the outcome of a background task to the App
+    pub fn handle_task_outcome(&mut self, outcome: TaskOutcome) {
+        match outcome {
+            TaskOutcome::ConversationLoaded(Ok(mut loaded)) => {
+                let participants = loaded.conversation.participants.clone();
+                let key_changed = std::mem::take(&mut loaded.key_changed);
+                let c_id = self.apply_loaded_conversation(loaded);
+                if !self.warn_about_held_back_bmail(key_changed)
+                    && !self.warn_about_changed_keys(&participants)
+                {
+                    self.set_status(format!("Loaded Conversation with {}", self.conversation_label(&c_id)));
+                }
+            }
+            TaskOutcome::ParticipantKeysChecked(keys) => {
+                let participants: Vec<String> = keys.keys().cloned().collect();
+                self.apply_participant_keys(keys);
+                self.warn_about_changed_keys(&participants);
+            }
+            TaskOutcome::ConversationLoaded(Err(e)) => {
+                self.set_error(format!("Failed to load conversation: {:?}", e.to_string()))
+            }
+            TaskOutcome::ConversationSynced(Ok(fetched)) => {
+                let c_id = fetched.conversation.conversation_id;
+                for msg in fetched.conversation.messages.into_values() {
+                    self.add_conversation_from_bmail(&msg);
+                    // Conversations aren't removed, so this is always there
+                    let _ = self.add_bmail_to_conversation(c_id, msg);
+                }
+                self.warn_about_held_back_bmail(fetched.key_changed);
+            }
+            TaskOutcome::ConversationSynced(Err(e)) => {
+                self.set_error(format!("Failed to fetch new Bmail: {}", e));
+            }
+            TaskOutcome::BmailSent {
+                conversation_id,
+                mut draft,
+                result,
+            } => {
+                let sent = match result {
+                    Ok(sent) => sent,
+                    Err(e) => {
+                        let error = match e {
+                            BmailError::MissingRecipient(r) => {
+                                format!("Recipient {} is not using Bmail", r)
                             }
-                            Err(e) => {
-                                app.status = format!("Unexpected Error: {:#?}", e.to_string())
+                            BmailError::KeyChanged(did) => {
+                                // Find out what the new key is, so it can be compared
+                                self.refresh_participant_keys(vec![did.clone()]);
+                                format!(
+                                    "Not sent: the Bmail key for {} has changed since you first saw it!",
+                                    self.handle_for(&did)
+                                )
                             }
+                            e => format!("Unexpected Error: {:#?}", e.to_string()),
                         };
+                        self.set_error(error);
+                        // Give the message back so it isn't lost, unless they've started another one
+                        if self.current_conversation_id != Some(conversation_id) {
+                            if self.store.draft(&conversation_id).is_none() {
+                                self.store.set_draft(conversation_id, &draft);
+                            }
+                        } else if self.input.is_empty() {
+                            // Moved rather than copied, so the only copy left is in the editor
+                            self.input.set_text(std::mem::take(&mut *draft));
+                        }
+                        return;
                     }
-                    KeyCode::Char(c) => {
-                        app.input.push(c);
+                };
+                self.apply_sent_bmail(sent);
+            }
+            TaskOutcome::GroupUpdated(Ok(sent)) => {
+                let c_id = sent.message.conversation_id;
+                let created = matches!(
+                    &sent.message.group,
+                    Some(update) if update.change == GroupChange::Created
+                );
+                self.apply_sent_bmail(sent);
+                // A new group is where we want to be next
+                if created {
+                    self.switch_conversation(c_id);
+                    self.conversation_state = ListState::default();
+                    let i = self
+                        .conversation_list()
+                        .iter()
+                        .position(|c| c.conversation_id == c_id);
+                    self.conversation_list_state.select(i);
+                }
+            }
+            TaskOutcome::GroupUpdated(Err(e)) => {
+                if let BmailError::KeyChanged(did) = &e {
+                    self.refresh_participant_keys(vec![did.clone()]);
+                }
+                self.set_error(format!("Failed to update group: {}", e));
+            }
+            TaskOutcome::FollowsLoaded(Ok(follows)) => {
+                self.autocomplete.add_known(follows);
+                self.update_suggestions();
+            }
+            TaskOutcome::FollowsLoaded(Err(e)) => {
+                self.set_error(format!("Failed to load follows: {}", e));
+            }
+            TaskOutcome::ActorsSearched {
+                query,
+                result: Ok(actors),
+            } => {
+                self.autocomplete.set_search_results(query, actors);
+                self.update_suggestions();
+            }
+            TaskOutcome::ActorsSearched {
+                query,
+                result: Err(e),
+            } => {
+                self.autocomplete.search_failed(&query);
+                self.set_error(format!("Failed to search handles: {}", e));
+            }
+            TaskOutcome::BmailCapabilityChecked(capable) => {
+                self.autocomplete.set_bmail_capable(capable);
+            }
+            TaskOutcome::SettingsSynced(result) => {
+                match result {
+                    Ok(settings) => self.store.merge_synced_settings(&settings),
+                    Err(e) => {
+                        self.set_error(format!("Failed to sync conversation settings: {}", e))
                     }
-                    KeyCode::Backspace => {
-                        app.input.pop();
+                }
+                if std::mem::take(&mut self.settings_sync_pending) {
+                    self.sync_settings();
+                }
+            }
+            TaskOutcome::ExpiredRecordsDeleted { deleted, result } => {
+                self.store.remove_expiring_records(&deleted);
+                match result {
+                    Ok(()) => self.delete_retry_at = None,
+                    Err(e) => {
+                        self.delete_retry_at =
+                            Some(Utc::now() + chrono::Duration::minutes(DELETE_RETRY_MINUTES));
+                        self.set_error(format!("Failed to delete expired Bmails: {}", e));
                     }
-                    KeyCode::Esc => {
-                        app.input_mode = InputMode::Normal;
+                }
+            }
+            TaskOutcome::Locked(Ok(())) => (),
+            TaskOutcome::Locked(Err(e)) => {
+                self.set_error(format!("Locked, but failed to save drafts: {}", e));
+            }
+            TaskOutcome::Unlocked(Ok(store)) => {
+                self.store = store;
+                self.input_mode = InputMode::Normal;
+                self.set_status("Unlocked".to_string());
+                if let Some(c_id) = self.current_conversation_id {
+                    self.input.set_text(self.store.draft(&c_id).unwrap_or_default().to_string());
+                    self.open_conversation(c_id, self.current_others());
+                }
+                for (did, c_id) in std::mem::take(&mut self.locked_likes) {
+                    self.handle_bmail_like(&did, c_id);
+                }
+            }
+            TaskOutcome::Unlocked(Err(BmailError::WrongPassphrase)) => {
+                self.set_error("Wrong passphrase".to_string());
+            }
+            TaskOutcome::Unlocked(Err(e)) => {
+                self.set_error(format!("Failed to unlock: {}", e));
+            }
+        }
+    }
+
+    /// Add a Bmail we sent to its Conversation, and keep what we learned sending it
+    fn apply_sent_bmail(&mut self, sent: SentBmail) {
+        self.known_handles.extend(sent.handles);
+        if let Some(expires_at) = sent.message.expires_at() {
+            self.store.add_expiring_record(sent.uri, expires_at);
+        }
+        self.apply_participant_keys(
+            sent.keys
+                .into_iter()
+                .map(|(did, key)| (did, ParticipantKey::Published(key)))
+                .collect(),
+        );
+        // Add the decrypted message to the Conversation
+        match self.add_bmail_to_conversation(sent.message.conversation_id, sent.message) {
+            Ok(_) => (),
+            Err(BmailError::ConversationNotFound) => {
+                self.set_error("Failed to find conversation".to_string())
+            }
+            Err(e) => self.set_error(format!("Unexpected_error: {}", e)),
+        };
+        if let Err(e) = sent.notified {
+            self.set_error(format!("Sent, but failed to notify recipients: {}", e));
+        } else if self.conf.forward_secrecy.enabled && !sent.forward_secret {
+            self.set_error(
+                "Sent without forward secrecy, since not every device has a prekey".to_string(),
+            );
+        }
+    }
+
+    /// Load the accounts the user follows in the background, so they can be suggested as recipients
+    pub fn load_follows(&mut self) {
+        let session = self.session();
+        self.tasks.spawn("Loading follows", async move {
+            TaskOutcome::FollowsLoaded(session.fetch_follows().await)
+        });
+    }
+
+    /// Update the handle suggestions for the recipient box, and start any lookups they need in the background
+    pub fn update_suggestions(&mut self) {
+        // Everyone we've had a Conversation with can be suggested too
+        let participants: Vec<Candidate> = self
+            .known_handles
+            .iter()
+            .filter(|(did, _)| self.user_did.as_ref() != Some(*did))
+            .map(|(did, handle)| Candidate {
+                did: did.clone(),
+                handle: handle.clone(),
+                display_name: None,
+            })
+            .collect();
+        self.autocomplete.add_known(participants);
+        self.autocomplete.update(&self.recipient);
+
+        if let Some(query) = self.autocomplete.start_search() {
+            let session = self.session();
+            self.tasks.spawn("Searching handles", async move {
+                let result = session.search_actors(&query).await;
+                TaskOutcome::ActorsSearched { query, result }
+            });
+        }
+        let unchecked = self.autocomplete.start_capability_check();
+        if !unchecked.is_empty() {
+            let session = self.session();
+            self.tasks.spawn("Checking for Bmail keys", async move {
+                TaskOutcome::BmailCapabilityChecked(session.check_bmail_capable(&unchecked).await)
+            });
+        }
+    }
+
+    /// Add a single Bmail message to the Conversation
+    pub fn add_bmail_to_conversation(
+        &mut self,
+        conv_id: Uuid,
+        msg: DecryptedMessage,
+    ) -> Result<(), BmailError> {
+        self.known_handles
+            .insert(msg.creator.clone(), msg.creator_handle.clone());
+        self.log_gossip(&msg);
+        self.note_disappearing_timer(&msg);
+        self.apply_group_update(&msg);
+        if msg.is_expired(&Utc::now()) {
+            return Ok(());
+        }
+        // Only people who have been in a group can write in it
+        let outsider = matches!(
+            self.store.group(&conv_id),
+            Some(group) if !group.everyone().contains(&msg.creator)
+        );
+        if outsider {
+            return Ok(());
+        }
+        match self.conversations.get_mut(&conv_id) {
+            Some(c) => {
+                insert_with_collisions(&mut c.messages, msg);
+                // Set current state to newest message, if we're looking at it
+                if self.current_conversation_id == Some(conv_id) {
+                    self.conversation_state.select(Some(c.messages.keys().count()-1));
+                }
+                Ok(())
+            }
+            None => Err(BmailError::ConversationNotFound),
+        }
+    }
+
+    /// Handle a notification Like addressed to us. Anyone can make one and put whatever recipients they like
+    /// in it, so it's only taken as a sign that `did` has sent something in the Conversation. Who's in it comes
+    /// from what we already know, or from their Bmails once they've been fetched and decrypted
+    pub fn handle_bmail_like(&mut self, did: &str, c_id: Uuid) {
+        let locked = matches!(self.input_mode, InputMode::Locked);
+        let participants = self.known_participants(&c_id);
+        // Someone who has left a group might still have Bmails in it we haven't seen
+        let everyone = match self.store.group(&c_id) {
+            Some(group) => Some(group.everyone()),
+            None => participants.clone(),
+        };
+        let fetch_from = match everyone {
+            Some(everyone) if everyone.iter().any(|p| p == did) => everyone,
+            // The store isn't open to check groups with while locked, so it's looked at again after unlocking
+            _ if locked => {
+                let held = (did.to_string(), c_id);
+                if !self.locked_likes.contains(&held) {
+                    self.locked_likes.push(held);
+                }
+                return;
+            }
+            // Someone who isn't in a Conversation can't have written in it
+            Some(_) => return,
+            // A Conversation we haven't seen yet is only added once one of their Bmails in it decrypts
+            None => vec![did.to_string()],
+        };
+
+        if let Some(participants) = participants {
+            let conversation = self
+                .conversations
+                .entry(c_id)
+                .or_insert_with(|| Conversation {
+                    conversation_id: c_id,
+                    participants,
+                    ..Default::default()
+                });
+            if self.current_conversation_id != Some(c_id) {
+                conversation.unread_count += 1;
+            }
+        }
+        // It'll be fetched when it's loaded after unlocking
+        if locked {
+            return;
+        }
+
+        let session = self.session();
+        let sender_keys = self.all_sender_keys();
+        self.tasks.spawn("Fetching new Bmail", async move {
+            TaskOutcome::ConversationSynced(
+                session
+                    .fetch_conversation(c_id, fetch_from, &sender_keys)
+                    .await,
+            )
+        });
+    }
+
+    /// Who we know to be in a Conversation, from its group, the sidebar, or the conversation map
+    fn known_participants(&self, c_id: &Uuid) -> Option<Vec<String>> {
+        if let Some(group) = self.store.group(c_id) {
+            return Some(group.members.clone());
+        }
+        if let Some(conversation) = self.conversations.get(c_id) {
+            return Some(conversation.participants.clone());
+        }
+        self.recipients_conversation_map
+            .iter()
+            .find(|(_, id)| *id == c_id)
+            .map(|(participants, _)| participants.clone())
+    }
+
+    /// Add a Conversation we've just found out about from a Bmail in it we could decrypt. The Bmail was
+    /// encrypted to us, so who it says it's for can be trusted as much as its sender
+    fn add_conversation_from_bmail(&mut self, msg: &DecryptedMessage) {
+        let c_id = msg.conversation_id;
+        if self.conversations.contains_key(&c_id) {
+            return;
+        }
+        let mut participants = msg.recipients.clone();
+        participants.push(msg.creator.clone());
+        participants.sort();
+        participants.dedup();
+        if msg.group.is_none() && self.store.group(&c_id).is_none() {
+            self.recipients_conversation_map
+                .entry(participants.clone())
+                .or_insert(c_id);
+        }
+        self.conversations.insert(
+            c_id,
+            Conversation {
+                conversation_id: c_id,
+                participants,
+                unread_count: usize::from(self.current_conversation_id != Some(c_id)),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Process a message from the Firehose, or from the startup sync
+    pub async fn handle_firehose_message(
+        &mut self,
+        message: FirehoseMessages,
+    ) -> Result<(), BmailError> {
+        let locked = matches!(self.input_mode, InputMode::Locked);
+        match message {
+            // Nothing is decrypted while locked. The Likes that come with Bmails still count as unread
+            FirehoseMessages::Bmail(_) | FirehoseMessages::SyncedBmail(_) if locked => (),
+            FirehoseMessages::Bmail(m) => {
+                let Some(msg) = self.decrypt_firehose_bmail(&m).await else {
+                    return Ok(());
+                };
+                // Don't notify about our own messages from another client, ones that are already gone,
+                // group changes, or muted Conversations
+                if self.user_did.as_ref() != Some(&msg.creator)
+                    && !msg.is_expired(&Utc::now())
+                    && msg.group.is_none()
+                    && !self.is_muted(&msg.conversation_id)
+                {
+                    let label = self.conversation_label(&msg.conversation_id);
+                    self.notifier.notify(&msg, &label);
+                }
+                match self.add_bmail_to_conversation(msg.conversation_id, msg) {
+                    // The notification Like will fetch the Conversation for us
+                    Err(BmailError::ConversationNotFound) => (),
+                    r => r?,
+                };
+            }
+            FirehoseMessages::SyncedBmail(m) => {
+                let Some(msg) = self.decrypt_firehose_bmail(&m).await else {
+                    return Ok(());
+                };
+                match self.add_bmail_to_conversation(msg.conversation_id, msg) {
+                    Err(BmailError::ConversationNotFound) => (),
+                    r => r?,
+                };
+            }
+            FirehoseMessages::BmailLike { did, like } => {
+                self.handle_bmail_like(&did, like.bmail_conversation_id)
+            }
+            FirehoseMessages::PublicKey { did, key } => self.handle_published_key(did, key),
+        }
+        Ok(())
+    }
+
+    /// Put pasted text wherever we're typing
+    pub fn handle_paste(&mut self, text: &str) {
+        match self.input_mode {
+            InputMode::Editing => self.input.insert_str(text),
+            // Handles don't have line breaks in them
+            InputMode::EditingRecipient => {
+                self.recipient
+                    .extend(text.chars().filter(|c| !c.is_control()));
+                self.update_suggestions();
+            }
+            InputMode::EditingGroup => self
+                .group_command
+                .extend(text.chars().filter(|c| !c.is_control())),
+            InputMode::EditingTitle => self
+                .title_input
+                .extend(text.chars().filter(|c| !c.is_control())),
+            _ => (),
+        }
+    }
+
+    /// Move the selection in the message list. Gets the selected index and the number of messages,
+    /// and returns the index to select. Does nothing if there are no messages to select
+    pub fn scroll_messages(&mut self, scroll: impl FnOnce(usize, usize) -> usize) {
+        let count = self
+            .current_conversation_id
+            .and_then(|c_id| self.conversations.get(&c_id))
+            .map(|c| c.messages.len())
+            .unwrap_or(0);
+        if count == 0 {
+            return;
+        }
+        let i = match self.conversation_state.selected() {
+            Some(i) => scroll(i.min(count - 1), count),
+            None => count - 1,
+        };
+        self.conversation_state.select(Some(i));
+    }
+
+    /// Get the handle for a DID if we know it, or the DID if we don't
+    pub fn handle_for(&self, did: &str) -> String {
+        self.known_handles
+            .get(did)
+            .cloned()
+            .unwrap_or_else(|| did.to_string())
+    }
+
+    /// Get the handles of everyone in a list of participants except us
+    pub fn participants_label(&self, participants: &[String]) -> String {
+        let others: Vec<String> = participants
+            .iter()
+            .filter(|p| Some(*p) != self.user_did.as_ref())
+            .map(|p| self.handle_for(p))
+            .collect();
+        match others.is_empty() {
+            true => "Just you".to_string(),
+            false => others.join(", "),
+        }
+    }
+
+    /// Get a short human readable name for a Conversation
+    pub fn conversation_label(&self, conversation_id: &Uuid) -> String {
+        if let Some(title) = self
+            .store
+            .conversation_settings(conversation_id)
+            .and_then(|s| s.title.clone())
+        {
+            return title;
+        }
+        if let Some(group) = self.store.group(conversation_id) {
+            return group.name.clone();
+        }
+        match self.conversations.get(conversation_id) {
+            Some(c) => self.participants_label(&c.participants),
+            None => {
+                let id = conversation_id.to_string();
+                format!("Conversation {}", &id[..8])
+            }
+        }
+    }
+
+    /// Get every Conversation we know about for the sidebar, newest activity first
+    pub fn conversation_list(&self) -> Vec<ConversationSummary> {
+        let mut summaries: Vec<ConversationSummary> = Vec::new();
+        let mut seen: HashSet<Uuid> = HashSet::new();
+
+        // Conversations we've loaded, and ones we only know the participants of
+        let known = self
+            .conversations
+            .values()
+            .map(|c| (c.conversation_id, c.participants.clone()))
+            .chain(
+                self.recipients_conversation_map
+                    .iter()
+                    .map(|(participants, c_id)| (*c_id, participants.clone())),
+            );
+        for (c_id, participants) in known {
+            if !seen.insert(c_id) {
+                continue;
+            }
+
+            let settings = self.store.conversation_settings(&c_id);
+            let archived = matches!(settings, Some(s) if s.archived);
+            if archived && !self.show_archived {
+                continue;
+            }
+            let conversation = self.conversations.get(&c_id);
+            let label = match (settings.and_then(|s| s.title.clone()), self.store.group(&c_id)) {
+                (Some(title), _) => title,
+                (None, Some(group)) => group.name.clone(),
+                (None, None) => self.participants_label(&participants),
+            };
+            let others: Vec<String> = participants
+                .into_iter()
+                .filter(|p| Some(p) != self.user_did.as_ref())
+                .collect();
+            let last_message = conversation.and_then(|c| c.messages.values().last());
+            summaries.push(ConversationSummary {
+                conversation_id: c_id,
+                label,
+                others,
+                last_message_at: last_message.map(|m| m.created_at),
+                preview: last_message
+                    .map(|m| self.message_preview(m))
+                    .unwrap_or_default(),
+                unread_count: conversation.map(|c| c.unread_count).unwrap_or(0),
+                muted: matches!(settings, Some(s) if s.muted),
+                archived,
+            });
+        }
+        // Most recent first, Conversations without messages at the bottom. Ties go by name and then ID, so the
+        // order doesn't change with the HashMaps' and the selection stays put
+        summaries.sort_by(|a, b| {
+            b.last_message_at
+                .cmp(&a.last_message_at)
+                .then_with(|| a.label.cmp(&b.label))
+                .then_with(|| a.conversation_id.cmp(&b.conversation_id))
+        });
+        summaries
+    }
+
+    /// Get the total number of unread notifications across all Conversations
+    pub fn unread_count(&self) -> usize {
+        self.conversations.values().map(|c| c.unread_count).sum()
+    }
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            input: Editor::default(),
+            recipient: String::new(),
+            input_mode: InputMode::Normal,
+            bluesky: SharableBluesky::default(),
+            identity: BmailIdentity::X25519(Identity::generate()),
+            message_rx: None,
+            status: "ALL GOOD".to_string(),
+            status_is_error: false,
+            conversations: HashMap::new(),
+            conf: Settings::default(),
+            user_did: None,
+            current_conversation_id: None,
+            recipients_conversation_map: HashMap::new(),
+            conversation_state: ListState::default(),
+            notifier: Notifier::default(),
+            known_handles: HashMap::new(),
+            conversation_list_state: ListState::default(),
+            tasks: TaskManager::default(),
+            message_page_size: 1,
+            store: LocalStore::default(),
+            key_log: KeyLog::default(),
+            own_key_set: None,
+            ratchet: SharedRatchet::default(),
+            time: TimeFormatter::default(),
+            keymap: Keymap::default(),
+            theme: Theme::default(),
+            autocomplete: Autocomplete::default(),
+            participant_keys: HashMap::new(),
+            verify_list_state: ListState::default(),
+            unlock_input: Zeroizing::default(),
+            delete_retry_at: None,
+            group_command: String::new(),
+            title_input: String::new(),
+            renaming: None,
+            show_archived: false,
+            settings_sync_pending: false,
+            appview: AppView::default(),
+            locked_likes: Vec::new(),
+        }
+    }
+}
+
+pub async fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    mut app: App,
+) -> Result<(), BmailError> {
+    let mut events = EventStream::new();
+    let mut message_rx = app.message_rx.take();
+    // Redraw every so often even if nothing happens, so anything time based stays current
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        terminal.draw(|f| ui(f, &mut app))?;
+
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) => {
+                    if handle_key_event(&mut app, key)? {
+                        return app.save_store().await;
                     }
-                    KeyCode::Tab => {
-                        app.input_mode = InputMode::EditingRecipient;
+                }
+                Some(Ok(Event::Paste(text))) => app.handle_paste(&text),
+                // Resizes and the like just need a redraw
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+                None => return app.save_store().await,
+            },
+            message = recv_firehose_message(&mut message_rx) => match message {
+                Some(m) => app.handle_firehose_message(m).await?,
+                None => return Err(BmailError::FirehoseProcessCrashed),
+            },
+            outcome = app.tasks.recv() => app.handle_task_outcome(outcome),
+            _ = ticker.tick() => {
+                app.expire_messages().await;
+                app.show_key_messages();
+                // Drafts are saved as we go, so they survive a crash
+                if let Err(e) = app.save_store().await {
+                    app.set_error(format!("Failed to save drafts: {}", e));
+                }
+            }
+        }
+    }
+}
+
+/// Wait for the next message from the Firehose. If there's no Firehose, this waits forever so the
+/// event loop only wakes up for everything else
+async fn recv_firehose_message(
+    rx: &mut Option<Receiver<FirehoseMessages>>,
+) -> Option<FirehoseMessages> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Handle a single key press. Returns true if the app should quit
+fn handle_key_event(app: &mut App, key: KeyEvent) -> Result<bool, BmailError> {
+    if key.kind != KeyEventKind::Press {
+        return Ok(false);
+    }
+    let action = app.keymap.action(&app.input_mode, &key);
+    // Cancelling whatever is running in the background works whatever mode we're in
+    if action == Some(Action::CancelTasks) {
+        let cancelled = app.tasks.cancel_all();
+        if cancelled > 0 {
+            app.set_status(format!("Cancelled {} background task(s)", cancelled));
+        }
+        return Ok(false);
+    }
+    if action == Some(Action::Lock) {
+        app.lock();
+        return Ok(false);
+    }
+    match app.input_mode {
+        InputMode::Normal => match action {
+            Some(Action::StartEditing) => {
+                app.input_mode = InputMode::Editing;
+            }
+            Some(Action::ScrollMessages) => {
+                app.input_mode = InputMode::ScrollingMessages;
+            }
+            Some(Action::SelectConversation) => {
+                if app.conversation_list_state.selected().is_none() {
+                    app.conversation_list_state.select(Some(0));
+                }
+                app.input_mode = InputMode::SelectingConversation;
+            }
+            Some(Action::EditRecipient) => {
+                app.input_mode = InputMode::EditingRecipient;
+            }
+            Some(Action::DisappearingTimer) => app.cycle_disappearing_timer(),
+            Some(Action::ManageGroup) => {
+                app.input_mode = InputMode::EditingGroup;
+            }
+            Some(Action::VerifyKeys) => {
+                if app.current_others().is_empty() {
+                    app.set_error("Load a conversation to verify keys".to_string());
+                } else {
+                    app.verify_list_state.select(Some(0));
+                    app.input_mode = InputMode::VerifyingKeys;
+                }
+            }
+            Some(Action::Quit) => {
+                return Ok(true);
+            }
+            _ => {}
+        },
+        InputMode::Editing => match action {
+            Some(Action::Newline) => {
+                app.input.newline();
+            }
+            Some(Action::Send) => {
+                let Some(c_id) = app.current_conversation_id else{
+                    app.set_error("No conversation is active".to_string());
+                    return Ok(false)
+                };
+                if let Some(blocker) = app.send_blocker() {
+                    app.set_error(format!("Can't send: {}", blocker));
+                    return Ok(false);
+                }
+                // A group goes to whoever is in it now, whatever is in the recipient box
+                let recipients = match app.store.group(&c_id) {
+                    Some(_) => app.current_others(),
+                    None => app.recipient.split(',').map(|s| s.to_string()).collect(),
+                };
+                // Clear the input now so we can start on the next one while this sends
+                let input = app.input.take();
+                app.send_bmail(c_id, recipients, input);
+            }
+            Some(Action::DeleteWordBack) => app.input.delete_word_left(),
+            Some(Action::DeleteBack) => app.input.backspace(),
+            Some(Action::DeleteForward) => app.input.delete(),
+            Some(Action::WordLeft) => app.input.move_word_left(),
+            Some(Action::WordRight) => app.input.move_word_right(),
+            Some(Action::CursorLeft) => app.input.move_left(),
+            Some(Action::CursorRight) => app.input.move_right(),
+            Some(Action::CursorUp) => app.input.move_up(),
+            Some(Action::CursorDown) => app.input.move_down(),
+            Some(Action::LineStart) => app.input.move_home(),
+            Some(Action::LineEnd) => app.input.move_end(),
+            Some(Action::StopEditing) => {
+                app.input_mode = InputMode::Normal;
+            }
+            Some(Action::EditRecipient) => {
+                app.input_mode = InputMode::EditingRecipient;
+            }
+            // Anything that isn't bound to something types itself
+            _ => {
+                if let KeyCode::Char(c) = key.code {
+                    if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                        app.input.insert_char(c);
                     }
-                    _ => {}
-                },
-                InputMode::EditingRecipient if key.kind == KeyEventKind::Press => match key.code {
-                    KeyCode::Char(c) => {
+                }
+            }
+        },
+        InputMode::EditingRecipient => match action {
+            Some(Action::DeleteBack) => {
+                app.recipient.pop();
+                app.update_suggestions();
+            }
+            Some(Action::NextSuggestion) => app.autocomplete.select_next(),
+            Some(Action::PreviousSuggestion) => app.autocomplete.select_previous(),
+            Some(Action::AcceptSuggestion) => {
+                if let Some(completed) = app.autocomplete.accept(&app.recipient) {
+                    app.recipient = completed;
+                }
+            }
+            Some(Action::StopEditing) => {
+                app.input_mode = InputMode::Normal;
+            }
+            Some(Action::LoadConversation) => {
+                let recipients_input = &app.recipient;
+                let recipients =
+                    recipients_input.split(',').map(|s| s.to_string()).collect();
+                app.load_conversation(recipients);
+            }
+            _ => {
+                if let KeyCode::Char(c) = key.code {
+                    if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
                         app.recipient.push(c);
+                        app.update_suggestions();
                     }
-                    KeyCode::Backspace => {
-                        app.recipient.pop();
-                    }
-                    KeyCode::Esc => {
-                        app.input_mode = InputMode::Normal;
+                }
+            }
+        },
+        InputMode::ScrollingMessages => match action {
+            Some(Action::Back) => {
+                app.input_mode = InputMode::Normal;
+            }
+            Some(Action::Up) => app.scroll_messages(|i, count| match i {
+                0 => count - 1,
+                i => i - 1,
+            }),
+            Some(Action::Down) => app.scroll_messages(|i, count| match i + 1 < count {
+                true => i + 1,
+                false => 0,
+            }),
+            Some(Action::PageUp) => {
+                let page = app.message_page_size;
+                app.scroll_messages(|i, _| i.saturating_sub(page))
+            }
+            Some(Action::PageDown) => {
+                let page = app.message_page_size;
+                app.scroll_messages(|i, count| (i + page).min(count - 1))
+            }
+            // Jump to the oldest and newest messages
+            Some(Action::First) => app.scroll_messages(|_, _| 0),
+            Some(Action::Last) => app.scroll_messages(|_, count| count - 1),
+            _ => {}
+        },
+        InputMode::SelectingConversation => {
+            let conversation_count = app.conversation_list().len();
+            match action {
+                Some(Action::Back) => {
+                    app.input_mode = InputMode::Normal;
+                }
+                Some(Action::Up) if conversation_count > 0 => {
+                    let i = match app.conversation_list_state.selected() {
+                        Some(0) | None => conversation_count - 1,
+                        Some(i) => i - 1,
+                    };
+                    app.conversation_list_state.select(Some(i));
+                }
+                Some(Action::Down) if conversation_count > 0 => {
+                    let i = match app.conversation_list_state.selected() {
+                        Some(i) if i + 1 < conversation_count => i + 1,
+                        _ => 0,
+                    };
+                    app.conversation_list_state.select(Some(i));
+                }
+                Some(Action::Open) => {
+                    let Some(summary) = app.selected_conversation() else {
+                        return Ok(false);
+                    };
+                    // Fill in the recipient box so sending goes to the right people. Groups keep track of
+                    // that themselves
+                    if app.store.group(&summary.conversation_id).is_none() {
+                        app.recipient = summary
+                            .others
+                            .iter()
+                            .map(|did| app.handle_for(did))
+                            .collect::<Vec<String>>()
+                            .join(",");
                     }
-                    KeyCode::Tab => {
-                        app.input_mode = InputMode::EditingRecipient;
+                    app.open_conversation(summary.conversation_id, summary.others);
+                    app.input_mode = InputMode::Normal;
+                }
+                Some(Action::RenameConversation) => {
+                    let Some(summary) = app.selected_conversation() else {
+                        return Ok(false);
+                    };
+                    app.title_input = app
+                        .store
+                        .conversation_settings(&summary.conversation_id)
+                        .and_then(|s| s.title.clone())
+                        .unwrap_or_default();
+                    app.renaming = Some(summary.conversation_id);
+                    app.input_mode = InputMode::EditingTitle;
+                }
+                Some(Action::ToggleMuted) => app.toggle_selected(false),
+                Some(Action::ToggleArchived) => app.toggle_selected(true),
+                Some(Action::ShowArchived) => {
+                    app.show_archived = !app.show_archived;
+                    app.conversation_list_state.select(Some(0));
+                }
+                _ => {}
+            }
+        }
+        InputMode::VerifyingKeys => {
+            let others = app.current_others();
+            let selected = app
+                .verify_list_state
+                .selected()
+                .and_then(|i| others.get(i))
+                .cloned();
+            match action {
+                Some(Action::Back) => {
+                    app.input_mode = InputMode::Normal;
+                }
+                Some(Action::Up) if !others.is_empty() => {
+                    let i = match app.verify_list_state.selected() {
+                        Some(0) | None => others.len() - 1,
+                        Some(i) => i - 1,
+                    };
+                    app.verify_list_state.select(Some(i));
+                }
+                Some(Action::Down) if !others.is_empty() => {
+                    let i = match app.verify_list_state.selected() {
+                        Some(i) if i + 1 < others.len() => i + 1,
+                        _ => 0,
+                    };
+                    app.verify_list_state.select(Some(i));
+                }
+                Some(Action::ToggleVerified) => {
+                    let Some(did) = selected else {
+                        return Ok(false);
+                    };
+                    // Only the key they're using now can be verified. A changed key has to be accepted first
+                    if app.key_check(&did) != Some(KeyCheck::Matches) {
+                        app.set_error(format!(
+                            "{}'s key has changed, accept the new key before verifying it",
+                            app.handle_for(&did)
+                        ));
+                        return Ok(false);
                     }
-                    KeyCode::Enter => {
-                        let recipients_input = &app.recipient;
-                        let recipients =
-                            recipients_input.split(',').map(|s| s.to_string()).collect();
-                        match app.load_conversation(recipients).await {
-                            Ok(u) => app.status = format!("Loaded Conversation: {}", u),
-                            Err(e) => {
-                                app.status =
-                                    format!("Failed to load conversation: {:?}", e.to_string())
-                            }
-                        };
+                    let verified = !app.is_verified(&did);
+                    app.store.set_verified(&did, verified);
+                    app.set_status(format!(
+                        "Marked {}'s key as {}",
+                        app.handle_for(&did),
+                        if verified { "verified" } else { "unverified" }
+                    ));
+                }
+                Some(Action::AcceptKey) => {
+                    let Some(did) = selected else {
+                        return Ok(false);
+                    };
+                    if let Some(ParticipantKey::Published(key)) = app.participant_keys.get(&did).cloned() {
+                        app.store.pin_key(&did, &key);
+                        app.set_status(format!(
+                            "Now trusting {}'s new key. Compare the safety number with them to verify it",
+                            app.handle_for(&did)
+                        ));
                     }
-                    _ => {}
-                },
-                InputMode::ScrollingMessages if key.kind == KeyEventKind::Press => match key.code {
-                    KeyCode::Esc => {
-                        app.input_mode = InputMode::Normal;
+                }
+                _ => {}
+            }
+        }
+        InputMode::EditingGroup => match action {
+            Some(Action::StopEditing) => {
+                app.group_command.clear();
+                app.input_mode = InputMode::Normal;
+            }
+            Some(Action::DeleteBack) => {
+                app.group_command.pop();
+            }
+            Some(Action::RunGroupCommand) => app.run_group_command(),
+            _ => {
+                if let KeyCode::Char(c) = key.code {
+                    if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                        app.group_command.push(c);
                     }
-                    KeyCode::Up => {
-                        if let Some(c_id) = app.current_conversation_id {
-                            let i = match app.conversation_state.selected() {
-                                Some(i) => {
-                                    if i == 0 {
-                                        app.conversations.get(&c_id).unwrap().messages.len() - 1
-                                    } else {
-                                        i - 1
-                                    }
-                                }
-                                None => 0,
-                            };
-                            app.conversation_state.select(Some(i));
-                        }
+                }
+            }
+        },
+        InputMode::EditingTitle => match action {
+            Some(Action::StopEditing) => {
+                app.title_input.clear();
+                app.renaming = None;
+                app.input_mode = InputMode::SelectingConversation;
+            }
+            Some(Action::DeleteBack) => {
+                app.title_input.pop();
+            }
+            Some(Action::SaveTitle) => app.save_title(),
+            _ => {
+                if let KeyCode::Char(c) = key.code {
+                    if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                        app.title_input.push(c);
                     }
-                    KeyCode::Down => {
-                        if let Some(c_id) = app.current_conversation_id {
-                            let i = match app.conversation_state.selected() {
-                                Some(i) => {
-                                    if i >= app.conversations.get(&c_id).unwrap().messages.len() - 1
-                                    {
-                                        0
-                                    } else {
-                                        i + 1
-                                    }
-                                }
-                                None => 0,
-                            };
-                            app.conversation_state.select(Some(i));
-                        }
+                }
+            }
+        },
+        InputMode::Locked => match action {
+            Some(Action::Unlock) => app.unlock(),
+            Some(Action::DeleteBack) => {
+                app.unlock_input.pop();
+            }
+            _ => {
+                if let KeyCode::Char(c) = key.code {
+                    if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                        app.unlock_input.push(c);
                     }
-                    _ => {}
-                },
-                _ => {}
+                }
             }
-        }
+        },
     }
+    Ok(false)
 }
 
-fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
-    let chunks = Layout::default()
+/// Cover everything with a passphrase prompt while the app is locked
+fn draw_lock_screen<B: Backend>(f: &mut Frame<B>, app: &App) {
+    let area = f.size();
+    let rows = Layout::default()
         .direction(Direction::Vertical)
         .margin(2)
         .constraints(
@@ -781,103 +2074,232 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
                 Constraint::Length(1),
                 Constraint::Length(3),
                 Constraint::Length(1),
-                Constraint::Min(1),
-                Constraint::Length(3),
+                Constraint::Min(0),
             ]
             .as_ref(),
         )
+        .split(area);
+
+    let title = Spans::from(vec![
+        Span::styled("Bmail", app.theme.title),
+        Span::raw(" is locked. Type your lock passphrase and press "),
+        Span::styled(app.keymap.key_label(Action::Unlock).unwrap_or_default(), app.theme.key),
+    ]);
+    f.render_widget(Paragraph::new(title), rows[0]);
+
+    let unread = app.unread_count();
+    if unread > 0 {
+        let unread = Paragraph::new(Span::styled(format!("{} unread", unread), app.theme.unread));
+        f.render_widget(unread, rows[1]);
+    }
+
+    // Only show how much has been typed
+    let masked = "*".repeat(app.unlock_input.chars().count());
+    let prompt = Paragraph::new(masked.as_str())
+        .style(app.theme.focused)
+        .block(Block::default().borders(Borders::ALL).title("Passphrase"));
+    f.render_widget(prompt, rows[2]);
+    f.set_cursor(rows[2].x + masked.len() as u16 + 1, rows[2].y + 1);
+
+    let status = match app.tasks.progress(None) {
+        Some(progress) => format!("{} | {}", progress, app.status),
+        None => app.status.clone(),
+    };
+    let status = Paragraph::new(status).style(match app.status_is_error {
+        true => app.theme.status_error,
+        false => app.theme.status,
+    });
+    f.render_widget(status, rows[3]);
+}
+
+fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    if matches!(app.input_mode, InputMode::Locked) {
+        draw_lock_screen(f, app);
+        return;
+    }
+
+    // Conversation sidebar on the left, everything else on the right
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .margin(2)
+        .constraints([Constraint::Percentage(25), Constraint::Min(1)].as_ref())
         .split(f.size());
 
-    let title = Span::styled("Bmail", Style::default().add_modifier(Modifier::BOLD));
+    // The input box grows with the message, up to a point
+    let (input_lines, input_cursor) = app
+        .input
+        .layout((columns[1].width as usize).saturating_sub(2));
+    let input_height = input_lines.len().clamp(1, MAX_INPUT_LINES);
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Length(3),
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Min(1),
+                Constraint::Length(input_height as u16 + 2),
+            ]
+            .as_ref(),
+        )
+        .split(columns[1]);
+
+    let unread = app.unread_count();
+    let title = match unread {
+        0 => Spans::from(Span::styled("Bmail", app.theme.title)),
+        n => Spans::from(vec![
+            Span::styled("Bmail", app.theme.title),
+            Span::styled(format!(" ({} unread)", n), app.theme.unread),
+        ]),
+    };
     let mut title_text = Text::from(title);
     title_text.patch_style(Style::default());
     let title_message = Paragraph::new(title_text);
     f.render_widget(title_message, chunks[0]);
 
-    let (msg, style) = match app.input_mode {
-        InputMode::Normal => (
-            vec![
-                Span::raw("Press "),
-                Span::styled("q", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to exit, "),
-                Span::styled("e", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to start typing a message, "),
-                Span::styled("m", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to enter conversation scroll mode."),
-            ],
-            Style::default().add_modifier(Modifier::RAPID_BLINK),
-        ),
-        InputMode::Editing => (
-            vec![
-                Span::raw("Press "),
-                Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to stop Editing, "),
-                Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to change conversation, "),
-                Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to send the message"),
-            ],
-            Style::default(),
-        ),
-        InputMode::EditingRecipient => (
-            vec![
-                Span::raw("Press "),
-                Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to stop Editing, "),
-                Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to choose recipient, "),
-                Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to load the conversation"),
-            ],
-            Style::default(),
-        ),
-        InputMode::ScrollingMessages => (
-            vec![
-                Span::raw("Press "),
-                Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to stop Scrolling, "),
-                Span::styled(
-                    "Up/Dwn Arrow",
-                    Style::default().add_modifier(Modifier::BOLD),
-                ),
-                Span::raw(" to scroll messages, "),
-            ],
-            Style::default(),
-        ),
+    // The help line comes from the keymap, so it always matches the real bindings
+    let mut msg = vec![Span::raw("Press ")];
+    let help = app.keymap.help(&app.input_mode);
+    for (i, (keys, description)) in help.into_iter().enumerate() {
+        if i > 0 {
+            msg.push(Span::raw(", "));
+        }
+        msg.push(Span::styled(keys, app.theme.key));
+        msg.push(Span::raw(format!(" {}", description)));
+    }
+    let style = match app.input_mode {
+        InputMode::Normal => Style::default().add_modifier(Modifier::RAPID_BLINK),
+        _ => Style::default(),
     };
     let mut text = Text::from(Spans::from(msg));
     text.patch_style(style);
     let help_message = Paragraph::new(text);
     f.render_widget(help_message, chunks[1]);
 
-    let recipient = Paragraph::new(app.recipient.as_ref())
+    // The group command is typed where the recipient goes, since they're about who's in the Conversation
+    let (recipient, recipient_title) = match app.input_mode {
+        InputMode::EditingGroup => (
+            app.group_command.as_str(),
+            "Group: create <name>, rename <name>, add <handles>, remove <handles> or leave",
+        ),
+        InputMode::EditingTitle => (app.title_input.as_str(), "Conversation Title"),
+        _ => (app.recipient.as_str(), "Recipient Handle"),
+    };
+    let recipient = Paragraph::new(recipient)
         .style(match app.input_mode {
             InputMode::Normal => Style::default(),
             InputMode::Editing => Style::default(),
-            InputMode::EditingRecipient => Style::default().fg(Color::Yellow),
+            InputMode::EditingRecipient => app.theme.focused,
             InputMode::ScrollingMessages => Style::default(),
+            InputMode::SelectingConversation => Style::default(),
+            InputMode::VerifyingKeys => Style::default(),
+            InputMode::EditingGroup => app.theme.focused,
+            InputMode::EditingTitle => app.theme.focused,
+            InputMode::Locked => Style::default(),
         })
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title("Recipient Handle"),
-        );
+        .block(Block::default().borders(Borders::ALL).title(recipient_title));
     f.render_widget(recipient, chunks[2]);
 
-    let status = Paragraph::new(app.status.as_ref()).style(Style::default());
-    f.render_widget(status, chunks[3]);
+    // Who's in the Conversation, and whether they can receive Bmails
+    let mut participants = Vec::new();
+    if let Some(c) = app
+        .current_conversation_id
+        .and_then(|c_id| app.conversations.get(&c_id))
+    {
+        for did in c.participants.iter() {
+            if app.user_did.as_ref() == Some(did) {
+                continue;
+            }
+            if !participants.is_empty() {
+                participants.push(Span::raw(" | "));
+            }
+            participants.push(Span::raw(format!("{} ", app.handle_for(did))));
+            participants.push(match app.participant_keys.get(did) {
+                Some(ParticipantKey::Published(_)) if app.key_check(did) == Some(KeyCheck::Changed) => {
+                    Span::styled("⚠ KEY CHANGED", app.theme.status_error)
+                }
+                Some(ParticipantKey::Published(key)) if app.is_verified(did) => {
+                    Span::styled(format!("✔ verified {}", fingerprint(key)), app.theme.other_message)
+                }
+                Some(ParticipantKey::Published(key)) => {
+                    Span::styled(format!("✓ {} (unverified)", fingerprint(key)), app.theme.unverified)
+                }
+                Some(ParticipantKey::Missing) => {
+                    Span::styled("✗ no Bmail key", app.theme.unverified)
+                }
+                Some(ParticipantKey::Unavailable(_)) => {
+                    Span::styled("? couldn't check key", app.theme.unverified)
+                }
+                None => Span::styled("? not checked", app.theme.dim),
+            });
+        }
+    }
+    f.render_widget(Paragraph::new(Spans::from(participants)), chunks[3]);
 
+    let cancel_key = app.keymap.key_label(Action::CancelTasks);
+    let status = match app.tasks.progress(cancel_key.as_deref()) {
+        Some(progress) => format!("{} | {}", progress, app.status),
+        None => app.status.clone(),
+    };
+    let status = Paragraph::new(status).style(match app.status_is_error {
+        true => app.theme.status_error,
+        false => app.theme.status,
+    });
+    f.render_widget(status, chunks[4]);
+
+    // Room for the borders, the highlight symbol and the indent on the message body
+    let body_width = (chunks[5].width as usize).saturating_sub(2 + MESSAGE_HIGHLIGHT.width() + 2);
     let messages: Vec<ListItem> = match app.current_conversation_id {
         Some(c_id) => match app.conversations.get(&c_id) {
             Some(c) => c
                 .messages
                 .iter()
-                .map(|(k, v)| {
-                    let content = Spans::from(Span::raw(format!(
-                        "{} {}: {}",
-                        k.created_at.format("%Y/%m/%d %H:%M"), v.creator_handle, v.message
-                    )));
-                    ListItem::new(content)
+                .scan(None, |last_day, (k, v)| {
+                    // The first message of each day gets a header, so they stay in step with the selection
+                    let day = app.time.local_date(&k.created_at);
+                    let new_day = *last_day != Some(day);
+                    *last_day = Some(day);
+                    Some((new_day, k, v))
+                })
+                .map(|(new_day, k, v)| {
+                    let mut lines = Vec::new();
+                    if new_day {
+                        lines.push(Spans::from(Span::styled(
+                            format!("── {} ──", app.time.day_label(&k.created_at)),
+                            app.theme.dim,
+                        )));
+                    }
+                    // Group changes are a single quiet line
+                    if let Some(description) = app.describe_group_update(v) {
+                        lines.push(Spans::from(Span::styled(
+                            format!("{} {}", app.time.message_time(&k.created_at), description),
+                            app.theme.dim,
+                        )));
+                        return ListItem::new(Text::from(lines));
+                    }
+                    let mut header = vec![
+                        Span::raw(format!("{} ", app.time.message_time(&k.created_at))),
+                        Span::styled(
+                            v.creator_handle.clone(),
+                            match app.user_did.as_ref() == Some(&v.creator) {
+                                true => app.theme.own_message,
+                                false => app.theme.other_message,
+                            },
+                        ),
+                    ];
+                    if app.is_verified(&v.creator) {
+                        header.push(Span::styled(" ✔", app.theme.other_message));
+                    }
+                    lines.push(Spans::from(header));
+                    lines.extend(
+                        wrap_text(&v.message, body_width)
+                            .into_iter()
+                            .map(|line| Spans::from(Span::raw(format!("  {}", line)))),
+                    );
+                    ListItem::new(Text::from(lines))
                 })
                 .collect(),
             None => Default::default(),
@@ -885,25 +2307,71 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         None => Vec::new(),
     };
 
+    // Work out how many messages fit on screen, so paging moves by about a screenful
+    let viewport_height = (chunks[5].height as usize).saturating_sub(2);
+    let mut used_height = 0;
+    let mut page_size = 0;
+    for item in messages.iter().rev() {
+        used_height += item.height();
+        if used_height > viewport_height {
+            break;
+        }
+        page_size += 1;
+    }
+    app.message_page_size = page_size.max(1);
+
     let messages = List::new(messages)
         .style(match app.input_mode {
             InputMode::Normal => Style::default(),
             InputMode::Editing => Style::default(),
             InputMode::EditingRecipient => Style::default(),
-            InputMode::ScrollingMessages => Style::default().fg(Color::Yellow),
+            InputMode::ScrollingMessages => app.theme.focused,
+            InputMode::SelectingConversation => Style::default(),
+            InputMode::VerifyingKeys => Style::default(),
+            InputMode::EditingGroup => Style::default(),
+            InputMode::EditingTitle => Style::default(),
+            InputMode::Locked => Style::default(),
         })
-        .block(Block::default().borders(Borders::ALL).title("Messages"));
-    f.render_stateful_widget(messages, chunks[4], &mut app.conversation_state);
-
-    let input = Paragraph::new(app.input.as_ref())
+        .highlight_symbol(MESSAGE_HIGHLIGHT)
+        .block(Block::default().borders(Borders::ALL).title(
+            match app
+                .current_conversation_id
+                .and_then(|c_id| app.store.disappearing_timer(&c_id))
+            {
+                Some(seconds) => format!("Messages (disappear after {})", duration_label(seconds)),
+                None => "Messages".to_string(),
+            },
+        ));
+    f.render_stateful_widget(messages, chunks[5], &mut app.conversation_state);
+
+    // Keep the line with the cursor on it in view
+    let input_scroll = input_cursor.0.saturating_sub(input_height - 1);
+    let input_text: Vec<Spans> = input_lines
+        .into_iter()
+        .skip(input_scroll)
+        .take(input_height)
+        .map(|line| Spans::from(Span::raw(line)))
+        .collect();
+    let input = Paragraph::new(input_text)
         .style(match app.input_mode {
             InputMode::Normal => Style::default(),
-            InputMode::Editing => Style::default().fg(Color::Yellow),
+            InputMode::Editing => app.theme.focused,
             InputMode::EditingRecipient => Style::default(),
             InputMode::ScrollingMessages => Style::default(),
+            InputMode::SelectingConversation => Style::default(),
+            InputMode::VerifyingKeys => Style::default(),
+            InputMode::EditingGroup => Style::default(),
+            InputMode::EditingTitle => Style::default(),
+            InputMode::Locked => Style::default(),
         })
-        .block(Block::default().borders(Borders::ALL).title("Input"));
-    f.render_widget(input, chunks[5]);
+        .block(Block::default().borders(Borders::ALL).title(match app.send_blocker() {
+            Some(blocker) => Span::styled(
+                format!("Input (sending disabled: {})", blocker),
+                app.theme.unverified,
+            ),
+            None => Span::raw("Input"),
+        }));
+    f.render_widget(input, chunks[6]);
     match app.input_mode {
         InputMode::Normal =>
             // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
@@ -912,10 +2380,9 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         InputMode::Editing => {
             // Make the cursor visible and ask ratatui to put it at the specified coordinates after rendering
             f.set_cursor(
-                // Put cursor past the end of the input text
-                chunks[5].x + app.input.width() as u16 + 1,
-                // Move one line down, from the border to the input line
-                chunks[5].y + 1,
+                // Put the cursor where it is in the text, inside the border
+                chunks[6].x + input_cursor.1 as u16 + 1,
+                chunks[6].y + (input_cursor.0 - input_scroll) as u16 + 1,
             )
         }
         InputMode::EditingRecipient => {
@@ -927,6 +2394,164 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
                 chunks[2].y + 1,
             )
         }
+        InputMode::EditingGroup => f.set_cursor(
+            chunks[2].x + app.group_command.width() as u16 + 1,
+            chunks[2].y + 1,
+        ),
+        InputMode::EditingTitle => f.set_cursor(
+            chunks[2].x + app.title_input.width() as u16 + 1,
+            chunks[2].y + 1,
+        ),
         InputMode::ScrollingMessages => {}
+        InputMode::SelectingConversation => {}
+        InputMode::VerifyingKeys => {}
+        InputMode::Locked => {}
+    }
+
+    let summaries = app.conversation_list();
+    let conversations: Vec<ListItem> = summaries
+        .iter()
+        .map(|c| {
+            let mut header = vec![Span::styled(
+                c.label.as_str(),
+                Style::default().add_modifier(Modifier::BOLD),
+            )];
+            if c.unread_count > 0 {
+                header.push(Span::styled(
+                    format!(" ({})", c.unread_count),
+                    app.theme.unread,
+                ));
+            }
+            if c.muted {
+                header.push(Span::styled(" muted", app.theme.dim));
+            }
+            if c.archived {
+                header.push(Span::styled(" archived", app.theme.dim));
+            }
+            // The preview is borrowed rather than copied into the list, so it's only wiped in one place
+            let preview = match c.last_message_at {
+                Some(at) => Spans::from(vec![
+                    Span::styled(format!("{} ", app.time.sidebar_time(&at)), app.theme.dim),
+                    Span::styled(c.preview.as_str(), app.theme.dim),
+                ]),
+                None => Spans::from(Span::styled("No messages loaded", app.theme.dim)),
+            };
+            ListItem::new(Text::from(vec![Spans::from(header), preview]))
+        })
+        .collect();
+    let conversations = List::new(conversations)
+        .style(match app.input_mode {
+            InputMode::SelectingConversation => app.theme.focused,
+            _ => Style::default(),
+        })
+        .highlight_style(app.theme.selected)
+        .block(Block::default().borders(Borders::ALL).title(match app.show_archived {
+            true => "Conversations (with archived)",
+            false => "Conversations",
+        }));
+    f.render_stateful_widget(conversations, columns[0], &mut app.conversation_list_state);
+
+    // Handle suggestions drop down over the messages while the recipient is being typed
+    let suggestions = app.autocomplete.suggestions();
+    if matches!(app.input_mode, InputMode::EditingRecipient) && !suggestions.is_empty() {
+        let below = chunks[2].y + chunks[2].height;
+        let area = Rect {
+            x: chunks[2].x,
+            y: below,
+            width: chunks[2].width,
+            height: (suggestions.len() as u16 + 2).min(chunks[6].y.saturating_sub(below)),
+        };
+        let items: Vec<ListItem> = suggestions
+            .iter()
+            .map(|c| {
+                let (marker, marker_style) = match app.autocomplete.bmail_capable(&c.did) {
+                    Some(true) => ("✓ ", app.theme.other_message),
+                    Some(false) => ("✗ ", app.theme.unverified),
+                    None => ("? ", app.theme.dim),
+                };
+                let mut line = vec![
+                    Span::styled(marker, marker_style),
+                    Span::raw(c.handle.clone()),
+                ];
+                if let Some(name) = &c.display_name {
+                    line.push(Span::styled(format!(" {}", name), app.theme.dim));
+                }
+                if app.autocomplete.bmail_capable(&c.did) == Some(false) {
+                    line.push(Span::styled(" (no Bmail key)", app.theme.unverified));
+                }
+                ListItem::new(Spans::from(line))
+            })
+            .collect();
+        let mut state = ListState::default();
+        state.select(app.autocomplete.selected());
+        let list = List::new(items)
+            .highlight_style(app.theme.selected)
+            .block(Block::default().borders(Borders::ALL).title("Suggestions"));
+        f.render_widget(Clear, area);
+        f.render_stateful_widget(list, area, &mut state);
+    }
+
+    // Key verification covers the messages
+    if matches!(app.input_mode, InputMode::VerifyingKeys) {
+        let our_key = app
+            .own_key_set
+            .clone()
+            .unwrap_or_else(|| app.identity.public_key());
+        let items: Vec<ListItem> = app
+            .current_others()
+            .iter()
+            .map(|did| {
+                let mut lines = vec![Spans::from(vec![
+                    Span::styled(app.handle_for(did), app.theme.other_message),
+                    Span::styled(format!(" {}", did), app.theme.dim),
+                ])];
+                let Some(ParticipantKey::Published(key)) = app.participant_keys.get(did) else {
+                    lines.push(Spans::from(Span::styled(
+                        "  No Bmail key to verify",
+                        app.theme.unverified,
+                    )));
+                    return ListItem::new(Text::from(lines));
+                };
+                let pin = app.store.pinned_key(did);
+                lines.push(Spans::from(format!("  Fingerprint:   {}", fingerprint(key))));
+                if app.key_check(did) == Some(KeyCheck::Changed) {
+                    if let Some(pin) = pin {
+                        lines.push(Spans::from(Span::styled(
+                            format!("  KEY CHANGED, was {}", fingerprint(&pin.key)),
+                            app.theme.status_error,
+                        )));
+                    }
+                }
+                lines.push(Spans::from(format!(
+                    "  Safety number: {}",
+                    safety_number(&our_key, key)
+                )));
+                if let Some(pin) = pin {
+                    lines.push(Spans::from(Span::styled(
+                        format!(
+                            "  First seen {} {}",
+                            app.time.day_label(&pin.first_seen),
+                            app.time.message_time(&pin.first_seen)
+                        ),
+                        app.theme.dim,
+                    )));
+                }
+                lines.push(Spans::from(match app.is_verified(did) {
+                    true => Span::styled("  ✔ Verified", app.theme.other_message),
+                    false => Span::styled("  Not verified", app.theme.unverified),
+                }));
+                ListItem::new(Text::from(lines))
+            })
+            .collect();
+        let list = List::new(items)
+            .style(app.theme.focused)
+            .highlight_symbol(MESSAGE_HIGHLIGHT)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Verify Keys: compare safety numbers in person or over another app"),
+            );
+        f.render_widget(Clear, chunks[5]);
+        f.render_stateful_widget(list, chunks[5], &mut app.verify_list_state);
     }
 }
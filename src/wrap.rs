@@ -0,0 +1,82 @@
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Word wrap some text to fit in a width, measured in terminal columns so wide characters and emoji
+/// take up the room they actually do. Line breaks in the text are kept, and words too long to fit
+/// on a line of their own are split wherever they run out of room
+pub fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut line_width = 0;
+        // Whether anything has been put on this line yet, including empty words from repeated spaces
+        let mut line_started = false;
+
+        for word in paragraph.split(' ') {
+            let word_width = word.width();
+            let space = usize::from(line_started);
+            if line_width + space + word_width <= width {
+                if line_started {
+                    line.push(' ');
+                }
+                line.push_str(word);
+                line_width += space + word_width;
+                line_started = true;
+                continue;
+            }
+
+            // Doesn't fit, so it starts the next line
+            if line_started {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+            }
+            for c in word.chars() {
+                let c_width = c.width().unwrap_or(0);
+                if line_width + c_width > width && line_width > 0 {
+                    lines.push(std::mem::take(&mut line));
+                    line_width = 0;
+                }
+                line.push(c);
+                line_width += c_width;
+            }
+            line_started = true;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_at_spaces() {
+        assert_eq!(wrap_text("hello big world", 9), vec!["hello big", "world"]);
+        assert_eq!(wrap_text("hello world", 11), vec!["hello world"]);
+    }
+
+    #[test]
+    fn keeps_line_breaks() {
+        assert_eq!(wrap_text("a\n\nb", 10), vec!["a", "", "b"]);
+        assert_eq!(wrap_text("", 10), vec![""]);
+    }
+
+    #[test]
+    fn splits_long_words() {
+        assert_eq!(wrap_text("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap_text("hi abcdefgh", 5), vec!["hi", "abcde", "fgh"]);
+    }
+
+    #[test]
+    fn measures_wide_characters() {
+        assert_eq!(wrap_text("日本語", 4), vec!["日本", "語"]);
+        assert_eq!(wrap_text("日本 語", 7), vec!["日本 語"]);
+    }
+
+    #[test]
+    fn zero_width_still_wraps() {
+        assert_eq!(wrap_text("a b", 0), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,120 @@
+use curve25519_dalek::{
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    montgomery::MontgomeryPoint,
+    scalar::{clamp_integer, Scalar},
+};
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+
+/// Sign a message with an X25519 secret key, using XEdDSA. See https://signal.org/docs/specifications/xeddsa/
+pub fn sign(secret: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let k = Scalar::from_bytes_mod_order(clamp_integer(*secret));
+    let (a, public) = edwards_keypair(k);
+
+    let mut random = [0u8; 64];
+    rand::rngs::OsRng.fill_bytes(&mut random);
+    // hash_1 from the spec, a hash prefixed with 0xFE and then 31 0xFF bytes
+    let mut prefix = [0xFFu8; 32];
+    prefix[0] = 0xFE;
+    let r = hash_to_scalar(&[&prefix, a.as_bytes(), message, &random]);
+    let big_r = EdwardsPoint::mul_base(&r).compress();
+    let h = hash_to_scalar(&[big_r.as_bytes(), public.as_bytes(), message]);
+    let s = r + h * a;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(big_r.as_bytes());
+    signature[32..].copy_from_slice(s.as_bytes());
+    signature
+}
+
+/// Check a signature made by [sign] against the X25519 public key that made it
+pub fn verify(public: &[u8; 32], message: &[u8], signature: &[u8]) -> bool {
+    if signature.len() != 64 {
+        return false;
+    }
+    // The public key is turned into an Edwards point with its sign bit cleared, which sign made sure of
+    let Some(big_a) = MontgomeryPoint(*public).to_edwards(0) else {
+        return false;
+    };
+    let big_r = CompressedEdwardsY::from_slice(&signature[..32]);
+    let Ok(big_r) = big_r else {
+        return false;
+    };
+    let mut s_bytes = [0u8; 32];
+    s_bytes.copy_from_slice(&signature[32..]);
+    let Some(s) = Option::<Scalar>::from(Scalar::from_canonical_bytes(s_bytes)) else {
+        return false;
+    };
+    let h = hash_to_scalar(&[big_r.as_bytes(), big_a.compress().as_bytes(), message]);
+    let check = EdwardsPoint::mul_base(&s) - h * big_a;
+    check.compress() == big_r
+}
+
+/// Get the Edwards private scalar and public key for a Montgomery private scalar, flipping them if needed so
+/// the public key's sign bit is 0
+fn edwards_keypair(k: Scalar) -> (Scalar, CompressedEdwardsY) {
+    let public = EdwardsPoint::mul_base(&k);
+    let compressed = public.compress();
+    match compressed.as_bytes()[31] >> 7 {
+        1 => (-k, (-public).compress()),
+        _ => (k, compressed),
+    }
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+    fn keypair() -> ([u8; 32], [u8; 32]) {
+        let mut secret = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut secret);
+        (secret, x25519(secret, X25519_BASEPOINT_BYTES))
+    }
+
+    #[test]
+    fn signatures_verify() {
+        for _ in 0..16 {
+            let (secret, public) = keypair();
+            let signature = sign(&secret, b"hello");
+            assert!(verify(&public, b"hello", &signature));
+        }
+    }
+
+    #[test]
+    fn tampered_message_fails() {
+        let (secret, public) = keypair();
+        let signature = sign(&secret, b"hello");
+        assert!(!verify(&public, b"hellp", &signature));
+    }
+
+    #[test]
+    fn tampered_signature_fails() {
+        let (secret, public) = keypair();
+        let signature = sign(&secret, b"hello");
+        for i in [0, 31, 32, 63] {
+            let mut tampered = signature;
+            tampered[i] ^= 1;
+            assert!(!verify(&public, b"hello", &tampered));
+        }
+        assert!(!verify(&public, b"hello", &signature[..63]));
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let (secret, _) = keypair();
+        let (_, other) = keypair();
+        let signature = sign(&secret, b"hello");
+        assert!(!verify(&other, b"hello", &signature));
+    }
+}